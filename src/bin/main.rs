@@ -9,7 +9,7 @@ use iced::widget::container::Style;
 use iced::widget::{
     button, column, container, row, scrollable, stack, text, text_editor,
 };
-use iced::{Color, Element, Theme};
+use iced::{Color, Element, Font, Theme};
 
 pub fn main() -> iced::Result {
     #[cfg(target_arch = "wasm32")]
@@ -69,6 +69,11 @@ impl State {
                         self.sheet.update_cell(id, self.editor_contents.text());
                     }
                 }
+                MainEditorMessage::RerollCell => {
+                    if let Some(id) = &self.selected_cell {
+                        self.sheet.reroll(id);
+                    }
+                }
             },
             (SubState::NewCell { editor_contents }, Message::NewCellEditorMessage(msg)) => {
                 match msg {
@@ -112,9 +117,14 @@ impl State {
                 text_editor(&self.editor_contents).on_action(|action| Message::MainEditorMessage(
                     MainEditorMessage::Edit(action)
                 )),
-                button("Update")
-                    .on_press(Message::MainEditorMessage(MainEditorMessage::UpdateCell))
-                    .width(Length::Fill),
+                row![
+                    button("Update")
+                        .on_press(Message::MainEditorMessage(MainEditorMessage::UpdateCell))
+                        .width(Length::Fill),
+                    button("Reroll")
+                        .on_press(Message::MainEditorMessage(MainEditorMessage::RerollCell))
+                        .width(Length::Fill),
+                ],
             ])
             .align_y(Vertical::Bottom),
         ])
@@ -125,21 +135,22 @@ impl State {
         column(self.cells.iter().map(|id| {
             let cell_name = self.sheet.get_cell_name(id);
             let cell_value = self.sheet.get_cell_value(id).unwrap();
+            let cell_type = match self.sheet.get_cell_type(id) {
+                Some(Ok(t)) => text(format!(": {}", t)),
+                Some(Err(e)) => text(format!(": {}", e.kind)),
+                None => text(""),
+            };
             let button = button(column![
-                text(cell_name),
+                row![text(cell_name), cell_type.font(Font::MONOSPACE)],
                 match cell_value {
                     Ok(v) => Self::draw_value(v),
-                    Err(e) => text(format!("Error: {}", e.message)).into(),
+                    Err(e) => text(format!("Error: {}", e)).font(Font::MONOSPACE).into(),
                 },
             ])
             .on_press(Message::MainEditorMessage(MainEditorMessage::SelectCell(
                 id.clone(),
             )));
-            if self
-                .selected_cell
-                .as_ref()
-                .map_or(false, |selected| selected == id)
-            {
+            if self.selected_cell.as_ref() == Some(id) {
                 button.style(button::success)
             } else {
                 button
@@ -150,7 +161,7 @@ impl State {
 
     fn draw_value(value: &EvaluatedValue) -> Element<'_, Message> {
         match &value.0 {
-            Value::Unit => text(format!("()")).into(),
+            Value::Unit => text("()").into(),
             Value::Integer(i) => text(format!("{}", i)).into(),
             Value::String(s) => text(s).into(),
             Value::Boolean(b) => text(format!("{}", b)).into(),
@@ -160,8 +171,21 @@ impl State {
                 })).into()
             }
             Value::List(items) => column(items.iter().map(|item| Self::draw_value(item))).into(),
+            Value::Tag(tag, None) => text(format!("#{}", tag)).into(),
+            Value::Tag(tag, Some(payload)) => {
+                row![text(format!("#{}", tag)), Self::draw_value(payload)].spacing(5).into()
+            }
+            Value::Dice(dice) => text(dice.to_string()).into(),
+            Value::Distribution(distribution) => {
+                column(distribution.outcomes().iter().map(|(outcome, chance)| {
+                    row![text(format!("{}: ", outcome)), text(chance.to_string())].spacing(20).into()
+                })).into()
+            }
+            Value::Rational(r) => text(r.to_string()).into(),
+            Value::Quantity(q) => text(q.to_string()).into(),
             Value::Function(function) => match function {
                 Function::Lambda(args, body) => {
+                    let args = args.iter().map(|a| a.to_s_expr()).collect::<Vec<_>>();
                     text(format!("fn ({}) -> {}", args.join(", "), body.to_s_expr())).into()
                 }
                 Function::Builtin(name) => {
@@ -218,6 +242,7 @@ enum MainEditorMessage {
     Edit(text_editor::Action),
     SelectCell(CellId),
     UpdateCell,
+    RerollCell,
 }
 
 #[derive(Debug, Clone)]
@@ -1,6 +1,14 @@
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 
-use crate::language::{bultins::BuiltinFunction, errors::Error};
+use crate::language::{
+    bultins::BuiltinFunction,
+    dice::Dice,
+    distribution::Distribution,
+    errors::{Error, ErrorKind, Span},
+    rational::Rational,
+    units::{Quantity, Unit, dimension_mismatch},
+};
 
 #[derive(Debug, Clone)]
 pub enum Value<T> {
@@ -12,15 +20,127 @@ pub enum Value<T> {
     Record(BTreeMap<String, T>),
     List(Vec<T>),
 
+    /// A tagged variant such as `#fire`, optionally carrying a value as in `#Some(3)`
+    Tag(String, Option<Box<T>>),
+
+    /// Dice to be rolled such as `2d6 + 3`
+    Dice(Dice),
+    /// The exact chance of each outcome of dice
+    Distribution(Distribution),
+    /// An exact fraction, such as a probability
+    Rational(Rational),
+    /// An amount with a unit of measure such as `30 ft`
+    Quantity(Quantity),
+
     Function(Function),
 }
 
+/// The runtime type of a value, used when reporting type errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Unit,
+    Integer,
+    String,
+    Boolean,
+    Record,
+    List,
+    Tag,
+    Dice,
+    Distribution,
+    Rational,
+    Quantity,
+    Function,
+}
+
+impl ValueType {
+    pub const ALL: [ValueType; 12] = [
+        ValueType::Unit,
+        ValueType::Integer,
+        ValueType::String,
+        ValueType::Boolean,
+        ValueType::Record,
+        ValueType::List,
+        ValueType::Tag,
+        ValueType::Dice,
+        ValueType::Distribution,
+        ValueType::Rational,
+        ValueType::Quantity,
+        ValueType::Function,
+    ];
+
+    /// An arbitrary value of this type
+    pub fn example(self) -> EvaluatedValue {
+        match self {
+            ValueType::Unit => Value::Unit,
+            ValueType::Integer => Value::Integer(0),
+            ValueType::String => Value::String(String::new()),
+            ValueType::Boolean => Value::Boolean(false),
+            ValueType::Record => Value::Record(BTreeMap::new()),
+            ValueType::List => Value::List(vec![]),
+            ValueType::Tag => Value::Tag(String::new(), None),
+            ValueType::Dice => Value::Dice(Dice::default()),
+            ValueType::Distribution => Value::Distribution(Distribution::constant(0)),
+            ValueType::Rational => Value::Rational(Rational::ZERO),
+            ValueType::Quantity => Value::Quantity(Quantity::new(Rational::ZERO, Unit::lookup("ft").unwrap())),
+            ValueType::Function => Value::Function(Function::Builtin(BuiltinFunction::Add)),
+        }
+        .into()
+    }
+}
+
+impl std::fmt::Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ValueType::Unit => "unit",
+            ValueType::Integer => "integer",
+            ValueType::String => "string",
+            ValueType::Boolean => "boolean",
+            ValueType::Record => "record",
+            ValueType::List => "list",
+            ValueType::Tag => "tag",
+            ValueType::Dice => "dice",
+            ValueType::Distribution => "distribution",
+            ValueType::Rational => "rational",
+            ValueType::Quantity => "quantity",
+            ValueType::Function => "function",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl<T> Value<T> {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Unit => ValueType::Unit,
+            Value::Integer(_) => ValueType::Integer,
+            Value::String(_) => ValueType::String,
+            Value::Boolean(_) => ValueType::Boolean,
+            Value::Record(_) => ValueType::Record,
+            Value::List(_) => ValueType::List,
+            Value::Tag(..) => ValueType::Tag,
+            Value::Dice(_) => ValueType::Dice,
+            Value::Distribution(_) => ValueType::Distribution,
+            Value::Rational(_) => ValueType::Rational,
+            Value::Quantity(_) => ValueType::Quantity,
+            Value::Function(_) => ValueType::Function,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Function {
-    Lambda(Vec<String>, Box<AST>),
+    Lambda(Vec<Param>, Box<AST>),
     Builtin(BuiltinFunction),
 }
 
+/// The number of parameters still missing when a function taking one of `arities` arguments is
+/// called with `provided`, or `None` if the call should not be a partial application.
+/// Calls with no arguments are never partial, so `f()` still reports the missing arguments.
+pub fn missing_arguments(arities: &[usize], provided: usize) -> Option<usize> {
+    let fewest = arities.iter().copied().min()?;
+    (provided > 0 && provided < fewest).then(|| fewest - provided)
+}
+
 #[derive(Debug, Clone)]
 pub struct EvaluatedValue(pub Value<EvaluatedValue>);
 
@@ -32,7 +152,7 @@ impl From<Value<EvaluatedValue>> for EvaluatedValue {
 
 impl From<EvaluatedValue> for AST {
     fn from(value: EvaluatedValue) -> Self {
-        AST::Literal(value.0.into())
+        Expr::Literal(value.0.into()).into()
     }
 }
 
@@ -53,12 +173,108 @@ impl From<Value<EvaluatedValue>> for Value<AST> {
                 Value::Record(fields.into_iter().map(|(k, v)| (k, v.into())).collect())
             }
             Value::List(items) => Value::List(items.into_iter().map(Into::into).collect()),
+            Value::Tag(tag, payload) => Value::Tag(tag, payload.map(|p| Box::new((*p).into()))),
+            Value::Dice(dice) => Value::Dice(dice),
+            Value::Distribution(distribution) => Value::Distribution(distribution),
+            Value::Rational(r) => Value::Rational(r),
+            Value::Quantity(q) => Value::Quantity(q),
             Value::Function(Function::Builtin(function)) => Value::Function(Function::Builtin(function)),
             Value::Function(Function::Lambda(args, body)) => Value::Function(Function::Lambda(args, body)),
         }
     }
 }
 
+impl Value<EvaluatedValue> {
+    /// Compares two values structurally.
+    ///
+    /// Values are totally ordered, first by type and then by contents:
+    ///
+    /// - unit < booleans < numbers < strings < lists < records < tags < dice < distributions < quantities
+    /// - `false < true`, integers and rationals numerically and strings by code point
+    /// - lists lexicographically by their elements, so `[1] < [1, 0] < [2]`
+    /// - records lexicographically by their fields in key order, comparing
+    ///   each key before its value
+    /// - tags by name, then by their values with tags without one first
+    /// - dice by how they are written, so `1d6 + 1d8` and `1d8 + 1d6` differ
+    /// - distributions by their outcomes in order, comparing each outcome before its chance
+    /// - quantities by their size, whatever their units, so `1 mi > 5000 ft`
+    ///
+    /// Quantities can only be compared with quantities of the same dimension, comparing one
+    /// with a quantity of another dimension or with a plain number is an error.
+    /// Functions have no meaningful notion of equality, so comparing a value
+    /// containing a function is an error unless the comparison is decided
+    /// before the function is reached.
+    pub fn compare(&self, other: &Value<EvaluatedValue>) -> Result<Ordering, Error> {
+        fn rank<T>(value: &Value<T>) -> Result<u8, Error> {
+            match value {
+                Value::Unit => Ok(0),
+                Value::Boolean(_) => Ok(1),
+                Value::Integer(_) | Value::Rational(_) => Ok(2),
+                Value::String(_) => Ok(3),
+                Value::List(_) => Ok(4),
+                Value::Record(_) => Ok(5),
+                Value::Tag(..) => Ok(6),
+                Value::Dice(_) => Ok(7),
+                Value::Distribution(_) => Ok(8),
+                Value::Quantity(_) => Ok(9),
+                Value::Function(_) => Err(Error::new(ErrorKind::TypeMismatch {
+                    expected: ValueType::ALL
+                        .into_iter()
+                        .filter(|t| *t != ValueType::Function)
+                        .collect(),
+                    actual: ValueType::Function,
+                    position: None,
+                })),
+            }
+        }
+
+        match (self, other) {
+            (Value::Unit, Value::Unit) => Ok(Ordering::Equal),
+            (Value::Boolean(a), Value::Boolean(b)) => Ok(a.cmp(b)),
+            (Value::Integer(a), Value::Integer(b)) => Ok(a.cmp(b)),
+            (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+            (Value::List(a), Value::List(b)) => {
+                for (a, b) in a.iter().zip(b) {
+                    match a.0.compare(&b.0)? {
+                        Ordering::Equal => continue,
+                        ordering => return Ok(ordering),
+                    }
+                }
+                Ok(a.len().cmp(&b.len()))
+            }
+            (Value::Record(a), Value::Record(b)) => {
+                for ((ak, av), (bk, bv)) in a.iter().zip(b) {
+                    match ak.cmp(bk) {
+                        Ordering::Equal => {}
+                        ordering => return Ok(ordering),
+                    }
+                    match av.0.compare(&bv.0)? {
+                        Ordering::Equal => {}
+                        ordering => return Ok(ordering),
+                    }
+                }
+                Ok(a.len().cmp(&b.len()))
+            }
+            (Value::Tag(a, a_payload), Value::Tag(b, b_payload)) => match a.cmp(b) {
+                Ordering::Equal => match (a_payload, b_payload) {
+                    (Some(a), Some(b)) => a.0.compare(&b.0),
+                    (a, b) => Ok(a.is_some().cmp(&b.is_some())),
+                },
+                ordering => Ok(ordering),
+            },
+            (Value::Dice(a), Value::Dice(b)) => Ok(a.cmp(b)),
+            (Value::Distribution(a), Value::Distribution(b)) => Ok(a.cmp(b)),
+            (Value::Rational(a), Value::Rational(b)) => Ok(a.cmp(b)),
+            (Value::Rational(a), Value::Integer(b)) => Ok(a.cmp(&Rational::from(*b))),
+            (Value::Integer(a), Value::Rational(b)) => Ok(Rational::from(*a).cmp(b)),
+            (Value::Quantity(a), Value::Quantity(b)) => a.compare(b),
+            (Value::Quantity(a), Value::Integer(_) | Value::Rational(_)) => Err(dimension_mismatch(Some(a.dimension()), None)),
+            (Value::Integer(_) | Value::Rational(_), Value::Quantity(b)) => Err(dimension_mismatch(None, Some(b.dimension()))),
+            (a, b) => Ok(rank(a)?.cmp(&rank(b)?)),
+        }
+    }
+}
+
 impl TryFrom<EvaluatedValue> for bool {
     type Error = ();
 
@@ -71,34 +287,254 @@ impl TryFrom<EvaluatedValue> for bool {
 }
 
 #[derive(Debug, Clone)]
-pub struct Binding(pub String, pub AST);
+pub struct Binding(pub Pattern, pub AST);
 
+/// A lambda parameter, with the value it takes when no argument is given for it
 #[derive(Debug, Clone)]
-pub enum AST {
+pub struct Param {
+    pub pattern: Pattern,
+    pub default: Option<AST>,
+}
+
+/// An expression together with the span of source text it was parsed from.
+///
+/// Nodes that are synthesised rather than parsed (e.g. values substituted in
+/// when a lambda captures its environment) carry a default span.
+#[derive(Debug, Clone)]
+pub struct AST {
+    pub expr: Expr,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
     Literal(Value<AST>),
     Name(String),
     Function(Box<AST>, Vec<AST>),
+    /// A call with some arguments given by name, `f(1, crit_range: 19)`, after the positional ones
+    NamedCall(Box<AST>, Vec<AST>, Vec<(String, AST)>),
     FieldAccess(Box<AST>, String),
     Let(Vec<Binding>, Box<AST>),
+    Match(Box<AST>, Vec<MatchArm>),
+}
+
+/// A pattern that values are matched against, binding names to parts of the value
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum PatternKind {
+    /// `_`, which matches any value
+    Wildcard,
+    /// Matches any value and binds it to the name
+    Name(String),
+    /// Matches values equal to an integer, string or boolean literal
+    Literal(EvaluatedValue),
+    /// `[a, b, ..rest]` matches lists of exactly the given elements, or at
+    /// least that many if there is a rest pattern, which matches the remaining list
+    List(Vec<Pattern>, Option<Box<Pattern>>),
+    /// `{field: pattern, ...}` matches records containing at least the given fields
+    Record(Vec<(String, Pattern)>),
+    /// `#tag` matches the tag without a value, `#tag(pattern)` matches the tag with a value
+    Tag(String, Option<Box<Pattern>>),
+}
+
+/// One arm of a match expression, `| pattern if guard -> body`
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<AST>,
+    pub body: AST,
+}
+
+impl Param {
+    pub fn new(pattern: Pattern, default: Option<AST>) -> Self {
+        Param { pattern, default }
+    }
+
+    /// The name arguments for this parameter can be given with, if it is a plain name
+    pub fn name(&self) -> Option<&str> {
+        match &self.pattern.kind {
+            PatternKind::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn relocate(&self, span: Span) -> Param {
+        Param::new(self.pattern.relocate(span), self.default.as_ref().map(|d| d.relocate(span)))
+    }
+}
+
+impl Pattern {
+    pub fn new(kind: PatternKind, span: Span) -> Self {
+        Pattern { kind, span }
+    }
+
+    /// The names bound by the pattern, in the order they appear
+    pub fn bound_names(&self) -> Vec<&str> {
+        match &self.kind {
+            PatternKind::Wildcard | PatternKind::Literal(_) => vec![],
+            PatternKind::Name(name) => vec![name.as_str()],
+            PatternKind::List(elements, rest) => elements
+                .iter()
+                .chain(rest.as_deref())
+                .flat_map(|p| p.bound_names())
+                .collect(),
+            PatternKind::Record(fields) => fields.iter().flat_map(|(_, p)| p.bound_names()).collect(),
+            PatternKind::Tag(_, payload) => payload.iter().flat_map(|p| p.bound_names()).collect(),
+        }
+    }
+
+    /// A parameter introduced when desugaring, named so that it can not clash with names in the source
+    pub fn generated(index: usize, span: Span) -> Self {
+        Pattern::new(PatternKind::Name(Pattern::generated_name(index)), span)
+    }
+
+    pub fn generated_name(index: usize) -> String {
+        format!("%{index}")
+    }
+
+    fn relocate(&self, span: Span) -> Pattern {
+        let kind = match &self.kind {
+            PatternKind::List(elements, rest) => PatternKind::List(
+                elements.iter().map(|p| p.relocate(span)).collect(),
+                rest.as_ref().map(|p| Box::new(p.relocate(span))),
+            ),
+            PatternKind::Record(fields) => PatternKind::Record(
+                fields
+                    .iter()
+                    .map(|(k, p)| (k.clone(), p.relocate(span)))
+                    .collect(),
+            ),
+            PatternKind::Tag(tag, payload) => {
+                PatternKind::Tag(tag.clone(), payload.as_ref().map(|p| Box::new(p.relocate(span))))
+            }
+            kind => kind.clone(),
+        };
+        Pattern::new(kind, span)
+    }
 }
 
 pub fn pretty_print_result(res: &Result<EvaluatedValue, Error>) -> String {
     use super::s_exprs::ToSExpr;
     match res {
         Ok(v) => v.to_s_expr(),
-        Err(e) => format!("Error: {}", e.message),
+        Err(e) => format!("Error: {}", e),
     }
 }
 
 impl AST {
-    pub fn function(function: impl Into<AST>, args: Vec<AST>) -> AST {
-        AST::Function(Box::new(function.into()), args)
+    pub fn new(expr: Expr, span: Span) -> AST {
+        AST { expr, span }
+    }
+
+    pub fn function(function: impl Into<AST>, args: Vec<AST>, span: Span) -> AST {
+        AST::new(Expr::Function(Box::new(function.into()), args), span)
+    }
+
+    /// Replaces the span of this node
+    pub fn at(mut self, span: Span) -> AST {
+        self.span = span;
+        self
+    }
+
+    /// Replaces the span of this node and every node beneath it.
+    ///
+    /// Used when an expression is moved out of the text it was parsed from,
+    /// such as a lambda read from another cell, so that errors raised inside
+    /// it point at the place it was used instead of meaningless offsets.
+    pub fn relocate(&self, span: Span) -> AST {
+        let expr = match &self.expr {
+            Expr::Literal(value) => Expr::Literal(value.relocate(span)),
+            Expr::Name(name) => Expr::Name(name.clone()),
+            Expr::Function(function, args) => Expr::Function(
+                Box::new(function.relocate(span)),
+                args.iter().map(|a| a.relocate(span)).collect(),
+            ),
+            Expr::NamedCall(function, args, named) => Expr::NamedCall(
+                Box::new(function.relocate(span)),
+                args.iter().map(|a| a.relocate(span)).collect(),
+                named.iter().map(|(name, a)| (name.clone(), a.relocate(span))).collect(),
+            ),
+            Expr::FieldAccess(record, field) => {
+                Expr::FieldAccess(Box::new(record.relocate(span)), field.clone())
+            }
+            Expr::Let(bindings, body) => Expr::Let(
+                bindings
+                    .iter()
+                    .map(|Binding(pattern, expr)| Binding(pattern.relocate(span), expr.relocate(span)))
+                    .collect(),
+                Box::new(body.relocate(span)),
+            ),
+            Expr::Match(value, arms) => Expr::Match(
+                Box::new(value.relocate(span)),
+                arms.iter()
+                    .map(|arm| MatchArm {
+                        pattern: arm.pattern.relocate(span),
+                        guard: arm.guard.as_ref().map(|g| g.relocate(span)),
+                        body: arm.body.relocate(span),
+                    })
+                    .collect(),
+            ),
+        };
+        AST::new(expr, span)
+    }
+}
+
+impl Value<AST> {
+    fn relocate(&self, span: Span) -> Value<AST> {
+        match self {
+            Value::Record(fields) => Value::Record(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.relocate(span)))
+                    .collect(),
+            ),
+            Value::List(items) => Value::List(items.iter().map(|i| i.relocate(span)).collect()),
+            Value::Tag(tag, payload) => Value::Tag(tag.clone(), payload.as_ref().map(|p| Box::new(p.relocate(span)))),
+            Value::Function(Function::Lambda(params, body)) => Value::Function(Function::Lambda(
+                params.iter().map(|p| p.relocate(span)).collect(),
+                Box::new(body.relocate(span)),
+            )),
+            value => value.clone(),
+        }
+    }
+}
+
+impl EvaluatedValue {
+    /// Relocates the bodies of any lambdas contained in this value, see [`AST::relocate`].
+    pub fn relocate(&self, span: Span) -> EvaluatedValue {
+        match &self.0 {
+            Value::Record(fields) => Value::Record(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.relocate(span)))
+                    .collect(),
+            ),
+            Value::List(items) => Value::List(items.iter().map(|i| i.relocate(span)).collect()),
+            Value::Tag(tag, payload) => Value::Tag(tag.clone(), payload.as_ref().map(|p| Box::new(p.relocate(span)))),
+            Value::Function(Function::Lambda(params, body)) => Value::Function(Function::Lambda(
+                params.iter().map(|p| p.relocate(span)).collect(),
+                Box::new(body.relocate(span)),
+            )),
+            value => value.clone(),
+        }
+        .into()
+    }
+}
+
+impl From<Expr> for AST {
+    fn from(expr: Expr) -> Self {
+        AST::new(expr, Span::default())
     }
 }
 
 impl From<Value<AST>> for AST {
     fn from(value: Value<AST>) -> Self {
-        AST::Literal(value)
+        Expr::Literal(value).into()
     }
 }
 
@@ -106,12 +542,12 @@ impl<T> From<T> for AST
 where T: Into<String>
 {
     fn from(value: T) -> Self {
-        AST::Name(value.into())
+        Expr::Name(value.into()).into()
     }
 }
 
 impl From<BuiltinFunction> for AST {
     fn from(value: BuiltinFunction) -> Self {
-        AST::Literal(Value::Function(Function::Builtin(value)))
+        Expr::Literal(Value::Function(Function::Builtin(value))).into()
     }
 }
@@ -25,8 +25,21 @@ def_builtins!{
     "+" = Add,
     "-" = Sub,
     "*" = Mul,
+    "/" = Div,
+    "%" = Mod,
     "negate" = Negate,
 
+    "div_ceil" = DivCeil,
+    "round_half_up" = RoundHalfUp,
+    "min" = Min,
+    "max" = Max,
+    "clamp" = Clamp,
+    "abs" = Abs,
+    "floor" = Floor,
+    "ceil" = Ceil,
+    "round" = Round,
+    "to" = To,
+
     "push" = Push,
     "read" = Read,
 
@@ -44,9 +57,67 @@ def_builtins!{
 
     "if" = If,
 
+    "try" = Try,
+    "catch" = Catch,
+    "is_error" = IsError,
+
     "map" = Map,
     "fold" = Fold,
     "filter" = Filter,
 
+    "length" = Length,
+    "sum" = Sum,
+    "product" = Product,
+    "sort" = Sort,
+    "sort_by" = SortBy,
+    "reverse" = Reverse,
+    "zip" = Zip,
+    "range" = Range,
+    "contains" = Contains,
+    "find" = Find,
+    "any" = Any,
+    "all" = All,
+    "flatten" = Flatten,
+    "take" = Take,
+    "drop" = Drop,
+    "unique" = Unique,
+    "group_by" = GroupBy,
+
+    "upper" = Upper,
+    "lower" = Lower,
+    "split" = Split,
+    "join" = Join,
+    "trim" = Trim,
+    "starts_with" = StartsWith,
+    "replace" = Replace,
+    "to_string" = ToString,
+    "parse_int" = ParseInt,
+
     "//" = RecordUpdate,
+    "keys" = Keys,
+    "values" = Values,
+    "entries" = Entries,
+    "from_entries" = FromEntries,
+    "has" = Has,
+    "remove" = Remove,
+    "get_or" = GetOr,
+    "?." = OptionalField,
+    "[]:" = ComputedField,
+    "??" = Coalesce,
+    "deep_merge" = DeepMerge,
+
+    "enum" = Enum,
+
+    "average" = Average,
+    "min_roll" = MinRoll,
+    "max_roll" = MaxRoll,
+    "roll" = Roll,
+    "rolls" = Rolls,
+
+    "distribution" = ToDistribution,
+    "P" = Probability,
+    "expected" = Expected,
+    "advantage" = Advantage,
+    "disadvantage" = Disadvantage,
+    "attack_chances" = AttackChances,
 }
\ No newline at end of file
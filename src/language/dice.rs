@@ -0,0 +1,495 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::language::errors::{Error, ErrorKind};
+use crate::language::rational::Rational;
+
+/// The number of times a single exploding die can explode, so that rolls and
+/// their bounds are always finite.
+pub const EXPLOSION_LIMIT: u32 = 3;
+
+/// The most dice or sides a dice literal can have
+const MAX_DICE: u32 = 1000;
+
+/// The most steps spent working out the distribution of a single group of dice
+const MAX_WORK: u128 = 50_000_000;
+
+/// Which of the dice in a group count towards its total
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Keep {
+    All,
+    Highest(u32),
+    Lowest(u32),
+}
+
+/// A group of identical dice such as `4d6kh3` or `3d6!`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DiceGroup {
+    pub count: u32,
+    pub sides: u32,
+    pub keep: Keep,
+    /// Exploding dice are rolled again and added when they roll their highest side
+    pub explode: bool,
+}
+
+/// A dice expression, a sum of groups of dice each multiplied by a constant, plus a constant
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Dice {
+    pub groups: Vec<(i64, DiceGroup)>,
+    pub constant: i64,
+}
+
+/// The result of rolling dice, with every die rolled in order including
+/// explosions and dice that were not kept
+#[derive(Debug, Clone)]
+pub struct Roll {
+    pub total: i64,
+    pub dice: Vec<i64>,
+}
+
+/// The possible totals of some dice, each with the number of equally likely
+/// outcomes that give it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcomes {
+    pub counts: BTreeMap<i64, u128>,
+    pub total: u128,
+}
+
+fn too_many_dice() -> Error {
+    Error::new(ErrorKind::TooManyDice)
+}
+
+fn overflow() -> Error {
+    Error::new(ErrorKind::Overflow)
+}
+
+impl DiceGroup {
+    /// Parses a dice literal, `NdM` optionally followed by `!` to explode and
+    /// `khK` or `klK` to keep the highest or lowest `K` dice (1 if omitted).
+    pub fn parse(text: &str) -> Result<DiceGroup, String> {
+        let number = |digits: &str| -> Result<u32, String> {
+            match digits.parse::<u32>() {
+                Ok(n) if (1..=MAX_DICE).contains(&n) => Ok(n),
+                _ => Err(format!("Dice numbers must be between 1 and {}", MAX_DICE)),
+            }
+        };
+
+        let (count, rest) = text.split_once('d').ok_or("Dice must be written NdM")?;
+        let sides_end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (sides, mut rest) = rest.split_at(sides_end);
+        let count = number(count)?;
+        let sides = number(sides)?;
+
+        let explode = rest.starts_with('!');
+        if explode {
+            rest = &rest[1..];
+            if sides == 1 {
+                return Err("A one sided die can not explode".to_string());
+            }
+        }
+
+        let keep = match rest.get(..2) {
+            None => Keep::All,
+            Some(kind) => {
+                let kept = if rest.len() > 2 { number(&rest[2..])? } else { 1 };
+                if kept > count {
+                    return Err(format!("Can not keep {} of {} dice", kept, count));
+                }
+                match kind {
+                    "kh" => Keep::Highest(kept),
+                    "kl" => Keep::Lowest(kept),
+                    _ => return Err(format!("Unknown dice modifier {}", rest)),
+                }
+            }
+        };
+
+        Ok(DiceGroup {
+            count,
+            sides,
+            keep,
+            explode,
+        })
+    }
+
+    fn kept(&self) -> u32 {
+        match self.keep {
+            Keep::All => self.count,
+            Keep::Highest(n) | Keep::Lowest(n) => n,
+        }
+    }
+
+    fn min(&self) -> i64 {
+        self.kept() as i64
+    }
+
+    fn max(&self) -> i64 {
+        let die = if self.explode {
+            self.sides * (EXPLOSION_LIMIT + 1)
+        } else {
+            self.sides
+        };
+        self.kept() as i64 * die as i64
+    }
+
+    /// Rolls a single die, exploding at most [`EXPLOSION_LIMIT`] times
+    fn roll_die(&self, rng: &mut Rng, faces: &mut Vec<i64>) -> i64 {
+        let mut total = 0;
+        for explosions in 0..=EXPLOSION_LIMIT {
+            let face = rng.below(self.sides as u64) as i64 + 1;
+            faces.push(face);
+            total += face;
+            if !self.explode || face != self.sides as i64 || explosions == EXPLOSION_LIMIT {
+                break;
+            }
+        }
+        total
+    }
+
+    fn roll(&self, rng: &mut Rng, faces: &mut Vec<i64>) -> i64 {
+        let mut dice = (0..self.count)
+            .map(|_| self.roll_die(rng, faces))
+            .collect::<Vec<_>>();
+        dice.sort();
+        let kept = self.kept() as usize;
+        match self.keep {
+            Keep::All => dice.iter().sum(),
+            Keep::Highest(_) => dice[dice.len() - kept..].iter().sum(),
+            Keep::Lowest(_) => dice[..kept].iter().sum(),
+        }
+    }
+
+    /// The totals of a single die with the number of ways to get each, out of `sides^(explosions + 1)`
+    fn die_outcomes(&self) -> Vec<(i64, u128)> {
+        let sides = self.sides as i64;
+        if !self.explode {
+            return (1..=sides).map(|face| (face, 1)).collect();
+        }
+        // Ending on a face below the highest after `depth` explosions is less likely than
+        // ending after more, since later explosions each divide the remaining chance by `sides`
+        let mut outcomes = vec![];
+        for depth in 0..=EXPLOSION_LIMIT {
+            let weight = (self.sides as u128).pow(EXPLOSION_LIMIT - depth);
+            let last = if depth == EXPLOSION_LIMIT { sides } else { sides - 1 };
+            for face in 1..=last {
+                outcomes.push((depth as i64 * sides + face, weight));
+            }
+        }
+        outcomes
+    }
+
+    /// The exact distribution of the group's total.
+    ///
+    /// Dice are assigned to the values they rolled from the most to the least
+    /// favoured, counting the ways to choose which dice have each value, so
+    /// that the kept dice are always the first ones assigned.
+    pub fn outcomes(&self) -> Result<Outcomes, Error> {
+        let n = self.count as usize;
+        let kept = self.kept() as usize;
+        let mut values = self.die_outcomes();
+        // Roughly the number of steps below, so large groups fail rather than freezing the sheet
+        let work = values.len() as u128 * (n as u128 + 1).pow(2) * (self.max() as u128 + 1);
+        if work > MAX_WORK {
+            return Err(too_many_dice());
+        }
+        if let Keep::Highest(_) = self.keep {
+            values.reverse();
+        }
+
+        // binomial[a][b] = a choose b
+        let mut binomial = vec![vec![0u128; n + 1]; n + 1];
+        for a in 0..=n {
+            binomial[a][0] = 1;
+            for b in 1..=a {
+                binomial[a][b] = binomial[a - 1][b - 1]
+                    .checked_add(binomial[a - 1][b])
+                    .ok_or_else(too_many_dice)?;
+            }
+        }
+
+        // Number of dice assigned so far and the total of the kept ones, to the number of ways
+        let mut states: BTreeMap<(usize, i64), u128> = BTreeMap::from([((0, 0), 1)]);
+        for (value, weight) in values {
+            let mut next = BTreeMap::new();
+            for (&(assigned, sum), &ways) in &states {
+                let mut weight_power = 1u128;
+                for (c, choose) in binomial[n - assigned].iter().enumerate().take(n - assigned + 1) {
+                    let counted = c.min(kept.saturating_sub(assigned)) as i64;
+                    let ways = choose
+                        .checked_mul(weight_power)
+                        .and_then(|w| w.checked_mul(ways))
+                        .ok_or_else(too_many_dice)?;
+                    let entry = next.entry((assigned + c, sum + counted * value)).or_insert(0u128);
+                    *entry = entry.checked_add(ways).ok_or_else(too_many_dice)?;
+                    if c < n - assigned {
+                        weight_power = weight_power.checked_mul(weight).ok_or_else(too_many_dice)?;
+                    }
+                }
+            }
+            states = next;
+        }
+
+        let die_total = if self.explode {
+            (self.sides as u128).pow(EXPLOSION_LIMIT + 1)
+        } else {
+            self.sides as u128
+        };
+        let total = die_total.checked_pow(self.count).ok_or_else(too_many_dice)?;
+        let counts = states
+            .into_iter()
+            .filter(|((assigned, _), _)| *assigned == n)
+            .map(|((_, sum), ways)| (sum, ways))
+            .collect();
+        Ok(Outcomes { counts, total })
+    }
+
+    fn mean(&self) -> Result<Rational, Error> {
+        if self.keep == Keep::All && !self.explode {
+            return Rational::new(self.count as i128 * (self.sides as i128 + 1), 2);
+        }
+        let outcomes = self.outcomes()?;
+        let mut sum: i128 = 0;
+        for (value, ways) in &outcomes.counts {
+            let ways = i128::try_from(*ways).map_err(|_| too_many_dice())?;
+            sum = ways
+                .checked_mul(*value as i128)
+                .and_then(|v| v.checked_add(sum))
+                .ok_or_else(too_many_dice)?;
+        }
+        Rational::new(sum, i128::try_from(outcomes.total).map_err(|_| too_many_dice())?)
+    }
+}
+
+impl From<DiceGroup> for Dice {
+    fn from(group: DiceGroup) -> Self {
+        Dice {
+            groups: vec![(1, group)],
+            constant: 0,
+        }
+    }
+}
+
+impl Dice {
+    pub fn add(&self, other: &Dice) -> Result<Dice, Error> {
+        Ok(Dice {
+            groups: self.groups.iter().chain(&other.groups).cloned().collect(),
+            constant: self.constant.checked_add(other.constant).ok_or_else(overflow)?,
+        })
+    }
+
+    pub fn add_constant(&self, n: i64) -> Result<Dice, Error> {
+        Ok(Dice {
+            groups: self.groups.clone(),
+            constant: self.constant.checked_add(n).ok_or_else(overflow)?,
+        })
+    }
+
+    /// Multiplies every group and the constant by `n`
+    pub fn scale(&self, n: i64) -> Result<Dice, Error> {
+        let groups = self
+            .groups
+            .iter()
+            .filter(|_| n != 0)
+            .map(|(m, group)| Ok((m.checked_mul(n).ok_or_else(overflow)?, *group)))
+            .collect::<Result<_, Error>>()?;
+        Ok(Dice {
+            groups,
+            constant: self.constant.checked_mul(n).ok_or_else(overflow)?,
+        })
+    }
+
+    fn bound(&self, lowest: bool) -> Result<i64, Error> {
+        self.groups.iter().try_fold(self.constant, |total, (m, group)| {
+            let extreme = if (*m >= 0) == lowest { group.min() } else { group.max() };
+            m.checked_mul(extreme)
+                .and_then(|v| v.checked_add(total))
+                .ok_or_else(overflow)
+        })
+    }
+
+    pub fn min_roll(&self) -> Result<i64, Error> {
+        self.bound(true)
+    }
+
+    pub fn max_roll(&self) -> Result<i64, Error> {
+        self.bound(false)
+    }
+
+    /// The exact mean total
+    pub fn mean(&self) -> Result<Rational, Error> {
+        self.groups.iter().try_fold(Rational::from(self.constant), |total, (m, group)| {
+            total.add(&group.mean()?.mul(&Rational::from(*m))?)
+        })
+    }
+
+    /// The mean total rounded down, as averages are in stat blocks
+    pub fn average(&self) -> Result<i64, Error> {
+        i64::try_from(self.mean()?.floor()).map_err(|_| overflow())
+    }
+
+    /// Rolls the dice using a generator seeded with `seed`, so the same seed always gives the same roll
+    pub fn roll(&self, seed: i64) -> Result<Roll, Error> {
+        let mut rng = Rng::new(seed as u64);
+        let mut dice = vec![];
+        let mut total = self.constant;
+        for (m, group) in &self.groups {
+            let rolled = group.roll(&mut rng, &mut dice);
+            total = m
+                .checked_mul(rolled)
+                .and_then(|v| v.checked_add(total))
+                .ok_or_else(overflow)?;
+        }
+        Ok(Roll { total, dice })
+    }
+}
+
+impl Display for DiceGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)?;
+        if self.explode {
+            write!(f, "!")?;
+        }
+        match self.keep {
+            Keep::All => Ok(()),
+            Keep::Highest(n) => write!(f, "kh{}", n),
+            Keep::Lowest(n) => write!(f, "kl{}", n),
+        }
+    }
+}
+
+impl Display for Dice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut terms = self
+            .groups
+            .iter()
+            .map(|(m, group)| match m.unsigned_abs() {
+                1 => (*m < 0, group.to_string()),
+                n => (*m < 0, format!("{} * {}", n, group)),
+            })
+            .collect::<Vec<_>>();
+        if self.constant != 0 || terms.is_empty() {
+            terms.push((self.constant < 0, self.constant.unsigned_abs().to_string()));
+        }
+        for (i, (negative, term)) in terms.iter().enumerate() {
+            match (i, negative) {
+                (0, false) => write!(f, "{}", term)?,
+                (0, true) => write!(f, "-{}", term)?,
+                (_, false) => write!(f, " + {}", term)?,
+                (_, true) => write!(f, " - {}", term)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A small deterministic random number generator (SplitMix64), so that
+/// rolls with the same seed are the same on every platform
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A uniformly distributed number in `0..n`, rejecting values that would bias the result
+    fn below(&mut self, n: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return x % n;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dice(text: &str) -> Dice {
+        DiceGroup::parse(text).unwrap().into()
+    }
+
+    #[test]
+    fn test_parse() {
+        let group = DiceGroup::parse("4d6!kh3").unwrap();
+        assert_eq!(
+            group,
+            DiceGroup {
+                count: 4,
+                sides: 6,
+                keep: Keep::Highest(3),
+                explode: true
+            }
+        );
+        assert_eq!(DiceGroup::parse("2d20kl").unwrap().keep, Keep::Lowest(1));
+        assert!(DiceGroup::parse("0d6").is_err());
+        assert!(DiceGroup::parse("2d6kh3").is_err());
+        assert!(DiceGroup::parse("2d1!").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let d = dice("1d8").scale(2).unwrap().add(&dice("4d6kh3").scale(-1).unwrap()).unwrap();
+        assert_eq!(d.add_constant(-3).unwrap().to_string(), "2 * 1d8 - 4d6kh3 - 3");
+        assert_eq!(dice("3d6!").to_string(), "3d6!");
+        assert_eq!(dice("1d6").scale(0).unwrap().to_string(), "0");
+    }
+
+    #[test]
+    fn test_bounds() {
+        let d = dice("2d6").add_constant(3).unwrap();
+        assert_eq!((d.min_roll().unwrap(), d.max_roll().unwrap()), (5, 15));
+        let d = dice("1d20").add(&dice("1d4").scale(-1).unwrap()).unwrap();
+        assert_eq!((d.min_roll().unwrap(), d.max_roll().unwrap()), (-3, 19));
+        assert_eq!(dice("1d6!").max_roll().unwrap(), 24);
+    }
+
+    #[test]
+    fn test_average() {
+        assert_eq!(dice("2d6").add_constant(3).unwrap().average().unwrap(), 10);
+        assert_eq!(dice("1d8").average().unwrap(), 4);
+        // 2d20 keep highest averages 13.825
+        assert_eq!(dice("2d20kh1").average().unwrap(), 13);
+        assert_eq!(dice("2d20kl1").average().unwrap(), 7);
+        assert_eq!(dice("4d6kh3").average().unwrap(), 12);
+        assert_eq!(dice("1d6").scale(-1).unwrap().average().unwrap(), -4);
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let outcomes = DiceGroup::parse("2d20kh1").unwrap().outcomes().unwrap();
+        assert_eq!(outcomes.total, 400);
+        assert_eq!(outcomes.counts[&20], 39);
+        assert_eq!(outcomes.counts[&1], 1);
+        assert_eq!(outcomes.counts.values().sum::<u128>(), 400);
+
+        let outcomes = DiceGroup::parse("1d4!").unwrap().outcomes().unwrap();
+        assert_eq!(outcomes.counts.values().sum::<u128>(), outcomes.total);
+        assert_eq!(outcomes.counts[&5], 16);
+    }
+
+    #[test]
+    fn test_roll() {
+        let d = dice("4d6kh3").add_constant(2).unwrap();
+        let roll = d.roll(42).unwrap();
+        assert_eq!(roll.dice.len(), 4);
+        assert_eq!(roll.total, d.roll(42).unwrap().total);
+        let mut faces = roll.dice.clone();
+        faces.sort();
+        assert_eq!(roll.total, faces[1..].iter().sum::<i64>() + 2);
+
+        for seed in 0..100 {
+            let total = d.roll(seed).unwrap().total;
+            assert!((5..=20).contains(&total));
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::language::dice::Dice;
+use crate::language::errors::{Error, ErrorKind};
+use crate::language::rational::Rational;
+
+/// The exact chance of each integer outcome of something random, such as the total of some dice.
+///
+/// Only outcomes with a nonzero chance are stored, and the chances add up to one.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Distribution {
+    outcomes: BTreeMap<i64, Rational>,
+}
+
+/// The most pairs of outcomes combined at once, so that adding or comparing large groups of
+/// dice fails rather than freezing the sheet
+const MAX_PAIRS: usize = 250_000;
+
+fn overflow() -> Error {
+    Error::new(ErrorKind::Overflow)
+}
+
+/// The chances of the results of an attack roll
+pub struct AttackChances {
+    /// The chance to hit, including critical hits
+    pub hit: Rational,
+    pub crit: Rational,
+}
+
+impl Distribution {
+    /// An outcome that always happens
+    pub fn constant(n: i64) -> Distribution {
+        Distribution {
+            outcomes: BTreeMap::from([(n, Rational::ONE)]),
+        }
+    }
+
+    /// The chance of each total of some dice
+    pub fn from_dice(dice: &Dice) -> Result<Distribution, Error> {
+        let mut distribution = Distribution::constant(dice.constant);
+        for (m, group) in &dice.groups {
+            let outcomes = group.outcomes()?;
+            let total = i128::try_from(outcomes.total).map_err(|_| Error::new(ErrorKind::TooManyDice))?;
+            let mut group_distribution = Distribution {
+                outcomes: BTreeMap::new(),
+            };
+            for (value, ways) in outcomes.counts {
+                let ways = i128::try_from(ways).map_err(|_| Error::new(ErrorKind::TooManyDice))?;
+                group_distribution.outcomes.insert(value, Rational::new(ways, total)?);
+            }
+            distribution = distribution.add(&group_distribution.scale(*m)?)?;
+        }
+        Ok(distribution)
+    }
+
+    pub fn outcomes(&self) -> &BTreeMap<i64, Rational> {
+        &self.outcomes
+    }
+
+    /// Combines the outcomes of two independent distributions, adding together the chances of
+    /// pairs of outcomes that combine to the same result
+    fn combine(
+        &self,
+        other: &Distribution,
+        f: impl Fn(i64, i64) -> Option<i64>,
+    ) -> Result<Distribution, Error> {
+        if self.outcomes.len().saturating_mul(other.outcomes.len()) > MAX_PAIRS {
+            return Err(Error::new(ErrorKind::TooManyDice));
+        }
+        let mut outcomes = BTreeMap::new();
+        for (a, a_chance) in &self.outcomes {
+            for (b, b_chance) in &other.outcomes {
+                let outcome = f(*a, *b).ok_or_else(overflow)?;
+                let chance = a_chance.mul(b_chance)?;
+                let entry = outcomes.entry(outcome).or_insert(Rational::ZERO);
+                *entry = entry.add(&chance)?;
+            }
+        }
+        Ok(Distribution { outcomes })
+    }
+
+    fn map(&self, f: impl Fn(i64) -> Option<i64>) -> Result<Distribution, Error> {
+        self.combine(&Distribution::constant(0), |a, _| f(a))
+    }
+
+    /// The distribution of the sum of two independent outcomes
+    pub fn add(&self, other: &Distribution) -> Result<Distribution, Error> {
+        self.combine(other, i64::checked_add)
+    }
+
+    pub fn scale(&self, n: i64) -> Result<Distribution, Error> {
+        self.map(|a| a.checked_mul(n))
+    }
+
+    /// The chance that comparing an outcome of `self` to one of `other` passes `test`, as a
+    /// distribution of 1 for passing and 0 for failing
+    pub fn chance(&self, other: &Distribution, test: fn(Ordering) -> bool) -> Result<Distribution, Error> {
+        self.combine(other, |a, b| Some(test(a.cmp(&b)) as i64))
+    }
+
+    /// The chance that comparing an outcome to the fraction `r` passes `test`, as a distribution
+    /// of 1 for passing and 0 for failing
+    pub fn chance_rational(&self, r: &Rational, test: impl Fn(Ordering) -> bool) -> Result<Distribution, Error> {
+        self.map(|a| Some(test(Rational::from(a).cmp(r)) as i64))
+    }
+
+    /// Swaps the chances of zero and nonzero outcomes, so that events that pass fail
+    pub fn not(&self) -> Result<Distribution, Error> {
+        self.map(|a| Some((a == 0) as i64))
+    }
+
+    /// The chance of a nonzero outcome, which for events is the chance they pass
+    pub fn probability(&self) -> Result<Rational, Error> {
+        self.outcomes
+            .iter()
+            .filter(|(outcome, _)| **outcome != 0)
+            .try_fold(Rational::ZERO, |total, (_, chance)| total.add(chance))
+    }
+
+    /// The expected outcome
+    pub fn mean(&self) -> Result<Rational, Error> {
+        self.outcomes
+            .iter()
+            .try_fold(Rational::ZERO, |total, (outcome, chance)| {
+                total.add(&chance.mul(&Rational::from(*outcome))?)
+            })
+    }
+
+    /// The higher of two independent outcomes, as when rolling with advantage
+    pub fn advantage(&self) -> Result<Distribution, Error> {
+        // P(max = x) = P(X <= x)^2 - P(X < x)^2
+        let mut outcomes = BTreeMap::new();
+        let (mut below, mut below_squared) = (Rational::ZERO, Rational::ZERO);
+        for (outcome, chance) in &self.outcomes {
+            let at_most = below.add(chance)?;
+            let at_most_squared = at_most.mul(&at_most)?;
+            outcomes.insert(*outcome, at_most_squared.sub(&below_squared)?);
+            (below, below_squared) = (at_most, at_most_squared);
+        }
+        Ok(Distribution { outcomes })
+    }
+
+    /// The lower of two independent outcomes, as when rolling with disadvantage
+    pub fn disadvantage(&self) -> Result<Distribution, Error> {
+        self.scale(-1)?.advantage()?.scale(-1)
+    }
+
+    /// The chances to hit and to crit with an attack roll of this distribution.
+    ///
+    /// Rolls of `crit_range` or more are critical hits, which always hit, and rolls of 1 always
+    /// miss. Otherwise the attack hits when the roll plus `bonus` is at least `ac`.
+    pub fn attack(&self, bonus: i64, ac: i64, crit_range: i64) -> Result<AttackChances, Error> {
+        let mut chances = AttackChances {
+            hit: Rational::ZERO,
+            crit: Rational::ZERO,
+        };
+        for (roll, chance) in &self.outcomes {
+            let crit = *roll >= crit_range;
+            if crit {
+                chances.crit = chances.crit.add(chance)?;
+            }
+            if crit || (*roll != 1 && roll.checked_add(bonus).ok_or_else(overflow)? >= ac) {
+                chances.hit = chances.hit.add(chance)?;
+            }
+        }
+        Ok(chances)
+    }
+}
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let outcomes = self
+            .outcomes
+            .iter()
+            .map(|(outcome, chance)| format!("{}: {}", outcome, chance))
+            .collect::<Vec<_>>();
+        write!(f, "{}", outcomes.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::dice::DiceGroup;
+
+    fn distribution(text: &str) -> Distribution {
+        Distribution::from_dice(&DiceGroup::parse(text).unwrap().into()).unwrap()
+    }
+
+    fn r(n: i128, d: i128) -> Rational {
+        Rational::new(n, d).unwrap()
+    }
+
+    #[test]
+    fn test_from_dice() {
+        let d = distribution("2d6");
+        assert_eq!(d.outcomes().len(), 11);
+        assert_eq!(d.outcomes()[&7], r(1, 6));
+        assert_eq!(d.mean().unwrap(), r(7, 1));
+    }
+
+    #[test]
+    fn test_convolution() {
+        let d = distribution("1d4").add(&distribution("1d4").scale(-1).unwrap()).unwrap();
+        assert_eq!(d.outcomes()[&0], r(1, 4));
+        assert_eq!(d.outcomes()[&-3], r(1, 16));
+        assert_eq!(d.mean().unwrap(), Rational::ZERO);
+    }
+
+    #[test]
+    fn test_too_many_pairs() {
+        let d = distribution("1d600");
+        assert_eq!(distribution("10d20").add(&distribution("10d20")).unwrap().outcomes().len(), 381);
+        let e = d.add(&distribution("1d500")).unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::TooManyDice));
+        assert!(matches!(d.chance(&d, Ordering::is_gt).unwrap_err().kind, ErrorKind::TooManyDice));
+    }
+
+    #[test]
+    fn test_advantage() {
+        let d20 = distribution("1d20");
+        assert_eq!(d20.advantage().unwrap(), distribution("2d20kh1"));
+        assert_eq!(d20.disadvantage().unwrap(), distribution("2d20kl1"));
+    }
+
+    #[test]
+    fn test_chance() {
+        let hit = distribution("1d20").chance(&Distribution::constant(11), Ordering::is_ge).unwrap();
+        assert_eq!(hit.probability().unwrap(), r(1, 2));
+        assert_eq!(hit.not().unwrap().probability().unwrap(), r(1, 2));
+        let above = distribution("1d20").chance_rational(&r(21, 2), Ordering::is_ge).unwrap();
+        assert_eq!(above.probability().unwrap(), r(1, 2));
+    }
+
+    #[test]
+    fn test_attack() {
+        let chances = distribution("1d20").attack(5, 30, 19).unwrap();
+        assert_eq!(chances.hit, r(1, 10));
+        assert_eq!(chances.crit, r(1, 10));
+        let chances = distribution("1d20").attack(10, 5, 20).unwrap();
+        assert_eq!(chances.hit, r(19, 20));
+    }
+}
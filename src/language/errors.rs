@@ -1,17 +1,295 @@
+use std::fmt::Display;
+
+use crate::language::ast::ValueType;
+use crate::language::types::Type;
+use crate::language::units::Dimension;
+
+/// A byte range into the source text of a formula.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Returns the smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// The line of source an error occurred on, with the offending range underlined.
+#[derive(Debug, Clone)]
+pub struct Snippet {
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column number, counted in characters
+    pub column: usize,
+    pub source_line: String,
+    pub underline: String,
+}
+
+impl Snippet {
+    fn render(source: &str, span: Span) -> Snippet {
+        let start = span.start.min(source.len());
+        let end = span.end.clamp(start, source.len());
+
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let source_line = &source[line_start..line_end];
+
+        let column = source[line_start..start].chars().count();
+        let width = source[start..end.min(line_end)].chars().count().max(1);
+
+        Snippet {
+            line: source[..line_start].matches('\n').count() + 1,
+            column: column + 1,
+            source_line: source_line.to_string(),
+            underline: format!("{}{}", " ".repeat(column), "^".repeat(width)),
+        }
+    }
+}
+
+impl Display for Snippet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}:\n{}\n{}",
+            self.line, self.column, self.source_line, self.underline
+        )
+    }
+}
+
+/// The kind of error raised while parsing or evaluating a formula.
+#[derive(Debug, Clone)]
+pub enum ErrorKind {
+    /// The formula text could not be parsed
+    Parse(String),
+    /// A name was not bound locally, a builtin, or a cell
+    UnknownName(String),
+    /// An explicit `$cell` reference to a cell that does not exist
+    UnknownCell(String),
+    /// A value had the wrong type.
+    ///
+    /// `position` is the 0-based index of the offending argument when the
+    /// value was passed to a function, and `None` otherwise (e.g. calling a
+    /// non-function or accessing a field of a non-record).
+    TypeMismatch {
+        expected: Vec<ValueType>,
+        actual: ValueType,
+        position: Option<usize>,
+    },
+    /// The type checker found a value whose type does not match how it is used
+    StaticTypeMismatch { expected: Type, actual: Type },
+    /// A function was called with the wrong number of arguments
+    Arity { expected: Vec<usize>, actual: usize },
+    /// A named argument did not match any of the function's parameter names
+    UnknownArgument { name: String, params: Vec<String> },
+    /// An argument was given both by position and by name
+    DuplicateArgument(String),
+    /// A parameter without a default was not given a value
+    MissingArgument(String),
+    /// An operation that needs at least one element was given an empty list
+    EmptyList,
+    /// A range would have more elements than the limit
+    RangeTooLong { len: i128, max: i64 },
+    /// A list was indexed outside of its bounds
+    Index { index: i64, len: usize },
+    /// An integer was divided by zero
+    DivisionByZero,
+    /// Integer arithmetic produced a result that does not fit in 64 bits
+    Overflow,
+    /// Dice have too many outcomes to work out their distribution exactly
+    TooManyDice,
+    /// Quantities of different dimensions were added or compared, where `None` is a plain number
+    DimensionMismatch(Option<Dimension>, Option<Dimension>),
+    /// A unit name was not recognised
+    UnknownUnit(String),
+    /// A string could not be parsed as an integer
+    InvalidInteger(String),
+    /// A record did not contain the requested field
+    MissingField(String),
+    /// No arm of a match expression matched the value, given as an s-expression
+    NoMatch(String),
+    /// A list pattern was matched against a list of the wrong length.
+    ///
+    /// `at_least` is set when the pattern has a rest pattern, so longer lists also match.
+    ListLength {
+        expected: usize,
+        at_least: bool,
+        actual: usize,
+    },
+    /// A literal pattern was matched against a different value, both given as s-expressions
+    PatternMismatch { pattern: String, value: String },
+    /// The cell depends on its own value
+    Cycle(String),
+    /// A cell read by the formula is itself in error
+    Propagated { cell: String, cause: Box<Error> },
+}
 
 #[derive(Debug, Clone)]
 pub struct Error {
-    pub message: String,
+    pub kind: ErrorKind,
+    pub span: Option<Span>,
+    pub snippet: Option<Box<Snippet>>,
 }
 
 impl Error {
-    pub fn with_message<'a>(message: impl Into<String>) -> Self {
+    pub fn new(kind: ErrorKind) -> Self {
         Error {
-            message: message.into(),
+            kind,
+            span: None,
+            snippet: None,
+        }
+    }
+
+    pub fn propagated(cell_name: &str, cause: Error) -> Self {
+        Error::new(ErrorKind::Propagated {
+            cell: cell_name.to_string(),
+            cause: Box::new(cause),
+        })
+    }
+
+    pub fn type_mismatch(expected: ValueType, actual: ValueType, position: Option<usize>) -> Self {
+        Error::new(ErrorKind::TypeMismatch {
+            expected: vec![expected],
+            actual,
+            position,
+        })
+    }
+
+    /// Iterates over this error followed by the errors it was propagated from.
+    pub fn causes(&self) -> impl Iterator<Item = &Error> {
+        std::iter::successors(Some(self), |e| match &e.kind {
+            ErrorKind::Propagated { cause, .. } => Some(cause),
+            _ => None,
+        })
+    }
+
+    /// The error that originally caused this one, following propagation through cells.
+    pub fn root_cause(&self) -> &Error {
+        self.causes().last().unwrap()
+    }
+
+    /// Attaches a span to the error if it does not already have one.
+    ///
+    /// Errors are created without a location and pick up the span of the
+    /// innermost expression they pass through, so this is a no-op once
+    /// a span has been set.
+    pub fn or_span(mut self, span: Span) -> Self {
+        if self.span.is_none() {
+            self.span = Some(span);
         }
+        self
     }
 
-    pub fn propogated_error(cell_name: &str) -> Self {
-        Error::with_message(format!("Error in read cell {}", cell_name))
+    /// Renders the snippet for this error against the source it was produced from.
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.snippet = self.span.map(|span| Box::new(Snippet::render(source, span)));
+        self
+    }
+}
+
+fn join_alternatives<T: Display>(items: &[T]) -> String {
+    match items {
+        [] => "nothing".to_string(),
+        [item] => item.to_string(),
+        [rest @ .., last] => format!(
+            "{} or {}",
+            rest.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", "),
+            last
+        ),
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Parse(message) => write!(f, "Parse Error: {}", message),
+            ErrorKind::UnknownName(name) => write!(f, "Unknown name \"{}\"", name),
+            ErrorKind::UnknownCell(name) => write!(f, "Unknown cell name \"{}\"", name),
+            ErrorKind::TypeMismatch {
+                expected,
+                actual,
+                position,
+            } => {
+                write!(f, "Expected {}", join_alternatives(expected))?;
+                if let Some(position) = position {
+                    write!(f, " for argument {}", position + 1)?;
+                }
+                write!(f, " but found {}", actual)
+            }
+            ErrorKind::StaticTypeMismatch { expected, actual } => {
+                write!(f, "Type error: expected {} but found {}", expected, actual)
+            }
+            ErrorKind::Arity { expected, actual } => write!(
+                f,
+                "Expected {} arguments but found {}",
+                join_alternatives(expected),
+                actual
+            ),
+            ErrorKind::UnknownArgument { name, params } if params.is_empty() => {
+                write!(f, "No parameter named {}, the function has no named parameters", name)
+            }
+            ErrorKind::UnknownArgument { name, params } => write!(
+                f,
+                "No parameter named {}, expected {}",
+                name,
+                join_alternatives(params)
+            ),
+            ErrorKind::DuplicateArgument(name) => {
+                write!(f, "Argument {} is given both by position and by name", name)
+            }
+            ErrorKind::MissingArgument(name) => write!(f, "No value given for parameter {}", name),
+            ErrorKind::EmptyList => write!(f, "List is empty"),
+            ErrorKind::RangeTooLong { len, max } => {
+                write!(f, "Range of {} numbers is longer than the maximum of {}", len, max)
+            }
+            ErrorKind::Index { index, len } => {
+                write!(f, "Index {} out of range for list of length {}", index, len)
+            }
+            ErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            ErrorKind::Overflow => write!(f, "Integer overflow"),
+            ErrorKind::TooManyDice => write!(f, "Too many dice to work out exactly"),
+            ErrorKind::DimensionMismatch(a, b) => {
+                let name = |d: &Option<Dimension>| d.map_or("a number".to_string(), |d| format!("a {}", d));
+                write!(f, "Cannot combine {} with {}", name(a), name(b))
+            }
+            ErrorKind::UnknownUnit(unit) => write!(f, "Unknown unit \"{}\"", unit),
+            ErrorKind::InvalidInteger(s) => write!(f, "\"{}\" is not a valid integer", s),
+            ErrorKind::MissingField(field) => write!(f, "Field \"{}\" does not exist", field),
+            ErrorKind::NoMatch(value) => write!(f, "No match arm matched the value {}", value),
+            ErrorKind::ListLength {
+                expected,
+                at_least,
+                actual,
+            } => write!(
+                f,
+                "Expected a list of {}{} elements but found {}",
+                if *at_least { "at least " } else { "" },
+                expected,
+                actual
+            ),
+            ErrorKind::PatternMismatch { pattern, value } => {
+                write!(f, "Expected {} but found {}", pattern, value)
+            }
+            ErrorKind::Cycle(cell) => write!(f, "Circular dependency in cell {}", cell),
+            ErrorKind::Propagated { cell, cause } => {
+                write!(f, "Error in read cell {}: {}", cell, cause.kind)
+            }
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.snippet {
+            Some(snippet) => write!(f, "{} at {}", self.kind, snippet),
+            None => write!(f, "{}", self.kind),
+        }
     }
 }
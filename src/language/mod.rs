@@ -1,8 +1,13 @@
 pub mod ast;
 pub mod bultins;
+pub mod dice;
+pub mod distribution;
 pub mod errors;
 mod parser;
+pub mod rational;
 pub mod s_exprs;
 pub mod treewalk;
+pub mod types;
+pub mod units;
 
 pub use parser::validate_name;
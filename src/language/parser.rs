@@ -1,29 +1,45 @@
 #![allow(unused)]
 
+use std::collections::BTreeMap;
 use std::iter::Peekable;
 
 use plex::lexer;
 
 use crate::language::ast::AST;
 use crate::language::ast::Binding;
+use crate::language::ast::Expr;
 use crate::language::ast::Function;
+use crate::language::ast::MatchArm;
+use crate::language::ast::Param;
+use crate::language::ast::Pattern;
+use crate::language::ast::PatternKind;
 use crate::language::ast::Value;
 use crate::language::bultins::BuiltinFunction;
+use crate::language::dice::DiceGroup;
 use crate::language::errors::Error;
+use crate::language::errors::ErrorKind;
+use crate::language::errors::Span;
 use crate::language::parser::precedence::*;
+use crate::language::rational::Rational;
+use crate::language::units::{Quantity, Unit};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
     Whitespace,
+    Comment,
     Error,
 
     // Literals
     IntLit,
+    DecimalLit,
+    DiceLit,
     StringLit,
+    InterpolatedStringLit,
     True,
     False,
     Name,
     CellName,
+    Tag,
 
     LParen,
     RParen,
@@ -35,6 +51,10 @@ pub enum TokenType {
     // Punctuation
     Comma,
     Dot,
+    DotDot,
+    DotDotEq,
+    Ellipsis,
+    Bar,
     Colon,
     SemiColon,
     Arrow,
@@ -44,6 +64,8 @@ pub enum TokenType {
     Plus,
     Minus,
     Star,
+    Slash,
+    Percent,
 
     // Boolean Operators
     And,
@@ -60,6 +82,9 @@ pub enum TokenType {
 
     // Misc Operators
     SlashSlash, // Record merge
+    QuestionDot, // Optional field access
+    QuestionQuestion, // Default for unit or missing values
+    Pipe, // Pipeline
 
     // Keywords
     Fn,
@@ -68,12 +93,17 @@ pub enum TokenType {
     If,
     Then,
     Else,
+    Match,
+    With,
+    Catch,
+    For,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Token<'a> {
     pub token_type: TokenType,
     pub text: &'a str,
+    pub span: Span,
 }
 
 // Lexer generated by plex
@@ -82,6 +112,10 @@ lexer! {
 
     r#"[ \t\n\r]+"# => TokenType::Whitespace,
 
+    // Line comments run to the end of the line, block comments are delimited by {- and -}
+    r#"--[^\n]*"# => TokenType::Comment,
+    r#"{-([^-]|-+[^-}])*-+}"# => TokenType::Comment,
+
     r#"\("# => TokenType::LParen,
     r#"\)"# => TokenType::RParen,
 
@@ -103,9 +137,16 @@ lexer! {
     r#"<="# => TokenType::LtEq,
 
     r#"//"# => TokenType::SlashSlash,
+    r#"\?\."# => TokenType::QuestionDot,
+    r#"\?\?"# => TokenType::QuestionQuestion,
 
     r#","# => TokenType::Comma,
     r#"\."# => TokenType::Dot,
+    r#"\.\."# => TokenType::DotDot,
+    r#"\.\.="# => TokenType::DotDotEq,
+    r#"\.\.\."# => TokenType::Ellipsis,
+    r#"\|"# => TokenType::Bar,
+    r#"\|>"# => TokenType::Pipe,
     r#":"# => TokenType::Colon,
     r#";"# => TokenType::SemiColon,
     r#"->"# => TokenType::Arrow,
@@ -114,9 +155,15 @@ lexer! {
     r#"\+"# => TokenType::Plus,
     r#"-"# => TokenType::Minus,
     r#"\*"# => TokenType::Star,
+    r#"/"# => TokenType::Slash,
+    r#"%"# => TokenType::Percent,
 
     r#"[0-9]+"# => TokenType::IntLit,
-    r#""[^"]*""# => TokenType::StringLit, //TODO escape chars
+    r#"[0-9]+\.[0-9]+"# => TokenType::DecimalLit,
+    // Dice such as 2d6, 3d6! (exploding) and 4d6kh3 (keep the highest 3)
+    r#"[0-9]+d[0-9]+!?(k[hl][0-9]*)?"# => TokenType::DiceLit,
+    r#""([^"\\]|\\.)*""# => TokenType::StringLit,
+    r#"f"([^"\\]|\\.)*""# => TokenType::InterpolatedStringLit,
 
     r#"fn"# => TokenType::Fn,
     r#"let"# => TokenType::Let,
@@ -127,27 +174,44 @@ lexer! {
     r#"if"# => TokenType::If,
     r#"then"# => TokenType::Then,
     r#"else"# => TokenType::Else,
+    r#"match"# => TokenType::Match,
+    r#"with"# => TokenType::With,
+    r#"catch"# => TokenType::Catch,
+    r#"for"# => TokenType::For,
 
     // Cell names are regular names prefixed with a $ to specifically indicate cell references
     r#"$[a-zA-Z_][a-zA-Z0-9_]*"# => TokenType::CellName,
     r#"[a-zA-Z_][a-zA-Z0-9_]*"# => TokenType::Name,
+    r#"#[a-zA-Z_][a-zA-Z0-9_]*"# => TokenType::Tag,
 
     r#"."# => TokenType::Error,
 }
 
+#[derive(Clone)]
 struct Lexer<'a> {
     current: &'a str,
+    // Byte offset of `current` into the original text
+    offset: usize,
 }
 
 impl<'a> Lexer<'a> {
     fn new(text: &'a str) -> Lexer<'a> {
-        Lexer { current: text }
+        Lexer::with_offset(text, 0)
+    }
+
+    /// Creates a lexer for a slice of a larger text starting at `offset`,
+    /// so that spans are relative to the larger text.
+    fn with_offset(text: &'a str, offset: usize) -> Lexer<'a> {
+        Lexer {
+            current: text,
+            offset,
+        }
     }
 }
 
 pub fn validate_name(name: &str) -> bool {
     let mut lexer = Lexer::new(name);
-    lexer.next().map_or(false, |t| t.token_type == TokenType::Name) && lexer.next().is_none()
+    lexer.next().is_some_and(|t| t.token_type == TokenType::Name) && lexer.next().is_none()
 }
 
 impl<'a> Iterator for Lexer<'a> {
@@ -156,15 +220,22 @@ impl<'a> Iterator for Lexer<'a> {
     fn next(&mut self) -> Option<Token<'a>> {
         loop {
             let token = next_token(self.current).map(|(t, rest)| {
+                let mut len = self.current.len() - rest.len();
+                // `3d6!=4` is a comparison with plain dice rather than exploding dice followed by `=`
+                if t == TokenType::DiceLit && self.current[..len].ends_with('!') && rest.starts_with('=') {
+                    len -= 1;
+                }
                 let token = Token {
                     token_type: t,
-                    text: &self.current[0..self.current.len() - rest.len()],
+                    text: &self.current[0..len],
+                    span: Span::new(self.offset, self.offset + len),
                 };
-                self.current = rest;
+                self.current = &self.current[len..];
+                self.offset += len;
                 token
             });
             if let Some(Token {
-                token_type: TokenType::Whitespace,
+                token_type: TokenType::Whitespace | TokenType::Comment,
                 ..
             }) = token
             {
@@ -176,6 +247,154 @@ impl<'a> Iterator for Lexer<'a> {
     }
 }
 
+/// Decodes the escape sequences in the body of a string literal.
+///
+/// Supports `\"`, `\\`, `\n`, `\r`, `\t` and `\u{...}` with a hexadecimal code point.
+/// `offset` is the position of `body` in the source text, used to report invalid escapes.
+fn unescape(body: &str, offset: usize) -> Result<String, Error> {
+    let mut result = String::with_capacity(body.len());
+    let mut chars = body.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        // Offset of the backslash in the source text
+        let escape_start = offset + i;
+        let escape_error = |len: usize, message: &str| {
+            Error::parse_error(message).or_span(Span::new(escape_start, escape_start + len))
+        };
+
+        match chars.next() {
+            Some((_, '"')) => result.push('"'),
+            Some((_, '\\')) => result.push('\\'),
+            Some((_, 'n')) => result.push('\n'),
+            Some((_, 'r')) => result.push('\r'),
+            Some((_, 't')) => result.push('\t'),
+            Some((_, 'u')) => {
+                let rest = &body[i + 2..];
+                let code = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .map(|(code, _)| code)
+                    .ok_or_else(|| escape_error(2, "Expected {...} after \\u"))?;
+                // Length of the whole escape including \u{ and }
+                let len = code.len() + 4;
+                let c = u32::from_str_radix(code, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| escape_error(len, "Invalid unicode escape"))?;
+                result.push(c);
+                // Skip over the code point and braces
+                for _ in 0..code.chars().count() + 2 {
+                    chars.next();
+                }
+            }
+            Some((_, c)) => {
+                return Err(escape_error(1 + c.len_utf8(), "Unknown escape sequence"));
+            }
+            None => unreachable!("the lexer never produces a trailing backslash"),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Parses the body of an interpolated string literal such as `f"AC {ac}"`.
+///
+/// The literal is desugared into string concatenations, with each `{expr}`
+/// converted using `to_string`. Literal braces are written `{{` and `}}`.
+/// `offset` is the position of `body` in the source text.
+fn parse_interpolated(body: &str, offset: usize, span: Span) -> Result<AST, Error> {
+    let mut parts = vec![];
+    let mut literal = String::new();
+    // Start of the run of literal text which has not been unescaped yet
+    let mut run_start = 0;
+    let mut chars = body.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // Escapes are decoded along with the rest of the run
+            '\\' => {
+                chars.next();
+            }
+            '{' | '}' if chars.next_if(|&(_, next)| next == c).is_some() => {
+                literal += &unescape(&body[run_start..i], offset + run_start)?;
+                literal.push(c);
+                run_start = i + 2;
+            }
+            '}' => {
+                return Err(Error::parse_error("Unmatched } in interpolated string, use }} for a literal brace")
+                    .or_span(Span::new(offset + i, offset + i + 1)));
+            }
+            '{' => {
+                literal += &unescape(&body[run_start..i], offset + run_start)?;
+                if !literal.is_empty() {
+                    parts.push(AST::from(Value::String(std::mem::take(&mut literal))).at(span));
+                }
+
+                // Find the matching close brace, allowing for records inside the expression
+                let mut depth = 0;
+                let close = chars.find_map(|(j, c)| match c {
+                    '{' => {
+                        depth += 1;
+                        None
+                    }
+                    '}' if depth == 0 => Some(j),
+                    '}' => {
+                        depth -= 1;
+                        None
+                    }
+                    _ => None,
+                });
+                let close = close.ok_or_else(|| {
+                    Error::parse_error("Unclosed { in interpolated string")
+                        .or_span(Span::new(offset + i, offset + i + 1))
+                })?;
+
+                let expr = parse_at(&body[i + 1..close], offset + i + 1)?;
+                let expr_span = expr.span;
+                parts.push(AST::function(
+                    AST::from(BuiltinFunction::ToString).at(expr_span),
+                    vec![expr],
+                    expr_span,
+                ));
+                run_start = close + 1;
+            }
+            _ => {}
+        }
+    }
+    literal += &unescape(&body[run_start..], offset + run_start)?;
+    if !literal.is_empty() || parts.is_empty() {
+        parts.push(AST::from(Value::String(literal)).at(span));
+    }
+
+    Ok(parts
+        .into_iter()
+        .reduce(|lhs, rhs| AST::function(AST::from(BuiltinFunction::Add).at(span), vec![lhs, rhs], span))
+        .unwrap())
+}
+
+/// The positional and named arguments of a call
+type Arguments = (Vec<AST>, Vec<(String, AST)>);
+
+/// Checks that patterns binding names in the same scope do not bind any name twice.
+fn check_distinct_names(patterns: &[Pattern]) -> Result<(), Error> {
+    let mut seen = vec![];
+    for pattern in patterns {
+        for name in pattern.bound_names() {
+            if seen.contains(&name) {
+                return Err(Error::parse_error(format!("\"{}\" is bound more than once", name))
+                    .or_span(pattern.span));
+            }
+            seen.push(name);
+        }
+    }
+    Ok(())
+}
+
 macro_rules! token_type {
     ($token_type:ident) => {
         Some(Token {
@@ -188,17 +407,29 @@ macro_rules! token_type {
         Some(Token {
             token_type: TokenType::$token_type,
             text: $text,
+            ..
         })
     };
 }
 
 pub struct Parser<'a> {
     tokens: Peekable<Lexer<'a>>,
+    // Span of the end of the text, used to report errors at end of input
+    eof: Span,
+    // End of the most recently consumed token
+    prev_end: usize,
 }
 
 impl Error {
     fn parse_error(message: impl Into<String>) -> Self {
-        Error::with_message(format!("Parse Error: {}", message.into()))
+        Error::new(ErrorKind::Parse(message.into()))
+    }
+
+    fn unexpected_token(token: Option<Token>, eof: Span) -> Self {
+        match token {
+            Some(t) => Error::parse_error(format!("Unexpected token \"{}\"", t.text)).or_span(t.span),
+            None => Error::parse_error("Unexpected end of input").or_span(eof),
+        }
     }
 }
 
@@ -206,11 +437,16 @@ impl Error {
 ///
 /// Returns an error if the string is not a valid expression.
 pub fn parse(text: &str) -> Result<AST, Error> {
-    let mut parser = Parser::new(text);
+    parse_at(text, 0).map_err(|e| e.with_source(text))
+}
+
+/// Parses a slice of a larger text which starts at `offset`.
+fn parse_at(text: &str, offset: usize) -> Result<AST, Error> {
+    let mut parser = Parser::new(text, offset);
     let expr = parser.parse_expr(BindingPower::zero())?;
     match parser.next() {
         None => Ok(expr),
-        Some(t) => Err(Error::parse_error(format!("Unexpected token: {}", t.text))),
+        t => Err(Error::unexpected_token(t, parser.eof)),
     }
 }
 
@@ -218,17 +454,35 @@ impl<'a> Iterator for Parser<'a> {
     type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.tokens.next()
+        let token = self.tokens.next();
+        if let Some(t) = &token {
+            self.prev_end = t.span.end;
+        }
+        token
     }
 }
 
 impl<'a> Parser<'a> {
-    fn new(text: &'a str) -> Self {
+    fn new(text: &'a str, offset: usize) -> Self {
+        let end = offset + text.len();
         Self {
-            tokens: Lexer::new(text).peekable(),
+            tokens: Lexer::with_offset(text, offset).peekable(),
+            eof: Span::new(end, end),
+            prev_end: offset,
         }
     }
 
+    /// Returns the span of the next token, or the end of the text if there are none left.
+    fn peek_span(&mut self) -> Span {
+        let eof = self.eof;
+        self.peek().map_or(eof, |t| t.span)
+    }
+
+    /// Returns the span from `start` to the end of the last consumed token.
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(start, self.prev_end)
+    }
+
     pub fn peek(&mut self) -> Option<&Token<'a>> {
         self.tokens.peek()
     }
@@ -240,7 +494,7 @@ impl<'a> Parser<'a> {
     fn expect_token(&mut self, token_type: TokenType) -> Result<Token<'a>, Error> {
         match self.next() {
             Some(t) if t.token_type == token_type => Ok(t),
-            _ => Err(Error::parse_error("Unexpected token")),
+            t => Err(Error::unexpected_token(t, self.eof)),
         }
     }
 
@@ -252,9 +506,70 @@ impl<'a> Parser<'a> {
     /// only if it matches a certain type, but do not want to generate an error if
     /// it does not match.
     fn next_if_eq(&mut self, token_type: TokenType) -> Option<Token<'a>> {
-        self.tokens.next_if(|t| t.token_type == token_type)
+        let token = self.tokens.next_if(|t| t.token_type == token_type);
+        if let Some(t) = &token {
+            self.prev_end = t.span.end;
+        }
+        token
     }
     
+    /// Parses an operator section after its opening bracket, if there is one.
+    ///
+    /// `(op)` is the operator as a function of both operands and `(op rhs)` is a function of
+    /// the left operand. `(- x)` is a negation rather than a section.
+    fn parse_section(&mut self) -> Result<Option<AST>, Error> {
+        let Some(&op) = self.peek() else {
+            return Ok(None);
+        };
+        let builtin = match op.token_type {
+            TokenType::Plus => BuiltinFunction::Add,
+            TokenType::Minus => BuiltinFunction::Sub,
+            TokenType::Star => BuiltinFunction::Mul,
+            TokenType::Slash => BuiltinFunction::Div,
+            TokenType::Percent => BuiltinFunction::Mod,
+            TokenType::SlashSlash => BuiltinFunction::RecordUpdate,
+            TokenType::QuestionQuestion => BuiltinFunction::Coalesce,
+            TokenType::Lt => BuiltinFunction::LessThan,
+            TokenType::LtEq => BuiltinFunction::LessThanEqual,
+            TokenType::Gt => BuiltinFunction::GreaterThan,
+            TokenType::GtEq => BuiltinFunction::GreaterThanEqual,
+            TokenType::EqEq | TokenType::NotEq => BuiltinFunction::Equals,
+            TokenType::And => BuiltinFunction::And,
+            TokenType::Or => BuiltinFunction::Or,
+            _ => return Ok(None),
+        };
+        let closes = |t: Option<Token>| t.is_some_and(|t| t.token_type == TokenType::RParen);
+        if op.token_type == TokenType::Minus && !closes(self.tokens.clone().nth(1)) {
+            return Ok(None);
+        }
+        self.next();
+
+        let apply = |lhs: AST, rhs: AST, span: Span| {
+            let result = AST::function(AST::from(builtin).at(op.span), vec![lhs, rhs], span);
+            if op.token_type == TokenType::NotEq {
+                AST::function(AST::from(BuiltinFunction::Not).at(op.span), vec![result], span)
+            } else {
+                result
+            }
+        };
+        let param = |i| AST::from(Pattern::generated_name(i)).at(op.span);
+
+        let (params, body) = if self.next_if_eq(TokenType::RParen).is_some() {
+            if op.token_type != TokenType::NotEq {
+                return Ok(Some(AST::from(builtin)));
+            }
+            let params = vec![Pattern::generated(0, op.span), Pattern::generated(1, op.span)];
+            (params, apply(param(0), param(1), op.span))
+        } else {
+            let rhs = self.parse_expr(BindingPower::zero())?;
+            self.expect_token(TokenType::RParen)?;
+            let span = op.span.to(rhs.span);
+            (vec![Pattern::generated(0, op.span)], apply(param(0), rhs, span))
+        };
+        let params = params.into_iter().map(|p| Param::new(p, None)).collect();
+        Ok(Some(AST::from(Value::Function(Function::Lambda(params, Box::new(body))))))
+    }
+
     /// Parses an expression from the given parser.
     ///
     /// The expression is parsed with a minimum binding power of `min_bp`.
@@ -263,9 +578,19 @@ impl<'a> Parser<'a> {
     ///
     /// Returns the parsed expression, or an error if the expression is invalid.
     fn parse_expr(&mut self, min_bp: BindingPower) -> Result<AST, Error> {
+        self.parse_expr_call(min_bp).map(|(expr, _)| expr)
+    }
+
+    /// Parses an expression like [`Parser::parse_expr`], and also returns whether the outermost
+    /// part of it is a call written as `f(args)` or `value.f(args)`.
+    ///
+    /// Pipes use this to tell calls in the source from calls made by desugaring other syntax.
+    fn parse_expr_call(&mut self, min_bp: BindingPower) -> Result<(AST, bool), Error> {
         // Generates a pattern for a token struct which matches a specific token type
 
         let mut lhs;
+        let mut written_call = false;
+        let start = self.peek_span().start;
 
         macro_rules! token_type {
             ($token_type:ident) => {
@@ -279,6 +604,7 @@ impl<'a> Parser<'a> {
                 Some(Token {
                     token_type: TokenType::$token_type,
                     text: $text,
+                    ..
                 })
             };
         }
@@ -303,153 +629,257 @@ impl<'a> Parser<'a> {
         macro_rules! infix_op {
             ($prec:expr, $assoc:ident, $func:ident) => {{
                 let prec = BindingPower::infix($prec, Assoc::$assoc);
-                if should_break(&prec, &min_bp)? {
+                let op_span = self.peek_span();
+                if should_break(&prec, &min_bp).map_err(|e| e.or_span(op_span))? {
                     break;
                 }
-                self.tokens.next();
+                let op = self.next().unwrap();
                 let rhs = self.parse_expr(prec)?;
-                lhs = AST::function(BuiltinFunction::$func, vec![lhs, rhs]);
+                lhs = AST::function(
+                    AST::from(BuiltinFunction::$func).at(op.span),
+                    vec![lhs, rhs],
+                    self.span_from(start),
+                );
             }};
 
             ($prec:expr, $assoc:ident, $func:expr) => {{
                 let prec = BindingPower::infix($prec, Assoc::$assoc);
-                if should_break(&prec, &min_bp)? {
+                let op_span = self.peek_span();
+                if should_break(&prec, &min_bp).map_err(|e| e.or_span(op_span))? {
                     break;
                 }
-                self.tokens.next();
+                let op = self.next().unwrap();
                 let rhs = self.parse_expr(prec)?;
-                lhs = $func(rhs);
+                lhs = $func(op.span, rhs);
             }};
         }
 
         macro_rules! prefix_op {
-            ($prec:expr, $func:ident) => {{
+            ($prec:expr, $op:expr, $func:ident) => {{
                 let rhs = self.parse_expr(BindingPower::prefix($prec))?;
-                AST::function(BuiltinFunction::$func, vec![rhs])
+                AST::function(
+                    AST::from(BuiltinFunction::$func).at($op.span),
+                    vec![rhs],
+                    self.span_from(start),
+                )
             }};
         }
 
         macro_rules! postfix_op {
             ($prec:expr, $func:expr) => {{
-                if should_break(&BindingPower::postfix($prec), &min_bp)? {
+                let op_span = self.peek_span();
+                if should_break(&BindingPower::postfix($prec), &min_bp).map_err(|e| e.or_span(op_span))? {
                     break;
                 }
-                self.tokens.next();
+                self.next();
                 lhs = $func;
             }};
         }
 
-        lhs = match self.next() {
+        let token = self.next();
+        lhs = match token {
             // Integer Literals
-            token_type!(IntLit, text) => AST::Literal(Value::Integer(
-                text.parse()
-                    .map_err(|_| Error::parse_error("Invalid int"))?,
+            // The lexer only produces digits, so parsing can only fail if the literal is too large
+            token_type!(IntLit, text) => AST::from(self.parse_unit(Value::Integer(text.parse().map_err(|_| {
+                Error::parse_error(format!("Integer literal {} is too large, the maximum is {}", text, i64::MAX))
+                    .or_span(self.span_from(start))
+            })?))),
+            token_type!(DecimalLit, text) => AST::from(self.parse_unit(Value::Rational(Rational::parse_decimal(text).ok_or_else(|| {
+                Error::parse_error(format!("Number literal {} has too many digits", text)).or_span(self.span_from(start))
+            })?))),
+            token_type!(DiceLit, text) => AST::from(Value::Dice(
+                DiceGroup::parse(text)
+                    .map_err(|e| Error::parse_error(e).or_span(self.span_from(start)))?
+                    .into(),
             )),
 
             // String Literals
-            // Trim the quotes
-            token_type!(StringLit, text) => {
-                AST::Literal(Value::String(text[1..text.len() - 1].to_string()))
-            } //TODO escape chars
+            Some(token @ Token { token_type: TokenType::StringLit, .. }) => {
+                AST::from(Value::String(unescape(&token.text[1..token.text.len() - 1], token.span.start + 1)?))
+            }
+            Some(token @ Token { token_type: TokenType::InterpolatedStringLit, .. }) => {
+                parse_interpolated(&token.text[2..token.text.len() - 1], token.span.start + 2, token.span)?
+            }
 
-            // List Literals
+            // List Literals and comprehensions
             token_type!(LBrack) => {
-                let elements = separated_by!(Comma, self.parse_expr(BindingPower::zero())?, RBrack);
-                AST::Literal(Value::List(elements))
+                if self.next_if_eq(TokenType::RBrack).is_some() {
+                    AST::from(Value::List(vec![]))
+                } else {
+                    let first = self.parse_expr(BindingPower::zero())?;
+                    if self.peek().is_some_and(|t| t.token_type == TokenType::For) {
+                        self.parse_comprehension(first, start)?
+                    } else {
+                        let mut elements = vec![first];
+                        while self.next_if_eq(TokenType::Comma).is_some() {
+                            elements.push(self.parse_expr(BindingPower::zero())?);
+                        }
+                        self.expect_token(TokenType::RBrack)?;
+                        AST::from(Value::List(elements))
+                    }
+                }
             }
 
             // Record Literals
-            token_type!(LBrace) => {
-                let elements = separated_by!(
-                    Comma,
-                    {
-                        let name = self.expect_token(TokenType::Name)?.text.to_string();
-                        self.expect_token(TokenType::Colon)?;
-                        let value = self.parse_expr(BindingPower::zero())?;
-                        (name, value)
-                    },
-                    RBrace
-                );
-                AST::Literal(Value::Record(elements.into_iter().collect()))
-            }
+            token_type!(LBrace) => self.parse_record_literal(start)?,
 
             // Boolean literals
-            token_type!(True) => AST::Literal(Value::Boolean(true)),
-            token_type!(False) => AST::Literal(Value::Boolean(false)),
+            token_type!(True) => AST::from(Value::Boolean(true)),
+            token_type!(False) => AST::from(Value::Boolean(false)),
 
             // Names
-            token_type!(Name, text) => AST::Name(text.to_string()),
-            token_type!(CellName, text) => AST::Name(text.to_string()),
+            token_type!(Name, text) => AST::from(text),
+            token_type!(CellName, text) => AST::from(text),
+
+            // Tags, optionally carrying a value in brackets
+            token_type!(Tag, text) => {
+                let payload = match self.next_if_eq(TokenType::LParen) {
+                    Some(_) => {
+                        let payload = self.parse_expr(BindingPower::zero())?;
+                        self.expect_token(TokenType::RParen)?;
+                        Some(Box::new(payload))
+                    }
+                    None => None,
+                };
+                AST::from(Value::Tag(text[1..].to_string(), payload))
+            }
 
             token_type!(Fn) => {
                 self.expect_token(TokenType::LParen)?;
-                let params = separated_by!(
-                    Comma,
-                    self.expect_token(TokenType::Name)?.text.to_string(),
-                    RParen
-                );
+                let params = separated_by!(Comma, self.parse_param()?, RParen);
+                check_distinct_names(&params.iter().map(|p| p.pattern.clone()).collect::<Vec<_>>())?;
+                if let Some(param) = params.iter().skip_while(|p| p.default.is_none()).find(|p| p.default.is_none()) {
+                    return Err(Error::parse_error("Parameters without a default must come before those with one")
+                        .or_span(param.pattern.span));
+                }
                 self.expect_token(TokenType::Arrow)?;
                 let body = self.parse_expr(BindingPower::zero())?;
-                AST::Literal(Value::Function(Function::Lambda(params, Box::new(body))))
+                AST::from(Value::Function(Function::Lambda(params, Box::new(body))))
             }
 
             token_type!(Let) => {
                 let bindings = separated_by!(
                     SemiColon,
                     {
-                        let name = self.expect_token(TokenType::Name)?.text.to_string();
+                        let pattern = self.parse_pattern()?;
                         self.expect_token(TokenType::Eq)?;
                         let expr = self.parse_expr(BindingPower::zero())?;
-                        Binding(name, expr)
+                        Binding(pattern, expr)
                     },
                     In
                 );
                 let expr = self.parse_expr(BindingPower::zero())?;
-                AST::Let(bindings, Box::new(expr))
+                AST::from(Expr::Let(bindings, Box::new(expr)))
             }
 
             // Brackets
             token_type!(LParen) => {
-                let expr = self.parse_expr(BindingPower::zero())?;
-                self.expect_token(TokenType::RParen)?;
-                expr
+                if let Some(section) = self.parse_section()? {
+                    section
+                } else {
+                    let expr = self.parse_expr(BindingPower::zero())?;
+                    self.expect_token(TokenType::RParen)?;
+                    expr
+                }
+            }
+
+            // Match expressions, the bar before the first arm is optional
+            token_type!(Match) => {
+                let value = self.parse_expr(BindingPower::zero())?;
+                self.expect_token(TokenType::With)?;
+                self.next_if_eq(TokenType::Bar);
+                let mut arms = vec![self.parse_match_arm()?];
+                while self.next_if_eq(TokenType::Bar).is_some() {
+                    arms.push(self.parse_match_arm()?);
+                }
+                AST::from(Expr::Match(Box::new(value), arms))
             }
 
             // Prefix operators
-            token_type!(Minus) => prefix_op!(9, Negate),
-            token_type!(Not) => prefix_op!(9, Not),
+            Some(op @ Token { token_type: TokenType::Minus, .. }) => prefix_op!(10, op, Negate),
+            Some(op @ Token { token_type: TokenType::Not, .. }) => prefix_op!(10, op, Not),
 
             // If-Then-Else
-            token_type!(If) => {
+            Some(op @ Token { token_type: TokenType::If, .. }) => {
                 let cond = self.parse_expr(BindingPower::zero())?;
                 self.expect_token(TokenType::Then)?;
                 let then = self.parse_expr(BindingPower::zero())?;
                 self.expect_token(TokenType::Else)?;
-                let else_ = self.parse_expr(BindingPower::prefix(1))?;
-                AST::function(BuiltinFunction::If, vec![cond, then, else_])
+                let else_ = self.parse_expr(BindingPower::zero())?;
+                AST::function(
+                    AST::from(BuiltinFunction::If).at(op.span),
+                    vec![cond, then, else_],
+                    self.span_from(start),
+                )
             }
 
-            _ => return Err(Error::parse_error("Expected name or lit int")),
-        };
+            t => {
+                return Err(Error::parse_error("Expected an expression")
+                    .or_span(t.map_or(self.eof, |t| t.span)))
+            }
+        }
+        .at(self.span_from(start));
 
         loop {
+            let mut call = false;
             match self.peek() {
                 // Postfix operators
-                token_type!(Dot) => postfix_op!(10, {
-                    let field = self.expect_token(TokenType::Name)?.text.to_string();
-                    AST::FieldAccess(Box::new(lhs), field)
+                token_type!(Dot) => postfix_op!(11, {
+                    let field = self.expect_token(TokenType::Name)?;
+                    // `value.f(args)` is `f(value, args)` for any function `f` in scope, so a function
+                    // stored in a record field has to be called as `(value.f)(args)`
+                    if self.next_if_eq(TokenType::LParen).is_some() {
+                        let mut args = vec![lhs];
+                        args.extend(separated_by!(Comma, self.parse_expr(BindingPower::zero())?, RParen));
+                        call = true;
+                        AST::function(AST::from(field.text).at(field.span), args, self.span_from(start))
+                    } else {
+                        AST::new(Expr::FieldAccess(Box::new(lhs), field.text.to_string()), self.span_from(start))
+                    }
                 }),
-                token_type!(LParen) => postfix_op!(10, {
-                    let args = separated_by!(Comma, self.parse_expr(BindingPower::zero())?, RParen);
-                    AST::Function(Box::new(lhs), args)
+                Some(op @ Token { token_type: TokenType::QuestionDot, .. }) => {
+                    let op_span = op.span;
+                    postfix_op!(11, {
+                        let field = self.expect_token(TokenType::Name)?;
+                        AST::function(
+                            AST::from(BuiltinFunction::OptionalField).at(op_span),
+                            vec![lhs, AST::from(Value::String(field.text.to_string())).at(field.span)],
+                            self.span_from(start),
+                        )
+                    })
+                }
+                token_type!(LParen) => postfix_op!(11, {
+                    let (args, named) = self.parse_args()?;
+                    call = true;
+                    if named.is_empty() {
+                        AST::new(Expr::Function(Box::new(lhs), args), self.span_from(start))
+                    } else {
+                        AST::new(Expr::NamedCall(Box::new(lhs), args, named), self.span_from(start))
+                    }
                 }),
                 
                 // Infix operators
-                token_type!(Star) => infix_op!(7, Left, Mul),
-                token_type!(Plus) => infix_op!(6, Left, Add),
-                token_type!(Minus) => infix_op!(6, Left, Sub),
-
-                token_type!(SlashSlash) => infix_op!(6, Left, RecordUpdate),
+                token_type!(Star) => infix_op!(9, Left, Mul),
+                token_type!(Slash) => infix_op!(9, Left, Div),
+                token_type!(Percent) => infix_op!(9, Left, Mod),
+                token_type!(Plus) => infix_op!(8, Left, Add),
+                token_type!(Minus) => infix_op!(8, Left, Sub),
+
+                token_type!(SlashSlash) => infix_op!(8, Left, RecordUpdate),
+
+                token_type!(QuestionQuestion) => infix_op!(7, Right, Coalesce),
+
+                // Ranges are calls to `range`, which excludes the end. They bind looser than `??`
+                // so that `r?.lo ?? 1..10` has a default start
+                token_type!(DotDot) => infix_op!(6, None, Range),
+                token_type!(DotDotEq) => infix_op!(6, None, |op_span: Span, rhs: AST| {
+                    let end = AST::function(
+                        AST::from(BuiltinFunction::Add).at(op_span),
+                        vec![rhs, AST::from(Value::Integer(1)).at(op_span)],
+                        op_span,
+                    );
+                    AST::function(AST::from(BuiltinFunction::Range).at(op_span), vec![lhs, end], self.span_from(start))
+                }),
 
                 token_type!(Lt) => infix_op!(5, Left, LessThan),
                 token_type!(LtEq) => infix_op!(5, Left, LessThanEqual),
@@ -457,22 +887,366 @@ impl<'a> Parser<'a> {
                 token_type!(GtEq) => infix_op!(5, Left, GreaterThanEqual),
                 
                 token_type!(EqEq) => infix_op!(4, Left, Equals),
-                token_type!(NotEq) => infix_op!(4, Left, |rhs| {
-                    AST::function(BuiltinFunction::Not, vec![AST::function(BuiltinFunction::Equals, vec![lhs, rhs])])
+                token_type!(NotEq) => infix_op!(4, Left, |op_span: Span, rhs| {
+                    let span = self.span_from(start);
+                    let equals = AST::function(AST::from(BuiltinFunction::Equals).at(op_span), vec![lhs, rhs], span);
+                    AST::function(AST::from(BuiltinFunction::Not).at(op_span), vec![equals], span)
                 }),
 
                 token_type!(And) => infix_op!(3, Left, And),
                 token_type!(Or) => infix_op!(2, Left, Or),
 
+                // `value |> f(args)` calls f with the value as its last argument, `value |> f` is just `f(value)`
+                token_type!(Pipe) => {
+                    let prec = BindingPower::infix(1, Assoc::Left);
+                    let op_span = self.peek_span();
+                    if should_break(&prec, &min_bp).map_err(|e| e.or_span(op_span))? {
+                        break;
+                    }
+                    self.next();
+                    // Only a call written as `f(args)` takes the value as an extra argument, calls
+                    // in brackets or made by desugaring operators are called with it instead
+                    let (rhs, written_call) = self.parse_expr_call(BindingPower::prefix(10))?;
+                    lhs = match rhs.expr {
+                        Expr::Function(func, mut args) if written_call => {
+                            args.push(lhs);
+                            AST::new(Expr::Function(func, args), self.span_from(start))
+                        }
+                        _ => AST::function(rhs, vec![lhs], self.span_from(start)),
+                    };
+                }
+
+                // `expr catch e -> handler` calls the handler with the error message if expr fails
+                token_type!(Catch) => {
+                    let prec = BindingPower::infix(2, Assoc::Left);
+                    let op_span = self.peek_span();
+                    if should_break(&prec, &min_bp).map_err(|e| e.or_span(op_span))? {
+                        break;
+                    }
+                    self.next();
+                    let handler_start = self.peek_span().start;
+                    let param = self.parse_pattern()?;
+                    self.expect_token(TokenType::Arrow)?;
+                    let body = self.parse_expr(BindingPower::zero())?;
+                    let handler = AST::from(Value::Function(Function::Lambda(vec![Param::new(param, None)], Box::new(body))))
+                        .at(self.span_from(handler_start));
+                    lhs = AST::function(
+                        AST::from(BuiltinFunction::Catch).at(op_span),
+                        vec![lhs, handler],
+                        self.span_from(start),
+                    );
+                }
+
                 _ => break,
             };
+            written_call = call;
         }
 
-        Ok(lhs)
+        Ok((lhs, written_call))
     }
 
-}
+    /// Parses the unit after a number literal, if there is one, so that `30 ft` is a quantity.
+    ///
+    /// A name can not otherwise follow a number, so unit names are still free to be used as names.
+    fn parse_unit(&mut self, number: Value<AST>) -> Value<AST> {
+        let unit = match self.peek() {
+            Some(Token { token_type: TokenType::Name, text, .. }) => Unit::lookup(text),
+            _ => None,
+        };
+        let amount = match &number {
+            Value::Integer(n) => Rational::from(*n),
+            Value::Rational(r) => *r,
+            _ => return number,
+        };
+        match unit {
+            Some(unit) => {
+                self.next();
+                Value::Quantity(Quantity::new(amount, unit))
+            }
+            None => number,
+        }
+    }
 
+    /// Parses the fields of a record literal after its opening brace.
+    ///
+    /// Fields are `name: expr`, `"quoted name": expr` or just `name`, which is
+    /// short for `name: name`. Spreads (`...expr`) and computed keys (`[expr]: expr`)
+    /// are desugared into record updates, so `{a: 1, ...base, [k]: v}` becomes
+    /// `{a: 1} // base // {[k]: v}`, where the last part is a call to the `[]:` builtin.
+    fn parse_record_literal(&mut self, start: usize) -> Result<AST, Error> {
+        // The record is built from parts joined with `//`, the first of which is always a literal
+        let mut parts: Vec<AST> = vec![];
+        let mut fields = BTreeMap::new();
+        let mut keys = vec![];
+
+        fn flush(parts: &mut Vec<AST>, fields: &mut BTreeMap<String, AST>) {
+            if !fields.is_empty() || parts.is_empty() {
+                parts.push(AST::from(Value::Record(std::mem::take(fields))));
+            }
+        }
+
+        if self.next_if_eq(TokenType::RBrace).is_none() {
+            loop {
+                let field_start = self.peek_span().start;
+                let (key, value) = match self.next() {
+                    token_type!(Ellipsis) => {
+                        flush(&mut parts, &mut fields);
+                        parts.push(self.parse_expr(BindingPower::zero())?);
+                        (None, None)
+                    }
+                    token_type!(LBrack) => {
+                        let key = self.parse_expr(BindingPower::zero())?;
+                        self.expect_token(TokenType::RBrack)?;
+                        self.expect_token(TokenType::Colon)?;
+                        let value = self.parse_expr(BindingPower::zero())?;
+                        let span = self.span_from(field_start);
+                        flush(&mut parts, &mut fields);
+                        parts.push(AST::function(AST::from(BuiltinFunction::ComputedField).at(span), vec![key, value], span));
+                        (None, None)
+                    }
+                    Some(token @ Token { token_type: TokenType::Name, .. }) => {
+                        let value = match self.next_if_eq(TokenType::Colon) {
+                            Some(_) => self.parse_expr(BindingPower::zero())?,
+                            None => AST::from(token.text).at(token.span),
+                        };
+                        (Some((token.text.to_string(), token.span)), Some(value))
+                    }
+                    Some(token @ Token { token_type: TokenType::StringLit, .. }) => {
+                        let key = unescape(&token.text[1..token.text.len() - 1], token.span.start + 1)?;
+                        self.expect_token(TokenType::Colon)?;
+                        (Some((key, token.span)), Some(self.parse_expr(BindingPower::zero())?))
+                    }
+                    t => return Err(Error::unexpected_token(t, self.eof)),
+                };
+
+                if let (Some((key, key_span)), Some(value)) = (key, value) {
+                    if keys.contains(&key) {
+                        return Err(Error::parse_error(format!("Duplicate key \"{}\" in record", key)).or_span(key_span));
+                    }
+                    keys.push(key.clone());
+                    fields.insert(key, value);
+                }
+
+                if self.next_if_eq(TokenType::Comma).is_none() {
+                    self.expect_token(TokenType::RBrace)?;
+                    break;
+                }
+            }
+        }
+        flush(&mut parts, &mut fields);
+
+        let span = self.span_from(start);
+        Ok(parts
+            .into_iter()
+            .reduce(|lhs, rhs| AST::function(AST::from(BuiltinFunction::RecordUpdate).at(span), vec![lhs, rhs], span))
+            .unwrap())
+    }
+
+    /// Parses the clauses of a list comprehension after its element, up to the closing bracket.
+    ///
+    /// `[e for x in xs if c]` is desugared to `map(fn (x) -> e, filter(fn (x) -> c, xs))`, and each
+    /// further `for` clause maps over the inner list and flattens the result.
+    fn parse_comprehension(&mut self, element: AST, start: usize) -> Result<AST, Error> {
+        let mut clauses = vec![];
+        while let Some(token) = self.next_if_eq(TokenType::For) {
+            let pattern = self.parse_pattern()?;
+            self.expect_token(TokenType::In)?;
+            let list = self.parse_expr(BindingPower::zero())?;
+            let mut conditions = vec![];
+            while self.next_if_eq(TokenType::If).is_some() {
+                conditions.push(self.parse_expr(BindingPower::zero())?);
+            }
+            clauses.push((token.span, pattern, list, conditions));
+        }
+        self.expect_token(TokenType::RBrack)?;
+
+        let span = self.span_from(start);
+        let lambda = |pattern: &Pattern, body: AST| {
+            let span = body.span;
+            AST::from(Value::Function(Function::Lambda(vec![Param::new(pattern.clone(), None)], Box::new(body)))).at(span)
+        };
+        let map = |(for_span, pattern, list, conditions): (Span, Pattern, AST, Vec<AST>), body: AST| {
+            let source = conditions.into_iter().fold(list, |source, condition| {
+                AST::function(AST::from(BuiltinFunction::Filter).at(for_span), vec![lambda(&pattern, condition), source], span)
+            });
+            AST::function(AST::from(BuiltinFunction::Map).at(for_span), vec![lambda(&pattern, body), source], span)
+        };
+
+        // There is always at least one clause since the caller saw the first `for`
+        let innermost = clauses.pop().unwrap();
+        let mut result = map(innermost, element);
+        while let Some(clause) = clauses.pop() {
+            let for_span = clause.0;
+            result = AST::function(AST::from(BuiltinFunction::Flatten).at(for_span), vec![map(clause, result)], span);
+        }
+        Ok(result)
+    }
+
+    /// Parses the arguments of a call after the opening bracket, positional arguments followed by
+    /// named ones, `name: value`.
+    fn parse_args(&mut self) -> Result<Arguments, Error> {
+        let mut args = vec![];
+        let mut named: Vec<(String, AST)> = vec![];
+        if self.next_if_eq(TokenType::RParen).is_some() {
+            return Ok((args, named));
+        }
+        loop {
+            let mut lookahead = self.tokens.clone();
+            let is_named = lookahead.next().is_some_and(|t| t.token_type == TokenType::Name)
+                && lookahead.next().is_some_and(|t| t.token_type == TokenType::Colon);
+            if is_named {
+                let name = self.next().unwrap();
+                self.next();
+                if named.iter().any(|(n, _)| n == name.text) {
+                    return Err(Error::parse_error(format!("Argument \"{}\" is given more than once", name.text))
+                        .or_span(name.span));
+                }
+                named.push((name.text.to_string(), self.parse_expr(BindingPower::zero())?));
+            } else {
+                let arg = self.parse_expr(BindingPower::zero())?;
+                if !named.is_empty() {
+                    return Err(Error::parse_error("Positional arguments must come before named ones").or_span(arg.span));
+                }
+                args.push(arg);
+            }
+
+            if self.next_if_eq(TokenType::Comma).is_none() {
+                self.expect_token(TokenType::RParen)?;
+                return Ok((args, named));
+            }
+        }
+    }
+
+    /// Parses a lambda parameter, a pattern with an optional default value `= value`.
+    fn parse_param(&mut self) -> Result<Param, Error> {
+        let pattern = self.parse_sub_pattern()?;
+        let default = match self.next_if_eq(TokenType::Eq) {
+            Some(_) => Some(self.parse_expr(BindingPower::zero())?),
+            None => None,
+        };
+        Ok(Param::new(pattern, default))
+    }
+
+    /// Parses a match arm, `pattern if guard -> body`, where the guard is optional.
+    fn parse_match_arm(&mut self) -> Result<MatchArm, Error> {
+        let pattern = self.parse_pattern()?;
+        let guard = match self.next_if_eq(TokenType::If) {
+            Some(_) => Some(self.parse_expr(BindingPower::zero())?),
+            None => None,
+        };
+        self.expect_token(TokenType::Arrow)?;
+        let body = self.parse_expr(BindingPower::zero())?;
+        Ok(MatchArm { pattern, guard, body })
+    }
+
+    /// Parses a pattern, checking that it does not bind the same name twice.
+    fn parse_pattern(&mut self) -> Result<Pattern, Error> {
+        let pattern = self.parse_sub_pattern()?;
+        check_distinct_names(std::slice::from_ref(&pattern))?;
+        Ok(pattern)
+    }
+
+    fn parse_sub_pattern(&mut self) -> Result<Pattern, Error> {
+        let start = self.peek_span().start;
+        let int_literal = |text: &str, span: Span| {
+            text.parse()
+                .map(|i| PatternKind::Literal(Value::Integer(i).into()))
+                .map_err(|_| Error::parse_error(format!("Integer literal {} is too large", text)).or_span(span))
+        };
+        let decimal_literal = |text: &str, span: Span| {
+            Rational::parse_decimal(text)
+                .map(|r| PatternKind::Literal(Value::Rational(r).into()))
+                .ok_or_else(|| Error::parse_error(format!("Number literal {} has too many digits", text)).or_span(span))
+        };
+
+        let kind = match self.next() {
+            token_type!(Name, "_") => PatternKind::Wildcard,
+            token_type!(Name, text) => PatternKind::Name(text.to_string()),
+
+            Some(token @ Token { token_type: TokenType::IntLit, .. }) => int_literal(token.text, token.span)?,
+            Some(token @ Token { token_type: TokenType::DecimalLit, .. }) => decimal_literal(token.text, token.span)?,
+            token_type!(Minus) => {
+                if let Some(token) = self.next_if_eq(TokenType::DecimalLit) {
+                    decimal_literal(&format!("-{}", token.text), self.span_from(start))?
+                } else {
+                    let token = self.expect_token(TokenType::IntLit)?;
+                    int_literal(&format!("-{}", token.text), self.span_from(start))?
+                }
+            }
+            Some(token @ Token { token_type: TokenType::StringLit, .. }) => PatternKind::Literal(
+                Value::String(unescape(&token.text[1..token.text.len() - 1], token.span.start + 1)?).into(),
+            ),
+            token_type!(True) => PatternKind::Literal(Value::Boolean(true).into()),
+            token_type!(False) => PatternKind::Literal(Value::Boolean(false).into()),
+
+            token_type!(Tag, text) => {
+                let payload = match self.next_if_eq(TokenType::LParen) {
+                    Some(_) => {
+                        let payload = self.parse_sub_pattern()?;
+                        self.expect_token(TokenType::RParen)?;
+                        Some(Box::new(payload))
+                    }
+                    None => None,
+                };
+                PatternKind::Tag(text[1..].to_string(), payload)
+            }
+
+            token_type!(LBrack) => {
+                let mut elements = vec![];
+                let mut rest = None;
+                if self.next_if_eq(TokenType::RBrack).is_none() {
+                    loop {
+                        // A rest pattern must come last, `..` on its own ignores the rest of the list
+                        if let Some(dots) = self.next_if_eq(TokenType::DotDot) {
+                            rest = Some(Box::new(match self.peek() {
+                                token_type!(RBrack) => Pattern::new(PatternKind::Wildcard, dots.span),
+                                _ => self.parse_sub_pattern()?,
+                            }));
+                            self.expect_token(TokenType::RBrack)?;
+                            break;
+                        }
+                        elements.push(self.parse_sub_pattern()?);
+                        if self.next_if_eq(TokenType::Comma).is_none() {
+                            self.expect_token(TokenType::RBrack)?;
+                            break;
+                        }
+                    }
+                }
+                PatternKind::List(elements, rest)
+            }
+
+            // Record patterns, where `{field}` is short for `{field: field}`
+            token_type!(LBrace) => {
+                let mut fields: Vec<(String, Pattern)> = vec![];
+                if self.next_if_eq(TokenType::RBrace).is_none() {
+                    loop {
+                        let name = self.expect_token(TokenType::Name)?;
+                        if fields.iter().any(|(field, _)| field == name.text) {
+                            return Err(Error::parse_error(format!("Duplicate field \"{}\" in pattern", name.text))
+                                .or_span(name.span));
+                        }
+                        let pattern = match self.next_if_eq(TokenType::Colon) {
+                            Some(_) => self.parse_sub_pattern()?,
+                            None => Pattern::new(PatternKind::Name(name.text.to_string()), name.span),
+                        };
+                        fields.push((name.text.to_string(), pattern));
+                        if self.next_if_eq(TokenType::Comma).is_none() {
+                            self.expect_token(TokenType::RBrace)?;
+                            break;
+                        }
+                    }
+                }
+                PatternKind::Record(fields)
+            }
+
+            t => {
+                return Err(Error::parse_error("Expected a pattern").or_span(t.map_or(self.eof, |t| t.span)));
+            }
+        };
+
+        Ok(Pattern::new(kind, self.span_from(start)))
+    }
+}
 pub mod precedence {
     use std::cmp::Ordering;
 
@@ -535,14 +1309,114 @@ mod tests {
 
     test_parse_success!(test_int, "5", "5");
     test_parse_success!(test_int2, "0", "0");
+    test_parse_success!(test_int_max, "9223372036854775807", "9223372036854775807");
     test_parse_success!(test_string, "\"string\"", "\"string\"");
+    test_parse_success!(test_string_escapes, r#""a\"b\\c\nd\te""#, r#""a\"b\\c\nd\te""#);
+    test_parse_success!(test_string_unicode, r#""\u{1F409} \u{41}""#, "\"\u{1F409} A\"");
+    test_parse_success!(test_string_multiline, "\"a\nb\"", r#""a\nb""#);
+    test_parse_success!(test_fstring_plain, r#"f"abc""#, r#""abc""#);
+    test_parse_success!(test_fstring_empty, r#"f"""#, r#""""#);
+    test_parse_success!(
+        test_fstring,
+        r#"f"AC {ac} with {armor.name}""#,
+        r#"((builtin +) ((builtin +) ((builtin +) "AC " ((builtin to_string) ac)) " with ") ((builtin to_string) (.name armor)))"#
+    );
+    test_parse_success!(test_fstring_only_expr, r#"f"{a}""#, "((builtin to_string) a)");
+    test_parse_success!(test_fstring_braces, r#"f"{{{a}}}\n""#, r#"((builtin +) ((builtin +) "{" ((builtin to_string) a)) "}\n")"#);
+    test_parse_success!(test_fstring_record, r#"f"{ {a: 1}.a }""#, "((builtin to_string) (.a {a: 1}))");
+    test_parse_success!(test_fstring_name, r#"f("a")"#, r#"(f "a")"#);
+    test_parse_success!(test_line_comment, "(10 + 5) * 2  -- Result: 30", "((builtin *) ((builtin +) 10 5) 2)");
+    test_parse_success!(test_line_comment2, "-- leading\n1 -- trailing\n+ 2", "((builtin +) 1 2)");
+    test_parse_success!(test_block_comment, "1 {- a - b -} + {--} 2", "((builtin +) 1 2)");
+    test_parse_success!(test_block_comment_multiline, "{- multi\nline -- comment -}\n5", "5");
     test_parse_success!(test_list_lit, "[1, 2, 3]", "[1, 2, 3]");
     test_parse_success!(test_record_lit, "{b: 2, a: 1}", "{a: 1, b: 2}");
+    test_parse_success!(test_tag, "#fire", "#fire");
+    test_parse_success!(test_tag_payload, "#Some(1 + 2)", "#Some(((builtin +) 1 2))");
+    test_parse_success!(test_tag_pattern, "match x with | #Some([a, _]) -> a | #none -> 0", "(match x (#Some([a, _]) a) (#none 0))");
+    test_parse_success!(test_range, "1..n + 1", "((builtin range) 1 ((builtin +) n 1))");
+    test_parse_success!(test_range_inclusive, "a..=b", "((builtin range) a ((builtin +) b 1))");
+    test_parse_success!(test_range_in_list, "[0..3]", "[((builtin range) 0 3)]");
+    test_parse_success!(
+        test_range_coalesce,
+        "[r?.lo ?? 1..10, 1..=r?.hi ?? 5, 1..2 < 3]",
+        "[((builtin range) ((builtin ??) ((builtin ?.) r \"lo\") 1) 10), ((builtin range) 1 ((builtin +) ((builtin ??) ((builtin ?.) r \"hi\") 5) 1)), ((builtin <) ((builtin range) 1 2) 3)]"
+    );
+    test_parse_success!(test_dice, "2d6 + 3", "((builtin +) 2d6 3)");
+    test_parse_success!(test_dice_modifiers, "[4d6kh3, 2d20kl, 3d6!]", "[4d6kh3, 2d20kl1, 3d6!]");
+    test_parse_success!(test_dice_name, "d6", "d6");
+    test_parse_success!(test_dice_not_equal, "[3d6!=4, 3d6! != 4]", "[((builtin not) ((builtin ==) 3d6 4)), ((builtin not) ((builtin ==) 3d6! 4))]");
+    test_parse_success!(test_decimal, "[2.5, 0.50, -1.25]", "[2.5, 0.5, ((builtin negate) 1.25)]");
+    test_parse_success!(test_quantity, "[30 ft, 2.5 lb, 1 round, 10 rounds]", "[30 ft, 2.5 lb, 1 round, 10 rounds]");
+    test_parse_success!(test_quantity_not_unit, "f(1, ft)", "(f 1 ft)");
+    test_parse_success!(test_quantity_method, "30 ft.to(\"m\")", "(to 30 ft \"m\")");
+    test_parse_success!(test_decimal_pattern, "match x with | 0.5 -> 1 | -1.5 -> 2", "(match x (0.5 1) (-1.5 2))");
+    test_parse_success!(test_comprehension, "[x * 2 for x in xs]", "((builtin map) (lambda (x) ((builtin *) x 2)) xs)");
+    test_parse_success!(
+        test_comprehension_filter,
+        "[x for x in xs if x > 3 if x < 9]",
+        "((builtin map) (lambda (x) x) ((builtin filter) (lambda (x) ((builtin <) x 9)) ((builtin filter) (lambda (x) ((builtin >) x 3)) xs)))"
+    );
+    test_parse_success!(
+        test_comprehension_nested,
+        "[[a, b] for a in xs for [b, _] in a]",
+        "((builtin flatten) ((builtin map) (lambda (a) ((builtin map) (lambda ([b, _]) [a, b]) a)) xs))"
+    );
+    test_parse_success!(test_default_param, "fn (a, b = 1) -> a", "(lambda (a, b = 1) a)");
+    test_parse_success!(test_named_args, "f(1, b: 2, c: 3)", "(f 1 b: 2 c: 3)");
+    test_parse_success!(test_named_args_only, "f(b: x + 1)", "(f b: ((builtin +) x 1))");
+    test_parse_success!(test_named_arg_record_value, "f({a: 1})", "(f {a: 1})");
+    test_parse_success!(test_section, "(+)", "(builtin +)");
+    test_parse_success!(test_section_minus, "(-)", "(builtin -)");
+    test_parse_success!(test_section_right, "(* 2)", "(lambda (%0) ((builtin *) %0 2))");
+    test_parse_success!(test_section_expr, "(< a + 1)", "(lambda (%0) ((builtin <) %0 ((builtin +) a 1)))");
+    test_parse_success!(test_section_not_eq, "(!=)", "(lambda (%0, %1) ((builtin not) ((builtin ==) %0 %1)))");
+    test_parse_success!(test_section_negate, "(- 2)", "((builtin negate) 2)");
+    test_parse_success!(test_section_negate_expr, "(-a * 2)", "((builtin *) ((builtin negate) a) 2)");
+    test_parse_success!(test_pipe, "xs |> sum", "(sum xs)");
+    test_parse_success!(test_pipe_call, "xs |> filter(f) |> map(g) |> sum", "(sum (map g (filter f xs)))");
+    test_parse_success!(test_pipe_prec, "a + b |> f(c) |> g", "(g (f c ((builtin +) a b)))");
+    test_parse_success!(test_pipe_lambda, "a |> fn (x) -> x", "((lambda (x) x) a)");
+    test_parse_success!(test_pipe_field, "a |> b.c", "((.c b) a)");
+    test_parse_success!(test_pipe_in_else, "if a then b else c |> f", "((builtin if) a b (f c))");
+    test_parse_success!(test_pipe_if, "a |> (if b then f else g)", "(((builtin if) b f g) a)");
+    test_parse_success!(test_pipe_negate, "a |> -f", "(((builtin negate) f) a)");
+    test_parse_success!(test_pipe_bracketed_call, "a |> (f(b))", "((f b) a)");
+    test_parse_success!(test_pipe_call_result, "a |> f(b)(c)", "((f b) c a)");
+    test_parse_success!(test_method, "name.upper()", "(upper name)");
+    test_parse_success!(test_method_args, "a.b.take(2).sum()", "(sum (take (.b a) 2))");
+    test_parse_success!(test_method_any_name, "a.b(c)", "(b a c)");
+    test_parse_success!(test_method_field, "(a.b)(c)", "((.b a) c)");
+    test_parse_success!(test_pipe_method, "a |> b.take(2)", "(take b 2 a)");
+    test_parse_success!(test_pipe_method_nested, "a |> f(b).take(g(2))", "(take (f b) (g 2) a)");
+    test_parse_success!(test_pipe_field_of_call, "a |> f(b).g", "((.g (f b)) a)");
+    test_parse_success!(test_method_name_only, "a.sum", "(.sum a)");
+    test_parse_success!(test_catch, "a / b catch e -> 0", "((builtin catch) ((builtin /) a b) (lambda (e) 0))");
+    test_parse_success!(test_catch_prec, "a or b catch e -> c or d", "((builtin catch) ((builtin or) a b) (lambda (e) ((builtin or) c d)))");
+    test_parse_success!(test_catch_in_else, "if a then b else c catch _ -> d", "((builtin if) a b ((builtin catch) c (lambda (_) d)))");
+    test_parse_success!(test_optional_field, "a?.b?.c", "((builtin ?.) ((builtin ?.) a \"b\") \"c\")");
+    test_parse_success!(test_coalesce, "a?.b ?? 0", "((builtin ??) ((builtin ?.) a \"b\") 0)");
+    test_parse_success!(test_coalesce_right_assoc, "a ?? b ?? c", "((builtin ??) a ((builtin ??) b c))");
+    test_parse_success!(test_coalesce_prec, "a ?? 1 + 2 > 3", "((builtin >) ((builtin ??) a ((builtin +) 1 2)) 3)");
+    test_parse_success!(test_record_empty, "{}", "{}");
+    test_parse_success!(test_record_pun, "{strength, dexterity: 3}", "{dexterity: 3, strength: strength}");
+    test_parse_success!(test_record_quoted_key, r#"{"two words": 1, "a\"b": 2}"#, r#"{a"b: 2, two words: 1}"#);
+    test_parse_success!(test_record_spread, "{...base, strength: 12}", "((builtin //) ((builtin //) {} base) {strength: 12})");
+    test_parse_success!(test_record_spread_middle, "{a: 1, ...b, c: 2, d: 3}", "((builtin //) ((builtin //) {a: 1} b) {c: 2, d: 3})");
+    test_parse_success!(
+        test_record_computed_key,
+        r#"{a: 1, ["skill_" + name]: 2}"#,
+        r#"((builtin //) {a: 1} ((builtin []:) ((builtin +) "skill_" name) 2))"#
+    );
     test_parse_success!(test_plus, "1 + 2", "((builtin +) 1 2)");
     test_parse_success!(test_minus, "1 - 2", "((builtin -) 1 2)");
     test_parse_success!(test_multiply, "1 * 2", "((builtin *) 1 2)");
+    test_parse_success!(test_divide, "1 / 2", "((builtin /) 1 2)");
+    test_parse_success!(test_modulo, "1 % 2", "((builtin %) 1 2)");
+    test_parse_success!(test_divide_record_update, "a / b // c", "((builtin //) ((builtin /) a b) c)");
+    test_parse_success!(test_divide_prec, "(score - 10) / 2 + 1", "((builtin +) ((builtin /) ((builtin -) score 10) 2) 1)");
     test_parse_success!(test_negate, "-1", "((builtin negate) 1)");
-    test_parse_success!(test_negate2, "--1", "((builtin negate) ((builtin negate) 1))");
+    test_parse_success!(test_negate2, "- -1", "((builtin negate) ((builtin negate) 1))");
     test_parse_success!(test_prec_left, "1 * 2 + 3", "((builtin +) ((builtin *) 1 2) 3)");
     test_parse_success!(test_prec_right, "1 + 2 * 3", "((builtin +) 1 ((builtin *) 2 3))");
     test_parse_success!(test_dot, "a.b", "(.b a)");
@@ -565,4 +1439,92 @@ mod tests {
         "let f = fn (x) -> x in f(5)",
         "(let ((f (lambda (x) x))) (f 5))"
     );
+
+    test_parse_success!(
+        test_let_destructure,
+        "let {strength, dexterity: dex} = $abilities in strength + dex",
+        "(let (({strength: strength, dexterity: dex} $abilities)) ((builtin +) strength dex))"
+    );
+    test_parse_success!(test_lambda_destructure, "fn ([a, b], _) -> a", "(lambda ([a, b], _) a)");
+
+    test_parse_success!(
+        test_match,
+        "match x with | 1 -> \"one\" | _ -> \"other\"",
+        "(match x (1 \"one\") (_ \"other\"))"
+    );
+    test_parse_success!(test_match_no_leading_bar, "match x with y -> y", "(match x (y y))");
+    test_parse_success!(
+        test_match_guard,
+        "match x with | n if n > 3 -> n | _ -> 0",
+        "(match x (n if ((builtin >) n 3) n) (_ 0))"
+    );
+    test_parse_success!(
+        test_match_patterns,
+        "match x with | {type: \"heavy\", ac} -> ac | [first, ..rest] -> first | [-1, ..] -> 0 | [] -> 1",
+        "(match x ({type: \"heavy\", ac: ac} ac) ([first, ..rest] first) ([-1, .._] 0) ([] 1))"
+    );
+
+    macro_rules! test_parse_error_span {
+        ($test_name:ident, $input:expr, $start:expr, $end:expr) => {
+            #[test]
+            fn $test_name() {
+                assert_eq!(parse($input).unwrap_err().span, Some(Span::new($start, $end)));
+            }
+        };
+    }
+
+    test_parse_error_span!(test_error_unexpected, "(1 + 2]", 6, 7);
+    test_parse_error_span!(test_error_eof, "let x = 1 in", 12, 12);
+    test_parse_error_span!(test_error_pipe_missing_rhs, "a |>", 4, 4);
+    test_parse_error_span!(test_error_comprehension_in, "[x for x xs]", 9, 11);
+    test_parse_error_span!(test_error_comprehension_duplicate, "[x for [x, x] in xs]", 7, 13);
+    test_parse_error_span!(test_error_range_chain, "1..2..3", 4, 6);
+    test_parse_error_span!(test_error_default_order, "fn (a = 1, b) -> a", 11, 12);
+    test_parse_error_span!(test_error_positional_after_named, "f(a: 1, 2)", 8, 9);
+    test_parse_error_span!(test_error_duplicate_named, "f(a: 1, a: 2)", 8, 9);
+    test_parse_error_span!(test_error_no_expr, "1 + )", 4, 5);
+    test_parse_error_span!(test_error_trailing, "1 2", 2, 3);
+    test_parse_error_span!(test_error_int_too_large, "1 + 9223372036854775808", 4, 23);
+    test_parse_error_span!(test_error_keep_too_many, "1 + 2d6kh3", 4, 10);
+    test_parse_error_span!(test_error_zero_dice, "0d6", 0, 3);
+    test_parse_error_span!(test_error_bad_escape, r#""ab\q""#, 3, 5);
+    test_parse_error_span!(test_error_fstring_bad_escape, r#"f"{a}\q""#, 5, 7);
+    test_parse_error_span!(test_error_fstring_unclosed, r#"f"a {b""#, 4, 5);
+    test_parse_error_span!(test_error_fstring_unmatched, r#"f"a } b""#, 4, 5);
+    test_parse_error_span!(test_error_fstring_expr, r#"f"x {1 +} y""#, 8, 8);
+    test_parse_error_span!(test_error_fstring_empty_expr, r#"f"x {}""#, 5, 5);
+    test_parse_error_span!(test_error_bad_unicode, r#""\u{110000}""#, 1, 11);
+    test_parse_error_span!(test_error_record_duplicate_key, "{a: 1, b: 2, a: 3}", 13, 14);
+    test_parse_error_span!(test_error_record_duplicate_pun, "{a, ...b, a: 3}", 10, 11);
+    test_parse_error_span!(test_error_record_duplicate_quoted, r#"{a: 1, "a": 2}"#, 7, 10);
+    test_parse_error_span!(test_error_record_bad_field, "{1: 2}", 1, 2);
+    test_parse_error_span!(test_error_lambda_duplicate_param, "fn (a, [b, a]) -> a", 7, 13);
+    test_parse_error_span!(test_error_let_bad_pattern, "let a.b = 1 in a", 5, 6);
+    test_parse_error_span!(test_error_match_no_arms, "match x with", 12, 12);
+    test_parse_error_span!(test_error_pattern, "match x with | 1 + 2 -> 3", 17, 18);
+    test_parse_error_span!(test_error_pattern_duplicate_name, "match x with | [a, a] -> a", 15, 21);
+    test_parse_error_span!(test_error_pattern_duplicate_field, "match x with | {a, a: b} -> b", 19, 20);
+    test_parse_error_span!(test_error_pattern_rest_not_last, "match x with | [..a, b] -> b", 19, 20);
+
+    #[test]
+    fn test_node_spans() {
+        let ast = parse("foo + bar.baz").unwrap();
+        assert_eq!(ast.span, Span::new(0, 13));
+        match ast.expr {
+            Expr::Function(op, args) => {
+                assert_eq!(op.span, Span::new(4, 5));
+                assert_eq!(args[0].span, Span::new(0, 3));
+                assert_eq!(args[1].span, Span::new(6, 13));
+            }
+            _ => panic!("expected a function call"),
+        }
+    }
+
+    #[test]
+    fn test_error_snippet() {
+        let snippet = parse("let x = 1 in\nx + + 2").unwrap_err().snippet.unwrap();
+        assert_eq!((snippet.line, snippet.column), (2, 5));
+        assert_eq!(snippet.source_line, "x + + 2");
+        assert_eq!(snippet.underline, "    ^");
+    }
 }
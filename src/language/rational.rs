@@ -0,0 +1,276 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+
+use crate::language::errors::{Error, ErrorKind};
+
+/// An exact fraction, always stored in lowest terms with a positive denominator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    numerator: i128,
+    denominator: i128,
+}
+
+fn overflow() -> Error {
+    Error::new(ErrorKind::Overflow)
+}
+
+pub(crate) fn gcd(a: u128, b: u128) -> u128 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+impl Rational {
+    pub const ZERO: Rational = Rational {
+        numerator: 0,
+        denominator: 1,
+    };
+    pub const ONE: Rational = Rational {
+        numerator: 1,
+        denominator: 1,
+    };
+
+    pub fn new(numerator: i128, denominator: i128) -> Result<Rational, Error> {
+        if denominator == 0 {
+            return Err(Error::new(ErrorKind::DivisionByZero));
+        }
+        let divisor = gcd(numerator.unsigned_abs(), denominator.unsigned_abs()) as i128;
+        // The divisor only fails to fit when both are i128::MIN
+        let divisor = if divisor <= 0 { 1 } else { divisor };
+        let (mut numerator, mut denominator) = (numerator / divisor, denominator / divisor);
+        if denominator < 0 {
+            numerator = numerator.checked_neg().ok_or_else(overflow)?;
+            denominator = denominator.checked_neg().ok_or_else(overflow)?;
+        }
+        Ok(Rational {
+            numerator,
+            denominator,
+        })
+    }
+
+    pub fn numerator(&self) -> i128 {
+        self.numerator
+    }
+
+    pub fn denominator(&self) -> i128 {
+        self.denominator
+    }
+
+    pub fn add(&self, other: &Rational) -> Result<Rational, Error> {
+        let divisor = gcd(self.denominator as u128, other.denominator as u128) as i128;
+        let (left, right) = (other.denominator / divisor, self.denominator / divisor);
+        let numerator = self
+            .numerator
+            .checked_mul(left)
+            .zip(other.numerator.checked_mul(right))
+            .and_then(|(a, b)| a.checked_add(b))
+            .ok_or_else(overflow)?;
+        let denominator = self.denominator.checked_mul(left).ok_or_else(overflow)?;
+        Rational::new(numerator, denominator)
+    }
+
+    pub fn sub(&self, other: &Rational) -> Result<Rational, Error> {
+        self.add(&other.neg()?)
+    }
+
+    pub fn mul(&self, other: &Rational) -> Result<Rational, Error> {
+        // Cancel common factors first so the products are less likely to overflow
+        let a = gcd(self.numerator.unsigned_abs(), other.denominator as u128).max(1) as i128;
+        let b = gcd(other.numerator.unsigned_abs(), self.denominator as u128).max(1) as i128;
+        let numerator = (self.numerator / a).checked_mul(other.numerator / b);
+        let denominator = (self.denominator / b).checked_mul(other.denominator / a);
+        Rational::new(numerator.ok_or_else(overflow)?, denominator.ok_or_else(overflow)?)
+    }
+
+    pub fn div(&self, other: &Rational) -> Result<Rational, Error> {
+        if other.numerator == 0 {
+            return Err(Error::new(ErrorKind::DivisionByZero));
+        }
+        let inverse = Rational::new(other.denominator, other.numerator)?;
+        self.mul(&inverse)
+    }
+
+    /// The remainder of dividing by `other`, rounding the quotient down like `%` on integers
+    pub fn modulo(&self, other: &Rational) -> Result<Rational, Error> {
+        let quotient = Rational::new(self.div(other)?.floor(), 1)?;
+        self.sub(&other.mul(&quotient)?)
+    }
+
+    pub fn abs(&self) -> Result<Rational, Error> {
+        if self.numerator < 0 { self.neg() } else { Ok(*self) }
+    }
+
+    pub fn neg(&self) -> Result<Rational, Error> {
+        Ok(Rational {
+            numerator: self.numerator.checked_neg().ok_or_else(overflow)?,
+            denominator: self.denominator,
+        })
+    }
+
+    /// The largest integer no greater than this number
+    pub fn floor(&self) -> i128 {
+        self.numerator.div_euclid(self.denominator)
+    }
+
+    /// The smallest integer no less than this number
+    pub fn ceil(&self) -> i128 {
+        // Negating the numerator would overflow for i128::MIN, and the floor is below i128::MAX when there is a remainder
+        self.floor() + (self.numerator.rem_euclid(self.denominator) != 0) as i128
+    }
+
+    /// The nearest integer, rounding halves up like `round_half_up`
+    pub fn round(&self) -> i128 {
+        // Round up when the part after the decimal point is at least a half, without doubling it so it can not overflow
+        let floor = self.floor();
+        let remainder = self.numerator.rem_euclid(self.denominator);
+        if remainder >= self.denominator - remainder { floor + 1 } else { floor }
+    }
+
+    /// Reads a decimal literal such as `2.5`, or `None` if it has too many digits to store
+    pub fn parse_decimal(text: &str) -> Option<Rational> {
+        if let Some(positive) = text.strip_prefix('-') {
+            return Rational::parse_decimal(positive)?.neg().ok();
+        }
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+        let denominator = 10i128.checked_pow(fraction.len() as u32)?;
+        let numerator = whole
+            .parse::<i128>()
+            .ok()?
+            .checked_mul(denominator)?
+            .checked_add(if fraction.is_empty() { 0 } else { fraction.parse().ok()? })?;
+        Rational::new(numerator, denominator).ok()
+    }
+
+    /// The digits after the decimal point when written out exactly, or `None` if the decimal
+    /// never ends or is too long to work out
+    fn decimal_places(&self) -> Option<u32> {
+        let mut denominator = self.denominator;
+        let (mut twos, mut fives) = (0, 0);
+        while denominator % 2 == 0 {
+            denominator /= 2;
+            twos += 1;
+        }
+        while denominator % 5 == 0 {
+            denominator /= 5;
+            fives += 1;
+        }
+        let places = u32::max(twos, fives);
+        (denominator == 1 && 10i128.checked_pow(places)?.checked_mul(self.numerator).is_some()).then_some(places)
+    }
+}
+
+impl From<i64> for Rational {
+    fn from(n: i64) -> Self {
+        Rational {
+            numerator: n as i128,
+            denominator: 1,
+        }
+    }
+}
+
+impl Ord for Rational {
+    /// Compares by continued fractions, so that comparing never overflows
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (mut a, mut b) = ((self.numerator, self.denominator), (other.numerator, other.denominator));
+        let mut reversed = false;
+        loop {
+            let ordering = a.0.div_euclid(a.1).cmp(&b.0.div_euclid(b.1));
+            let (a_rest, b_rest) = (a.0.rem_euclid(a.1), b.0.rem_euclid(b.1));
+            let ordering = match (ordering, a_rest, b_rest) {
+                (Ordering::Equal, 0, 0) => Ordering::Equal,
+                (Ordering::Equal, 0, _) => Ordering::Less,
+                (Ordering::Equal, _, 0) => Ordering::Greater,
+                (Ordering::Equal, _, _) => {
+                    // a_rest / a.1 against b_rest / b.1 is the reverse of a.1 / a_rest against b.1 / b_rest
+                    (a, b) = ((a.1, a_rest), (b.1, b_rest));
+                    reversed = !reversed;
+                    continue;
+                }
+                (ordering, _, _) => ordering,
+            };
+            return if reversed { ordering.reverse() } else { ordering };
+        }
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Whole numbers are written as integers, numbers with a decimal that ends as decimals such
+/// as `2.5` and `-0.125`, and others as fractions such as `1/3`
+impl Display for Rational {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.decimal_places() {
+            Some(0) => write!(f, "{}", self.numerator),
+            Some(places) => {
+                let scaled = self.numerator.unsigned_abs() * 10u128.pow(places) / self.denominator as u128;
+                let scale = 10u128.pow(places);
+                let sign = if self.numerator < 0 { "-" } else { "" };
+                let (whole, fraction) = (scaled / scale, scaled % scale);
+                write!(f, "{}{}.{:0width$}", sign, whole, fraction, width = places as usize)
+            }
+            None => write!(f, "{}/{}", self.numerator, self.denominator),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r(n: i128, d: i128) -> Rational {
+        Rational::new(n, d).unwrap()
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(r(2, 4), r(1, 2));
+        assert_eq!(r(3, -9).to_string(), "-1/3");
+        assert_eq!(r(4, 2).to_string(), "2");
+        assert!(Rational::new(1, 0).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(r(1, 6).add(&r(1, 3)).unwrap(), r(1, 2));
+        assert_eq!(r(1, 2).sub(&r(3, 4)).unwrap(), r(-1, 4));
+        assert_eq!(r(2, 3).mul(&r(9, 4)).unwrap(), r(3, 2));
+        assert_eq!(r(-7, 2).floor(), -4);
+        assert_eq!(r(7, 3).modulo(&r(1, 2)).unwrap(), r(1, 3));
+        assert_eq!(r(1, 2).div(&r(-1, 4)).unwrap(), Rational::from(-2));
+        assert!(r(1, 2).div(&Rational::ZERO).is_err());
+        assert!(r(i128::MAX, 1).add(&Rational::ONE).is_err());
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!((r(5, 2).floor(), r(5, 2).ceil(), r(5, 2).round()), (2, 3, 3));
+        assert_eq!((r(-5, 2).floor(), r(-5, 2).ceil(), r(-5, 2).round()), (-3, -2, -2));
+        assert_eq!((r(4, 3).round(), r(5, 3).round(), r(-4, 3).round()), (1, 2, -1));
+        assert_eq!(Rational::from(3).ceil(), 3);
+        assert_eq!((r(i128::MIN, 1).ceil(), r(i128::MIN, 3).ceil(), r(i128::MAX, 2).ceil()), (i128::MIN, i128::MIN / 3, i128::MAX / 2 + 1));
+    }
+
+    #[test]
+    fn test_decimal() {
+        assert_eq!(Rational::parse_decimal("2.5"), Some(r(5, 2)));
+        assert_eq!(Rational::parse_decimal("0.125"), Some(r(1, 8)));
+        assert_eq!(Rational::parse_decimal("3.0"), Some(Rational::from(3)));
+        assert_eq!(Rational::parse_decimal("-0.5"), Some(r(-1, 2)));
+        assert_eq!(r(5, 2).to_string(), "2.5");
+        assert_eq!(r(-1, 8).to_string(), "-0.125");
+        assert_eq!(r(-1, 50).to_string(), "-0.02");
+        assert_eq!(r(1, 6).to_string(), "1/6");
+    }
+
+    #[test]
+    fn test_compare() {
+        assert!(r(1, 3) < r(1, 2));
+        assert!(r(-1, 2) < r(-1, 3));
+        assert!(r(5, 3) > r(3, 2));
+        assert!(r(7, 2) > Rational::from(3));
+        assert_eq!(r(i128::MAX, 3).cmp(&r(i128::MAX, 3)), Ordering::Equal);
+        assert!(r(i128::MAX - 1, i128::MAX) < r(i128::MAX, i128::MAX - 1));
+    }
+}
@@ -2,6 +2,23 @@ use crate::language::bultins::stringify_builtin;
 
 use super::ast::*;
 
+/// Escapes a string so that it can be read back as a string literal
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 pub trait ToSExpr {
     fn to_s_expr(&self) -> String;
 }
@@ -18,8 +35,14 @@ impl<T: ToSExpr> ToSExpr for Value<T> {
         match self {
             Value::Unit => "()".to_string(),
             Value::Integer(i) => i.to_string(),
-            Value::String(s) => format!("\"{s}\""),
+            Value::String(s) => format!("\"{}\"", escape(s)),
             Value::Boolean(b) => b.to_string(),
+            Value::Tag(tag, None) => format!("#{}", tag),
+            Value::Tag(tag, Some(payload)) => format!("#{}({})", tag, payload.to_s_expr()),
+            Value::Dice(dice) => dice.to_string(),
+            Value::Distribution(distribution) => format!("(distribution {})", distribution),
+            Value::Rational(r) => r.to_string(),
+            Value::Quantity(q) => q.to_string(),
             Value::Record(fields) => format!(
                 "{{{}}}",
                 fields
@@ -38,7 +61,11 @@ impl<T: ToSExpr> ToSExpr for Value<T> {
             ),
             Value::Function(Function::Builtin(function)) => format!("(builtin {})", stringify_builtin(*function)),
             Value::Function(Function::Lambda(params, body)) => {
-                format!("(lambda ({}) {})", params.join(", "), body.to_s_expr())
+                format!(
+                    "(lambda ({}) {})",
+                    params.iter().map(|p| p.to_s_expr()).collect::<Vec<_>>().join(", "),
+                    body.to_s_expr()
+                )
             }
         }
     }
@@ -46,10 +73,10 @@ impl<T: ToSExpr> ToSExpr for Value<T> {
 
 impl ToSExpr for AST {
     fn to_s_expr(&self) -> String {
-        match self {
-            AST::Literal(value) => value.to_s_expr(),
-            AST::Name(name) => name.clone(),
-            AST::Function(name, args) => format!(
+        match &self.expr {
+            Expr::Literal(value) => value.to_s_expr(),
+            Expr::Name(name) => name.clone(),
+            Expr::Function(name, args) => format!(
                 "({} {})",
                 name.to_s_expr(),
                 args.iter()
@@ -57,15 +84,76 @@ impl ToSExpr for AST {
                     .collect::<Vec<_>>()
                     .join(" ")
             ),
-            AST::FieldAccess(record, field) => format!("(.{field} {})", record.to_s_expr()),
-            AST::Let(bindings, expr) => {
+            Expr::NamedCall(name, args, named) => format!(
+                "({} {})",
+                name.to_s_expr(),
+                args.iter()
+                    .map(|a| a.to_s_expr())
+                    .chain(named.iter().map(|(n, a)| format!("{}: {}", n, a.to_s_expr())))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            Expr::FieldAccess(record, field) => format!("(.{field} {})", record.to_s_expr()),
+            Expr::Let(bindings, expr) => {
                 let binding_s_exprs = bindings
                     .iter()
-                    .map(|Binding(name, value)| format!("({} {})", name, value.to_s_expr()))
+                    .map(|Binding(pattern, value)| format!("({} {})", pattern.to_s_expr(), value.to_s_expr()))
                     .collect::<Vec<_>>()
                     .join(" ");
                 format!("(let ({}) {})", binding_s_exprs, expr.to_s_expr())
             }
+            Expr::Match(value, arms) => {
+                let arm_s_exprs = arms
+                    .iter()
+                    .map(|arm| match &arm.guard {
+                        Some(guard) => format!(
+                            "({} if {} {})",
+                            arm.pattern.to_s_expr(),
+                            guard.to_s_expr(),
+                            arm.body.to_s_expr()
+                        ),
+                        None => format!("({} {})", arm.pattern.to_s_expr(), arm.body.to_s_expr()),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!("(match {} {})", value.to_s_expr(), arm_s_exprs)
+            }
+        }
+    }
+}
+
+impl ToSExpr for Param {
+    fn to_s_expr(&self) -> String {
+        match &self.default {
+            Some(default) => format!("{} = {}", self.pattern.to_s_expr(), default.to_s_expr()),
+            None => self.pattern.to_s_expr(),
+        }
+    }
+}
+
+impl ToSExpr for Pattern {
+    fn to_s_expr(&self) -> String {
+        match &self.kind {
+            PatternKind::Wildcard => "_".to_string(),
+            PatternKind::Name(name) => name.clone(),
+            PatternKind::Literal(value) => value.to_s_expr(),
+            PatternKind::List(elements, rest) => {
+                let mut parts = elements.iter().map(|p| p.to_s_expr()).collect::<Vec<_>>();
+                if let Some(rest) = rest {
+                    parts.push(format!("..{}", rest.to_s_expr()));
+                }
+                format!("[{}]", parts.join(", "))
+            }
+            PatternKind::Record(fields) => format!(
+                "{{{}}}",
+                fields
+                    .iter()
+                    .map(|(k, p)| format!("{}: {}", k, p.to_s_expr()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            PatternKind::Tag(tag, None) => format!("#{}", tag),
+            PatternKind::Tag(tag, Some(payload)) => format!("#{}({})", tag, payload.to_s_expr()),
         }
     }
 }
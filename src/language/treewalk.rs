@@ -1,11 +1,17 @@
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap};
 
 use crate::{
     language::{
-        ast::{AST, Binding, EvaluatedValue, Function, Value},
+        ast::{AST, Binding, EvaluatedValue, Expr, Function, MatchArm, Param, Pattern, PatternKind, Value, ValueType, missing_arguments},
         bultins::{BuiltinFunction, lookup_builtin},
-        errors::Error,
+        distribution::Distribution,
+        errors::{Error, ErrorKind},
         parser::parse,
+        rational::Rational,
+        s_exprs::ToSExpr,
+        types::{Type, check},
+        units::{Quantity, Unit, dimension_mismatch},
     },
     reactive::language::{IntermediateRep, ReactiveContext},
 };
@@ -43,6 +49,363 @@ impl<'a, T: Clone> Scope<'a, T> {
     }
 }
 
+/// Matches a value against a pattern, collecting the values of the names it binds.
+///
+/// If the value does not fit the pattern the error says why, and is attributed
+/// to the innermost part of the pattern that did not match.
+fn destructure(
+    pattern: &Pattern,
+    value: &EvaluatedValue,
+    bindings: &mut Vec<(String, EvaluatedValue)>,
+) -> Result<(), Error> {
+    let result = match (&pattern.kind, &value.0) {
+        (PatternKind::Wildcard, _) => Ok(()),
+        (PatternKind::Name(name), _) => {
+            bindings.push((name.clone(), value.clone()));
+            Ok(())
+        }
+        (PatternKind::Literal(literal), v) => {
+            if literal.0.compare(v).is_ok_and(|o| o.is_eq()) {
+                Ok(())
+            } else {
+                Err(Error::new(ErrorKind::PatternMismatch {
+                    pattern: literal.to_s_expr(),
+                    value: value.to_s_expr(),
+                }))
+            }
+        }
+        (PatternKind::List(elements, rest), Value::List(items)) => {
+            let length_matches = match rest {
+                Some(_) => items.len() >= elements.len(),
+                None => items.len() == elements.len(),
+            };
+            if !length_matches {
+                return Err(Error::new(ErrorKind::ListLength {
+                    expected: elements.len(),
+                    at_least: rest.is_some(),
+                    actual: items.len(),
+                })
+                .or_span(pattern.span));
+            }
+            for (p, item) in elements.iter().zip(items) {
+                destructure(p, item, bindings)?;
+            }
+            match rest {
+                Some(rest) => {
+                    let remaining = Value::List(items[elements.len()..].to_vec()).into();
+                    destructure(rest, &remaining, bindings)
+                }
+                None => Ok(()),
+            }
+        }
+        (PatternKind::List(..), v) => Err(Error::type_mismatch(ValueType::List, v.value_type(), None)),
+        (PatternKind::Record(fields), Value::Record(record)) => {
+            for (field, p) in fields {
+                let v = record
+                    .get(field)
+                    .ok_or_else(|| Error::new(ErrorKind::MissingField(field.clone())).or_span(p.span))?;
+                destructure(p, v, bindings)?;
+            }
+            Ok(())
+        }
+        (PatternKind::Record(_), v) => {
+            Err(Error::type_mismatch(ValueType::Record, v.value_type(), None))
+        }
+        (PatternKind::Tag(tag, payload), Value::Tag(value_tag, value_payload)) => match (payload, value_payload) {
+            (None, None) if tag == value_tag => Ok(()),
+            (Some(p), Some(v)) if tag == value_tag => destructure(p, v, bindings),
+            _ => Err(Error::new(ErrorKind::PatternMismatch {
+                pattern: pattern.to_s_expr(),
+                value: value.to_s_expr(),
+            })),
+        },
+        (PatternKind::Tag(..), v) => Err(Error::type_mismatch(ValueType::Tag, v.value_type(), None)),
+    };
+    result.map_err(|e| e.or_span(pattern.span))
+}
+
+/// Converts a value to a boolean, or reports a type error for the argument at `position`.
+fn expect_bool(value: EvaluatedValue, position: Option<usize>) -> Result<bool, Error> {
+    let actual = value.0.value_type();
+    value
+        .try_into()
+        .map_err(|_| Error::type_mismatch(ValueType::Boolean, actual, position))
+}
+
+/// Matches dice and distributions, whose comparisons give chances rather than booleans
+macro_rules! random {
+    () => {
+        Value::Dice(_) | Value::Distribution(_)
+    };
+}
+
+/// Matches the values [`to_distribution`] accepts
+macro_rules! random_or_int {
+    () => {
+        Value::Dice(_) | Value::Distribution(_) | Value::Integer(_)
+    };
+}
+
+/// Matches the values [`to_rational`] accepts
+macro_rules! rational_or_int {
+    () => {
+        Value::Rational(_) | Value::Integer(_)
+    };
+}
+
+/// The chance of each outcome of dice, distributions and integers, which always have the same outcome
+fn to_distribution(value: &Value<EvaluatedValue>) -> Result<Distribution, Error> {
+    match value {
+        Value::Dice(dice) => Distribution::from_dice(dice),
+        Value::Distribution(distribution) => Ok(distribution.clone()),
+        Value::Integer(n) => Ok(Distribution::constant(*n)),
+        v => Err(Error::type_mismatch(ValueType::Distribution, v.value_type(), None)),
+    }
+}
+
+fn to_rational(value: &Value<EvaluatedValue>) -> Result<Rational, Error> {
+    match value {
+        Value::Rational(r) => Ok(*r),
+        Value::Integer(n) => Ok(Rational::from(*n)),
+        v => Err(Error::type_mismatch(ValueType::Rational, v.value_type(), None)),
+    }
+}
+
+/// Compares the outcomes of two random values, giving an event with outcome 1 when `test` passes and 0 otherwise
+fn chance(
+    a: &Value<EvaluatedValue>,
+    b: &Value<EvaluatedValue>,
+    test: fn(Ordering) -> bool,
+) -> Result<EvaluatedValue, Error> {
+    Ok(Value::Distribution(to_distribution(a)?.chance(&to_distribution(b)?, test)?).into())
+}
+
+/// The chance that comparing dice or a distribution with a fraction passes `test`, where
+/// `rational_first` is set when the fraction is on the left of the comparison
+fn chance_rational(
+    random: &Value<EvaluatedValue>,
+    r: &Rational,
+    rational_first: bool,
+    test: fn(Ordering) -> bool,
+) -> Result<EvaluatedValue, Error> {
+    let distribution = to_distribution(random)?;
+    let chance = distribution.chance_rational(r, |o| test(if rational_first { o.reverse() } else { o }))?;
+    Ok(Value::Distribution(chance).into())
+}
+
+/// The chances to hit and crit with an attack roll as a record, see [`Distribution::attack`]
+fn attack_chances(roll: &Value<EvaluatedValue>, bonus: i64, ac: i64, crit_range: i64) -> Result<EvaluatedValue, Error> {
+    let chances = to_distribution(roll)?.attack(bonus, ac, crit_range)?;
+    Ok(Value::Record(BTreeMap::from([
+        ("crit".to_string(), Value::Rational(chances.crit).into()),
+        ("hit".to_string(), Value::Rational(chances.hit).into()),
+    ]))
+    .into())
+}
+
+/// Converts the result of a checked integer operation into an overflow error.
+fn checked(result: Option<i64>) -> Result<i64, Error> {
+    result.ok_or_else(|| Error::new(ErrorKind::Overflow))
+}
+
+/// Converts a rounded rational back to an integer.
+fn to_integer(n: i128) -> Result<i64, Error> {
+    i64::try_from(n).map_err(|_| Error::new(ErrorKind::Overflow))
+}
+
+/// Folds a list of numbers with `int_op`, switching to `rational_op` from the first rational on.
+fn fold_numbers(
+    list: &[EvaluatedValue],
+    init: i64,
+    int_op: fn(i64, i64) -> Option<i64>,
+    rational_op: fn(&Rational, &Rational) -> Result<Rational, Error>,
+) -> Result<EvaluatedValue, Error> {
+    let mut total = Value::Integer(init);
+    for elem in list {
+        total = match (&total, &elem.0) {
+            (Value::Integer(a), Value::Integer(b)) => Value::Integer(checked(int_op(*a, *b))?),
+            (a, b @ rational_or_int!()) => Value::Rational(rational_op(&to_rational(a)?, &to_rational(b)?)?),
+            (_, v) => return Err(Error::type_mismatch(ValueType::Integer, v.value_type(), None)),
+        };
+    }
+    Ok(total.into())
+}
+
+/// Adds up a list of quantities after `first`, giving the total in the unit of `first`
+fn sum_quantities(first: &Quantity, rest: &[EvaluatedValue]) -> Result<EvaluatedValue, Error> {
+    let mut total = first.clone();
+    for elem in rest {
+        total = match &elem.0 {
+            Value::Quantity(q) => total.add(q)?,
+            rational_or_int!() => return Err(dimension_mismatch(Some(total.dimension()), None)),
+            v => return Err(Error::type_mismatch(ValueType::Quantity, v.value_type(), None)),
+        };
+    }
+    Ok(Value::Quantity(total).into())
+}
+
+/// Divides `a` by `b`, rounding towards negative infinity.
+///
+/// This is the rounding D&D uses, so `floor_div(-1, 2)` is `-1` rather than `0`.
+fn floor_div(a: i64, b: i64) -> Result<i64, Error> {
+    if b == 0 {
+        return Err(Error::new(ErrorKind::DivisionByZero));
+    }
+    let q = checked(a.checked_div(b))?;
+    if a % b != 0 && ((a < 0) != (b < 0)) {
+        checked(q.checked_sub(1))
+    } else {
+        Ok(q)
+    }
+}
+
+/// The remainder of `floor_div`, which always has the same sign as `b`.
+fn floor_mod(a: i64, b: i64) -> Result<i64, Error> {
+    if b == 0 {
+        return Err(Error::new(ErrorKind::DivisionByZero));
+    }
+    let r = checked(a.checked_rem(b))?;
+    if r != 0 && ((r < 0) != (b < 0)) {
+        Ok(r + b)
+    } else {
+        Ok(r)
+    }
+}
+
+/// A stable merge sort which stops at the first comparison that fails.
+///
+/// `slice::sort_by` needs an infallible total order, but comparing values can
+/// fail (e.g. when they contain functions), so the comparison errors are threaded through here instead.
+fn try_sort<T>(
+    mut items: Vec<T>,
+    compare: &mut impl FnMut(&T, &T) -> Result<Ordering, Error>,
+) -> Result<Vec<T>, Error> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = try_sort(items, compare)?;
+    let right = try_sort(right, compare)?;
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        // Take from the left on ties to keep the sort stable
+        if compare(l, r)?.is_le() {
+            merged.extend(left.next());
+        } else {
+            merged.extend(right.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+/// Merges `right` into `left`, recursively merging fields which are records on both sides.
+///
+/// Any other field in `right` replaces the one in `left`, as with `//`.
+fn deep_merge(
+    left: &BTreeMap<String, EvaluatedValue>,
+    right: &BTreeMap<String, EvaluatedValue>,
+) -> BTreeMap<String, EvaluatedValue> {
+    let mut merged = left.clone();
+    for (k, v) in right {
+        let new_value = match (merged.get(k), v) {
+            (Some(EvaluatedValue(Value::Record(l))), EvaluatedValue(Value::Record(r))) => {
+                Value::Record(deep_merge(l, r)).into()
+            }
+            _ => v.clone(),
+        };
+        merged.insert(k.clone(), new_value);
+    }
+    merged
+}
+
+/// The most numbers a range can have, so that a mistyped bound can not use up all memory
+const MAX_RANGE: i64 = 1_000_000;
+
+/// The list of integers from `start` up to but not including `end`
+fn range(start: i64, end: i64) -> Result<EvaluatedValue, Error> {
+    let len = end as i128 - start as i128;
+    if len > MAX_RANGE as i128 {
+        return Err(Error::new(ErrorKind::RangeTooLong { len, max: MAX_RANGE }));
+    }
+    Ok(Value::List((start..end).map(|i| Value::Integer(i).into()).collect()).into())
+}
+
+/// Clamps a count argument such as the one given to `take` to the length of a list.
+fn clamp_count(n: i64, len: usize) -> usize {
+    n.clamp(0, len as i64) as usize
+}
+
+/// Builds a function taking the `missing` remaining arguments, which calls `function` with `args`
+/// followed by them. The arguments must not refer to local variables.
+fn partially_apply(function: &Function, args: Vec<AST>, missing: usize) -> EvaluatedValue {
+    let params = (0..missing)
+        .map(|i| Param::new(Pattern::generated(i, Default::default()), None))
+        .collect();
+    let args = args
+        .into_iter()
+        .chain((0..missing).map(|i| AST::from(Pattern::generated_name(i))))
+        .collect();
+    let body = AST::function(Value::Function(function.clone()), args, Default::default());
+    Value::Function(Function::Lambda(params, Box::new(body))).into()
+}
+
+/// A builtin signature as one predicate per argument
+type Signature<'a> = &'a [&'a dyn Fn(&EvaluatedValue) -> bool];
+
+/// Works out why a builtin's arguments did not match any of its signatures.
+///
+/// Each signature is a list of predicates, one per argument. If no signature
+/// takes the given number of arguments an arity error is reported. Otherwise
+/// the error points at the first mismatching argument of the signature that
+/// matched the most arguments, listing every type that would have been accepted there.
+fn argument_error(args: &[EvaluatedValue], signatures: &[Signature]) -> Error {
+    let candidates: Vec<_> = signatures
+        .iter()
+        .filter(|sig| sig.len() == args.len())
+        .collect();
+
+    if candidates.is_empty() {
+        let mut expected: Vec<usize> = signatures.iter().map(|sig| sig.len()).collect();
+        expected.sort();
+        expected.dedup();
+        return Error::new(ErrorKind::Arity {
+            expected,
+            actual: args.len(),
+        });
+    }
+
+    let first_mismatch = |sig: Signature| {
+        sig.iter()
+            .zip(args)
+            .position(|(matches, arg)| !matches(arg))
+            .unwrap_or(args.len())
+    };
+    let position = candidates
+        .iter()
+        .map(|sig| first_mismatch(sig))
+        .max()
+        .unwrap();
+    let expected = ValueType::ALL
+        .into_iter()
+        .filter(|t| {
+            candidates
+                .iter()
+                .any(|sig| first_mismatch(sig) == position && sig[position](&t.example()))
+        })
+        .collect();
+
+    Error::new(ErrorKind::TypeMismatch {
+        expected,
+        actual: args[position].0.value_type(),
+        position: Some(position),
+    })
+}
+
 struct InterpreterCtx<'inner, 'outer> {
     ctx: &'outer mut ReactiveContext<'inner, AST>,
     local_vars: Scope<'outer, EvaluatedValue>,
@@ -51,7 +414,7 @@ struct InterpreterCtx<'inner, 'outer> {
 impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
     fn new(ctx: &'outer mut ReactiveContext<'inner, AST>) -> Self {
         InterpreterCtx {
-            ctx: ctx,
+            ctx,
             local_vars: Scope::new(),
         }
     }
@@ -76,87 +439,210 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
         self.local_vars.insert(name, value);
     }
 
+    /// Destructures a value with a pattern, adding the names it binds to the local scope.
+    fn bind_pattern(&mut self, pattern: &Pattern, value: &EvaluatedValue) -> Result<(), Error> {
+        let mut bindings = vec![];
+        destructure(pattern, value, &mut bindings)?;
+        for (name, value) in bindings {
+            self.add_local_var(name, value);
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression, attributing any error raised directly by it to its span.
     fn evaluate(&mut self, ast: &AST) -> Result<EvaluatedValue, Error> {
-        match ast {
-            AST::Literal(value) => Ok(self.evaluate_value(value)?),
+        self.evaluate_expr(ast).map_err(|e| e.or_span(ast.span))
+    }
+
+    fn evaluate_expr(&mut self, ast: &AST) -> Result<EvaluatedValue, Error> {
+        match &ast.expr {
+            Expr::Literal(value) => self.evaluate_value(value),
 
-            AST::Name(name) => {
+            Expr::Name(name) => {
                 // Names starting $ force a cell reference using the rest of the name
                 if let Some(c) = name.chars().next()
                     && c == '$'
                 {
                     if let Some((_, value)) = self.ctx.read_cell_by_name(&name[1..]) {
-                        value.clone().map_err(|_| Error::propogated_error(&name))
+                        value
+                            .as_ref()
+                            .map(|v| v.relocate(ast.span))
+                            .map_err(|e| Error::propagated(&name[1..], e.clone()))
                     } else {
-                        Err(Error::with_message(format!(
-                            "Unknown cell name \"{}\"",
-                            name[1..].to_string()
-                        )))
+                        Err(Error::new(ErrorKind::UnknownCell(name[1..].to_string())))
                     }
                 } else if let Some(value) = self.local_vars.lookup(name) {
-                    Ok(value.clone())
+                    Ok(value)
                 } else if let Some(builtin) = lookup_builtin(name) {
                     Ok(Value::Function(Function::Builtin(builtin)).into())
                 } else if let Some((_, value)) = self.ctx.read_cell_by_name(name) {
-                    value.clone().map_err(|_| Error::propogated_error(&name))
+                    value
+                        .as_ref()
+                        .map(|v| v.relocate(ast.span))
+                        .map_err(|e| Error::propagated(name, e.clone()))
                 } else {
-                    Err(Error::with_message(format!("Unknown name \"{}\"", name)))
+                    Err(Error::new(ErrorKind::UnknownName(name.clone())))
                 }
             }
-            AST::FieldAccess(record, field) => {
+            Expr::FieldAccess(record, field) => {
                 let record = self.evaluate(record)?;
                 match record {
-                    EvaluatedValue(Value::Record(m)) => Ok(m
+                    EvaluatedValue(Value::Record(m)) => m
                         .get(field)
                         .cloned()
-                        .ok_or(Error::with_message("Field does not exist"))?
-                        .into()),
-                    _ => Err(Error::with_message(
-                        "Cannot access the field of a non-record type",
+                        .ok_or_else(|| Error::new(ErrorKind::MissingField(field.clone()))),
+                    EvaluatedValue(v) => Err(Error::type_mismatch(
+                        ValueType::Record,
+                        v.value_type(),
+                        None,
                     )),
                 }
             }
 
-            AST::Let(bindings, expr) => {
+            Expr::Let(bindings, expr) => {
                 let mut inner_scope = self.push_scope();
-                for Binding(name, expr) in bindings {
+                for Binding(pattern, expr) in bindings {
                     let value = inner_scope.evaluate(expr)?;
-                    inner_scope.add_local_var(name.clone(), value);
+                    inner_scope.bind_pattern(pattern, &value)?;
                 }
                 inner_scope.evaluate(expr)
             }
 
-            AST::Function(func_name, args) => {
+            Expr::Match(value, arms) => {
+                let value = self.evaluate(value)?;
+                for MatchArm { pattern, guard, body } in arms {
+                    let mut inner_scope = self.push_scope();
+                    if inner_scope.bind_pattern(pattern, &value).is_err() {
+                        continue;
+                    }
+                    if let Some(guard) = guard
+                        && !expect_bool(inner_scope.evaluate(guard)?, None).map_err(|e| e.or_span(guard.span))?
+                    {
+                        continue;
+                    }
+                    return inner_scope.evaluate(body);
+                }
+                Err(Error::new(ErrorKind::NoMatch(value.to_s_expr())))
+            }
+
+            Expr::Function(func_name, args) => {
                 let function = self.evaluate(func_name)?;
                 match function {
                     EvaluatedValue(Value::Function(function)) => {
                         self.evaluate_function(&function, args)
                     }
-                    _ => Err(Error::with_message("Uncallable type")),
+                    EvaluatedValue(v) => Err(Error::type_mismatch(
+                        ValueType::Function,
+                        v.value_type(),
+                        None,
+                    )),
+                }
+            }
+            Expr::NamedCall(func_name, args, named) => {
+                let function = self.evaluate(func_name)?;
+                match function {
+                    EvaluatedValue(Value::Function(function)) => {
+                        self.evaluate_named_call(&function, args, named)
+                    }
+                    EvaluatedValue(v) => Err(Error::type_mismatch(
+                        ValueType::Function,
+                        v.value_type(),
+                        None,
+                    )),
                 }
             }
         }
     }
 
+    /// Calls a lambda with a value for each parameter, or `None` to use the parameter's default.
+    /// Defaults are evaluated after the parameters before them are bound, so they can refer to them.
+    fn call_lambda(
+        &mut self,
+        params: &[Param],
+        body: &AST,
+        mut args: Vec<Option<EvaluatedValue>>,
+    ) -> Result<EvaluatedValue, Error> {
+        args.resize(params.len(), None);
+        let mut ctx = self.empty_context();
+        for (param, arg) in params.iter().zip(args) {
+            let value = match (arg, &param.default) {
+                (Some(value), _) => value,
+                (None, Some(default)) => ctx.evaluate(default)?,
+                (None, None) => {
+                    return Err(Error::new(ErrorKind::MissingArgument(param.pattern.to_s_expr())));
+                }
+            };
+            ctx.bind_pattern(&param.pattern, &value)?;
+        }
+        ctx.evaluate(body)
+    }
+
+    /// Calls a function with arguments given by position and by name, only lambdas have named parameters
+    fn evaluate_named_call(
+        &mut self,
+        function: &Function,
+        args: &[AST],
+        named: &[(String, AST)],
+    ) -> Result<EvaluatedValue, Error> {
+        let Function::Lambda(params, body) = function else {
+            let (name, arg) = &named[0];
+            return Err(Error::new(ErrorKind::UnknownArgument {
+                name: name.clone(),
+                params: vec![],
+            })
+            .or_span(arg.span));
+        };
+
+        if args.len() > params.len() {
+            let required = params.iter().take_while(|p| p.default.is_none()).count();
+            return Err(Error::new(ErrorKind::Arity {
+                expected: (required..=params.len()).collect(),
+                actual: args.len() + named.len(),
+            }));
+        }
+        let mut values = args
+            .iter()
+            .map(|ast| self.evaluate(ast).map(Some))
+            .collect::<Result<Vec<_>, Error>>()?;
+        values.resize(params.len(), None);
+        for (name, arg) in named {
+            let Some(position) = params.iter().position(|p| p.name() == Some(name)) else {
+                return Err(Error::new(ErrorKind::UnknownArgument {
+                    name: name.clone(),
+                    params: params.iter().filter_map(|p| p.name().map(str::to_string)).collect(),
+                })
+                .or_span(arg.span));
+            };
+            if values[position].is_some() {
+                return Err(Error::new(ErrorKind::DuplicateArgument(name.clone())).or_span(arg.span));
+            }
+            values[position] = Some(self.evaluate(arg)?);
+        }
+        self.call_lambda(params, body, values)
+    }
+
     fn evaluate_function(
         &mut self,
         function: &Function,
         args: &[AST],
     ) -> Result<EvaluatedValue, Error> {
         match function {
-            Function::Lambda(arg_names, body) => {
+            Function::Lambda(params, body) => {
                 let evaluated_args =
                     args.iter()
                         .map(|ast| self.evaluate(ast))
                         .collect::<Result<Vec<EvaluatedValue>, Error>>()?;
-                if evaluated_args.len() != arg_names.len() {
-                    return Err(Error::with_message("Incorrect number of arguments"));
+                let required = params.iter().take_while(|p| p.default.is_none()).count();
+                if let Some(missing) = missing_arguments(&[required], evaluated_args.len()) {
+                    return Ok(partially_apply(function, evaluated_args.into_iter().map(AST::from).collect(), missing));
                 }
-                let mut ctx = self.empty_context();
-                for (name, arg) in arg_names.iter().zip(evaluated_args.iter()) {
-                    ctx.add_local_var(name.clone(), arg.clone());
+                if evaluated_args.len() < required || evaluated_args.len() > params.len() {
+                    return Err(Error::new(ErrorKind::Arity {
+                        expected: (required..=params.len()).collect(),
+                        actual: evaluated_args.len(),
+                    }));
                 }
-                ctx.evaluate(&body)
+                self.call_lambda(params, body, evaluated_args.into_iter().map(Some).collect())
             }
             Function::Builtin(builtin) => {
                 // Strict evaluation, match on the number and types of arguments
@@ -167,52 +653,193 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                                     .map(|ast| self.evaluate(ast))
                                     .collect::<Result<Vec<EvaluatedValue>, Error>>()?;
 
+                                let arities = [$(<[&str]>::len(&[$(stringify!($pat)),*])),+];
+                                if let Some(missing) = missing_arguments(&arities, evaluated_args.len()) {
+                                    return Ok(partially_apply(function, evaluated_args.into_iter().map(AST::from).collect(), missing));
+                                }
+
                                 match evaluated_args.as_slice() {
                                     $([ $( EvaluatedValue($pat) ),* ] => $body,)+
-                                    _ => Err(Error::with_message("Invalid arguments")),
+                                    _ => {
+                                        // Predicates for each argument of each signature, used to work out what went wrong
+                                        #[allow(unused_variables)]
+                                        let signatures: &[Signature] = &[
+                                            $(&[$(&|v: &EvaluatedValue| matches!(v, EvaluatedValue($pat))),*]),+
+                                        ];
+                                        Err(argument_error(&evaluated_args, signatures))
+                                    }
                                 }
                             }};
                         }
 
                 // Lazy evaluation, match on the number of arguments but leave them as AST nodes
                 macro_rules! lazy_eval {
-                            ($([$( $name:ident ),*] => $body:expr),+ $(,)?) => {
+                            ($([$( $name:ident ),*] => $body:expr),+ $(,)?) => {{
+                                // The arguments are not evaluated yet, so capture the local variables they use
+                                let arities = [$([$(stringify!($name)),*].len()),+];
+                                if let Some(missing) = missing_arguments(&arities, args.len()) {
+                                    let args = args.iter().map(|a| self.capture_values(&mut Scope::new(), a)).collect();
+                                    return Ok(partially_apply(function, args, missing));
+                                }
+
                                 match args {
                                     $([ $( $name ),* ] => $body,)+
-                                    _ => Err(Error::with_message("Incorrect number of arguments")),
+                                    _ => Err(Error::new(ErrorKind::Arity {
+                                        expected: arities.to_vec(),
+                                        actual: args.len(),
+                                    })),
                                 }
-                            };
+                            }};
                         }
 
                 use BuiltinFunction::*;
 
                 match builtin {
                     Add => eval_function!(
-                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(a + b).into()),
+                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(checked(a.checked_add(*b))?).into()),
                         [Value::String(a), Value::String(b)] => Ok(Value::String(a.to_owned() + b).into()),
                         [Value::List(a), Value::List(b)] => Ok(Value::List(a.iter().chain(b.iter()).cloned().collect()).into()),
+                        [Value::Dice(a), Value::Dice(b)] => Ok(Value::Dice(a.add(b)?).into()),
+                        [Value::Dice(a), Value::Integer(b)] => Ok(Value::Dice(a.add_constant(*b)?).into()),
+                        [Value::Integer(a), Value::Dice(b)] => Ok(Value::Dice(b.add_constant(*a)?).into()),
+                        [a @ random_or_int!(), b @ random_or_int!()] => {
+                            Ok(Value::Distribution(to_distribution(a)?.add(&to_distribution(b)?)?).into())
+                        },
+                        [a @ rational_or_int!(), b @ rational_or_int!()] => Ok(Value::Rational(to_rational(a)?.add(&to_rational(b)?)?).into()),
+                        [Value::Quantity(a), Value::Quantity(b)] => Ok(Value::Quantity(a.add(b)?).into()),
+                        [Value::Quantity(a), rational_or_int!()] => Err(dimension_mismatch(Some(a.dimension()), None)),
+                        [rational_or_int!(), Value::Quantity(b)] => Err(dimension_mismatch(None, Some(b.dimension()))),
                     ),
                     Sub => eval_function!(
-                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(a - b).into()),
+                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(checked(a.checked_sub(*b))?).into()),
+                        [Value::Dice(a), Value::Dice(b)] => Ok(Value::Dice(a.add(&b.scale(-1)?)?).into()),
+                        [Value::Dice(a), Value::Integer(b)] => Ok(Value::Dice(a.add_constant(checked(b.checked_neg())?)?).into()),
+                        [Value::Integer(a), Value::Dice(b)] => Ok(Value::Dice(b.scale(-1)?.add_constant(*a)?).into()),
+                        [a @ random_or_int!(), b @ random_or_int!()] => {
+                            Ok(Value::Distribution(to_distribution(a)?.add(&to_distribution(b)?.scale(-1)?)?).into())
+                        },
+                        [a @ rational_or_int!(), b @ rational_or_int!()] => Ok(Value::Rational(to_rational(a)?.sub(&to_rational(b)?)?).into()),
+                        [Value::Quantity(a), Value::Quantity(b)] => Ok(Value::Quantity(a.sub(b)?).into()),
+                        [Value::Quantity(a), rational_or_int!()] => Err(dimension_mismatch(Some(a.dimension()), None)),
+                        [rational_or_int!(), Value::Quantity(b)] => Err(dimension_mismatch(None, Some(b.dimension()))),
                     ),
                     Mul => eval_function!(
-                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(a * b).into()),
+                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(checked(a.checked_mul(*b))?).into()),
+                        [Value::Integer(a), Value::Dice(b)] => Ok(Value::Dice(b.scale(*a)?).into()),
+                        [Value::Dice(a), Value::Integer(b)] => Ok(Value::Dice(a.scale(*b)?).into()),
+                        [Value::Integer(a), Value::Distribution(b)] => Ok(Value::Distribution(b.scale(*a)?).into()),
+                        [Value::Distribution(a), Value::Integer(b)] => Ok(Value::Distribution(a.scale(*b)?).into()),
+                        [a @ rational_or_int!(), b @ rational_or_int!()] => Ok(Value::Rational(to_rational(a)?.mul(&to_rational(b)?)?).into()),
+                        [Value::Quantity(a), b @ rational_or_int!()] => Ok(Value::Quantity(a.scale(&to_rational(b)?)?).into()),
+                        [a @ rational_or_int!(), Value::Quantity(b)] => Ok(Value::Quantity(b.scale(&to_rational(a)?)?).into()),
+                    ),
+                    Div => eval_function!(
+                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(floor_div(*a, *b)?).into()),
+                        // Dividing exactly once either side is a rational
+                        [a @ rational_or_int!(), b @ rational_or_int!()] => Ok(Value::Rational(to_rational(a)?.div(&to_rational(b)?)?).into()),
+                        [Value::Quantity(a), b @ rational_or_int!()] => {
+                            Ok(Value::Quantity(a.scale(&Rational::ONE.div(&to_rational(b)?)?)?).into())
+                        },
+                        // How many times one quantity fits in another, as in `30 ft / 5 ft`
+                        [Value::Quantity(a), Value::Quantity(b)] => Ok(Value::Rational(a.ratio(b)?).into()),
+                    ),
+                    Mod => eval_function!(
+                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(floor_mod(*a, *b)?).into()),
+                        [a @ rational_or_int!(), b @ rational_or_int!()] => Ok(Value::Rational(to_rational(a)?.modulo(&to_rational(b)?)?).into()),
                     ),
                     Negate => eval_function!(
-                        [Value::Integer(a)] => Ok(Value::Integer(-a).into()),
+                        [Value::Integer(a)] => Ok(Value::Integer(checked(a.checked_neg())?).into()),
+                        [Value::Dice(a)] => Ok(Value::Dice(a.scale(-1)?).into()),
+                        [Value::Distribution(a)] => Ok(Value::Distribution(a.scale(-1)?).into()),
+                        [Value::Rational(a)] => Ok(Value::Rational(a.neg()?).into()),
+                        [Value::Quantity(a)] => Ok(Value::Quantity(a.scale(&Rational::from(-1))?).into()),
+                    ),
+                    DivCeil => eval_function!(
+                        [Value::Integer(a), Value::Integer(b)] => {
+                            let q = floor_div(checked(a.checked_neg())?, *b)?;
+                            Ok(Value::Integer(checked(q.checked_neg())?).into())
+                        },
+                    ),
+                    // floor(a / b + 1/2), computed as floor((2a + b) / 2b) to stay in integers
+                    RoundHalfUp => eval_function!(
+                        [Value::Integer(a), Value::Integer(b)] => {
+                            let numerator = checked(a.checked_mul(2).and_then(|a| a.checked_add(*b)))?;
+                            let denominator = checked(b.checked_mul(2))?;
+                            Ok(Value::Integer(floor_div(numerator, denominator)?).into())
+                        },
+                    ),
+                    Min => eval_function!(
+                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(*a.min(b)).into()),
+                        [a @ rational_or_int!(), b @ rational_or_int!()] => Ok(Value::Rational(to_rational(a)?.min(to_rational(b)?)).into()),
+                        [Value::List(l)] => {
+                            let mut min = l.first().ok_or_else(|| Error::new(ErrorKind::EmptyList))?;
+                            for elem in &l[1..] {
+                                if elem.0.compare(&min.0)?.is_lt() {
+                                    min = elem;
+                                }
+                            }
+                            Ok(min.clone())
+                        },
+                    ),
+                    Max => eval_function!(
+                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(*a.max(b)).into()),
+                        [a @ rational_or_int!(), b @ rational_or_int!()] => Ok(Value::Rational(to_rational(a)?.max(to_rational(b)?)).into()),
+                        [Value::List(l)] => {
+                            let mut max = l.first().ok_or_else(|| Error::new(ErrorKind::EmptyList))?;
+                            for elem in &l[1..] {
+                                if elem.0.compare(&max.0)?.is_gt() {
+                                    max = elem;
+                                }
+                            }
+                            Ok(max.clone())
+                        },
+                    ),
+                    Clamp => eval_function!(
+                        [Value::Integer(x), Value::Integer(lo), Value::Integer(hi)] => {
+                            // Unlike Ord::clamp an inverted range is not a panic, the lower bound wins
+                            Ok(Value::Integer(*x.min(hi).max(lo)).into())
+                        },
+                        [x @ rational_or_int!(), lo @ rational_or_int!(), hi @ rational_or_int!()] => {
+                            Ok(Value::Rational(to_rational(x)?.min(to_rational(hi)?).max(to_rational(lo)?)).into())
+                        },
+                    ),
+                    Abs => eval_function!(
+                        [Value::Integer(a)] => Ok(Value::Integer(checked(a.checked_abs())?).into()),
+                        [Value::Rational(a)] => Ok(Value::Rational(a.abs()?).into()),
+                    ),
+                    Floor => eval_function!(
+                        [Value::Rational(a)] => Ok(Value::Integer(to_integer(a.floor())?).into()),
+                        [Value::Integer(a)] => Ok(Value::Integer(*a).into()),
+                    ),
+                    Ceil => eval_function!(
+                        [Value::Rational(a)] => Ok(Value::Integer(to_integer(a.ceil())?).into()),
+                        [Value::Integer(a)] => Ok(Value::Integer(*a).into()),
+                    ),
+                    Round => eval_function!(
+                        [Value::Rational(a)] => Ok(Value::Integer(to_integer(a.round())?).into()),
+                        [Value::Integer(a)] => Ok(Value::Integer(*a).into()),
+                    ),
+                    // Converts a quantity to another unit of the same dimension, or gives a number a unit
+                    To => eval_function!(
+                        [value @ (Value::Quantity(_) | rational_or_int!()), Value::String(unit)] => {
+                            let unit = Unit::lookup(unit).ok_or_else(|| Error::new(ErrorKind::UnknownUnit(unit.clone())))?;
+                            match value {
+                                Value::Quantity(q) => Ok(Value::Quantity(q.to(unit)?).into()),
+                                n => Ok(Value::Quantity(Quantity::new(to_rational(n)?, unit)).into()),
+                            }
+                        },
                     ),
                     Index => eval_function!(
                         [Value::List(l), Value::Integer(i)] => {
                             let len = l.len() as i64;
                             if *i < 0 || *i >= len {
-                                Err(Error::with_message("Index out of range"))
+                                Err(Error::new(ErrorKind::Index { index: *i, len: l.len() }))
                             } else {
-                                Ok(l[*i as usize].clone().into())
+                                Ok(l[*i as usize].clone())
                             }
                         },
                         [Value::Record(r), Value::String(s)] => {
-                            let value = r.get(s).cloned().ok_or(Error::with_message("Field does not exist"))?;
-                            Ok(value.into())
+                            r.get(s).cloned().ok_or_else(|| Error::new(ErrorKind::MissingField(s.clone())))
                         }
                     ),
                     Read => eval_function!([] => {
@@ -222,50 +849,91 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                         [Value::String(target), to_push] => {
                             let to_push = to_push.clone().into();
                             self.ctx.add_push_by_name(target, &to_push);
-                            Ok(to_push.into())
+                            Ok(to_push)
                         },
                     ),
                     LessThan => eval_function!(
-                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Boolean(a < b).into()),
+                        [a @ random!(), b @ random_or_int!()] => chance(a, b, Ordering::is_lt),
+                        [a @ Value::Integer(_), b @ random!()] => chance(a, b, Ordering::is_lt),
+                        [a @ random!(), Value::Rational(r)] => chance_rational(a, r, false, Ordering::is_lt),
+                        [Value::Rational(r), b @ random!()] => chance_rational(b, r, true, Ordering::is_lt),
+                        [a, b] => Ok(Value::Boolean(a.compare(b)?.is_lt()).into()),
                     ),
                     GreaterThan => eval_function!(
-                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Boolean(a > b).into()),
+                        [a @ random!(), b @ random_or_int!()] => chance(a, b, Ordering::is_gt),
+                        [a @ Value::Integer(_), b @ random!()] => chance(a, b, Ordering::is_gt),
+                        [a @ random!(), Value::Rational(r)] => chance_rational(a, r, false, Ordering::is_gt),
+                        [Value::Rational(r), b @ random!()] => chance_rational(b, r, true, Ordering::is_gt),
+                        [a, b] => Ok(Value::Boolean(a.compare(b)?.is_gt()).into()),
                     ),
                     LessThanEqual => eval_function!(
-                                [Value::Integer(a), Value::Integer(b)] => Ok(Value::Boolean(a <= b).into()),),
+                        [a @ random!(), b @ random_or_int!()] => chance(a, b, Ordering::is_le),
+                        [a @ Value::Integer(_), b @ random!()] => chance(a, b, Ordering::is_le),
+                        [a @ random!(), Value::Rational(r)] => chance_rational(a, r, false, Ordering::is_le),
+                        [Value::Rational(r), b @ random!()] => chance_rational(b, r, true, Ordering::is_le),
+                        [a, b] => Ok(Value::Boolean(a.compare(b)?.is_le()).into()),
+                    ),
                     GreaterThanEqual => eval_function!(
-                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Boolean(a >= b).into()),
+                        [a @ random!(), b @ random_or_int!()] => chance(a, b, Ordering::is_ge),
+                        [a @ Value::Integer(_), b @ random!()] => chance(a, b, Ordering::is_ge),
+                        [a @ random!(), Value::Rational(r)] => chance_rational(a, r, false, Ordering::is_ge),
+                        [Value::Rational(r), b @ random!()] => chance_rational(b, r, true, Ordering::is_ge),
+                        [a, b] => Ok(Value::Boolean(a.compare(b)?.is_ge()).into()),
                     ),
                     Equals => eval_function!(
-                        [Value::Integer(a), Value::Integer(b)] => Ok(Value::Boolean(a == b).into()),
-                        [Value::String(a), Value::String(b)] => Ok(Value::Boolean(a == b).into()),
-                        [Value::Boolean(a), Value::Boolean(b)] => Ok(Value::Boolean(a == b).into()),
+                        [a @ random!(), b @ random_or_int!()] => chance(a, b, Ordering::is_eq),
+                        [a @ Value::Integer(_), b @ random!()] => chance(a, b, Ordering::is_eq),
+                        [a @ random!(), Value::Rational(r)] => chance_rational(a, r, false, Ordering::is_eq),
+                        [Value::Rational(r), b @ random!()] => chance_rational(b, r, true, Ordering::is_eq),
+                        [a, b] => Ok(Value::Boolean(a.compare(b)?.is_eq()).into()),
                     ),
 
                     Not => eval_function!(
-                                [Value::Boolean(b)] => Ok(Value::Boolean(!b).into()),),
+                                [Value::Boolean(b)] => Ok(Value::Boolean(!b).into()),
+                                [Value::Distribution(d)] => Ok(Value::Distribution(d.not()?).into()),),
                     And => lazy_eval!([lhs, rhs] => {
-                        if self.evaluate(lhs)?.try_into().map_err(|_| {Error::with_message("Incorrect type")})? {
+                        if expect_bool(self.evaluate(lhs)?, Some(0))? {
                             self.evaluate(rhs)
                         } else {
                             Ok(Value::Boolean(false).into())
                         }
                     }),
                     Or => lazy_eval!([lhs, rhs] => {
-                        if self.evaluate(lhs)?.try_into().map_err(|_| {Error::with_message("Incorrect type")})? {
+                        if expect_bool(self.evaluate(lhs)?, Some(0))? {
                             Ok(Value::Boolean(true).into())
                         } else {
                             self.evaluate(rhs)
                         }
                     }),
                     If => lazy_eval!([cond, then, else_] => {
-                        if self.evaluate(cond)?.try_into().map_err(|_| {Error::with_message("Incorrect type")})? {
+                        if expect_bool(self.evaluate(cond)?, Some(0))? {
                             self.evaluate(then)
                         } else {
                             self.evaluate(else_)
                         }
                     }),
 
+                    // Errors raised by the first argument are replaced by the fallback
+                    Try => lazy_eval!([expr, fallback] => {
+                        match self.evaluate(expr) {
+                            Ok(value) => Ok(value),
+                            Err(_) => self.evaluate(fallback),
+                        }
+                    }),
+                    Catch => lazy_eval!([expr, handler] => {
+                        match self.evaluate(expr) {
+                            Ok(value) => Ok(value),
+                            Err(e) => match self.evaluate(handler)? {
+                                EvaluatedValue(Value::Function(f)) => {
+                                    let message = EvaluatedValue::from(Value::String(e.kind.to_string())).into();
+                                    self.evaluate_function(&f, &[message])
+                                }
+                                EvaluatedValue(v) => Err(Error::type_mismatch(ValueType::Function, v.value_type(), Some(1))),
+                            },
+                        }
+                    }),
+                    IsError => lazy_eval!([expr] => Ok(Value::Boolean(self.evaluate(expr).is_err()).into())),
+
                     Map => eval_function!(
                         [Value::Function(f), Value::List(l)] => {
                             let mut new_list = Vec::with_capacity(l.len());
@@ -277,7 +945,7 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                         [Value::Function(f), Value::Record(r)] => {
                             let mut new_record = BTreeMap::new();
                             for (k, v) in r {
-                                new_record.insert(k.clone(), self.evaluate_function(f, &[k.clone().into(),v.clone().into()])?);
+                                new_record.insert(k.clone(), self.evaluate_function(f, &[EvaluatedValue::from(Value::String(k.clone())).into(),v.clone().into()])?);
                             }
                             Ok(Value::Record(new_record).into())
                         }
@@ -293,7 +961,7 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                         [Value::Function(f), acc_base, Value::Record(r)] => {
                             let mut acc = EvaluatedValue(acc_base.clone());
                             for (k, v) in r {
-                                acc = self.evaluate_function(f, &[acc.clone().into(), k.clone().into(), v.clone().into()])?;
+                                acc = self.evaluate_function(f, &[acc.clone().into(), EvaluatedValue::from(Value::String(k.clone())).into(), v.clone().into()])?;
                             }
                             Ok(acc)
                         }
@@ -302,7 +970,7 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                         [Value::Function(f), Value::List(l)] => {
                             let mut new_list = Vec::new();
                             for elem in l {
-                                if self.evaluate_function(f, &[elem.clone().into()])?.try_into().map_err(|_| {Error::with_message("Incorrect type")})? {
+                                if expect_bool(self.evaluate_function(f, &[elem.clone().into()])?, None)? {
                                     new_list.push(elem.clone());
                                 }
                             }
@@ -311,11 +979,11 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                         [Value::Function(f), Value::Record(r)] => {
                             let mut new_record = BTreeMap::new();
                             for (k, v) in r {
-                                if self.evaluate_function(f, &[k.clone().into(),v.clone().into()])?.try_into().map_err(|_| {Error::with_message("Incorrect type")})? {
+                                if expect_bool(self.evaluate_function(f, &[EvaluatedValue::from(Value::String(k.clone())).into(),v.clone().into()])?, None)? {
                                     new_record.insert(k.clone(), v.clone());
                                 }
                             }
-                            todo!()
+                            Ok(Value::Record(new_record).into())
                         }
                     ),
                     RecordUpdate => eval_function!(
@@ -326,6 +994,370 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                             }
                             Ok(Value::Record(new_record).into())
                         }
+                    ),
+                    Keys => eval_function!(
+                        [Value::Record(r)] => Ok(Value::List(r.keys().map(|k| Value::String(k.clone()).into()).collect()).into()),
+                    ),
+                    Values => eval_function!(
+                        [Value::Record(r)] => Ok(Value::List(r.values().cloned().collect()).into()),
+                    ),
+                    Entries => eval_function!(
+                        [Value::Record(r)] => Ok(Value::List(
+                            r.iter()
+                                .map(|(k, v)| Value::List(vec![Value::String(k.clone()).into(), v.clone()]).into())
+                                .collect(),
+                        ).into()),
+                    ),
+                    // A record with a single field whose name is computed, `{[key]: value}`
+                    ComputedField => eval_function!(
+                        [Value::String(key), value] => Ok(Value::Record(BTreeMap::from([(key.clone(), value.clone().into())])).into()),
+                    ),
+                    FromEntries => eval_function!(
+                        [Value::List(l)] => {
+                            let mut record = BTreeMap::new();
+                            for entry in l {
+                                match &entry.0 {
+                                    Value::List(pair) => match pair.as_slice() {
+                                        [EvaluatedValue(Value::String(k)), v] => {
+                                            record.insert(k.clone(), v.clone());
+                                        }
+                                        [EvaluatedValue(k), _] => {
+                                            return Err(Error::type_mismatch(ValueType::String, k.value_type(), None));
+                                        }
+                                        _ => {
+                                            return Err(Error::new(ErrorKind::Arity { expected: vec![2], actual: pair.len() }));
+                                        }
+                                    },
+                                    v => return Err(Error::type_mismatch(ValueType::List, v.value_type(), None)),
+                                }
+                            }
+                            Ok(Value::Record(record).into())
+                        },
+                    ),
+
+                    // Integers are dice that always roll the same number
+                    Average => eval_function!(
+                        [Value::Dice(d)] => Ok(Value::Integer(d.average()?).into()),
+                        [Value::Integer(n)] => Ok(Value::Integer(*n).into()),
+                    ),
+                    MinRoll => eval_function!(
+                        [Value::Dice(d)] => Ok(Value::Integer(d.min_roll()?).into()),
+                        [Value::Integer(n)] => Ok(Value::Integer(*n).into()),
+                    ),
+                    MaxRoll => eval_function!(
+                        [Value::Dice(d)] => Ok(Value::Integer(d.max_roll()?).into()),
+                        [Value::Integer(n)] => Ok(Value::Integer(*n).into()),
+                    ),
+                    Roll => eval_function!(
+                        [Value::Dice(d), Value::Integer(seed)] => Ok(Value::Integer(d.roll(*seed)?.total).into()),
+                        [Value::Integer(n), Value::Integer(_)] => Ok(Value::Integer(*n).into()),
+                        // Without a seed the roll is logged, and kept until the cell is edited or rerolled
+                        [Value::Dice(d)] => {
+                            let seed = self.ctx.roll_seed();
+                            let roll = d.roll(seed)?;
+                            self.ctx.log_roll(d.to_string(), seed, roll.dice, roll.total);
+                            Ok(Value::Integer(roll.total).into())
+                        },
+                        [Value::Integer(n)] => Ok(Value::Integer(*n).into()),
+                    ),
+                    Rolls => eval_function!([] => {
+                        let rolls = self.ctx.read_roll_log().iter().map(|entry| {
+                            let fields = BTreeMap::from([
+                                ("cell".to_string(), Value::String(entry.cell.name().to_string()).into()),
+                                ("dice".to_string(), Value::List(entry.dice.iter().map(|d| Value::Integer(*d).into()).collect()).into()),
+                                ("formula".to_string(), Value::String(entry.formula.clone()).into()),
+                                ("seed".to_string(), Value::Integer(entry.seed).into()),
+                                ("time".to_string(), Value::Integer(entry.time as i64).into()),
+                                ("total".to_string(), Value::Integer(entry.total).into()),
+                            ]);
+                            Value::Record(fields).into()
+                        });
+                        Ok(Value::List(rolls.collect()).into())
+                    }),
+
+                    ToDistribution => eval_function!(
+                        [a @ random_or_int!()] => Ok(Value::Distribution(to_distribution(a)?).into()),
+                    ),
+                    Probability => eval_function!(
+                        [Value::Distribution(d)] => Ok(Value::Rational(d.probability()?).into()),
+                        [Value::Boolean(b)] => Ok(Value::Rational(Rational::from(*b as i64)).into()),
+                    ),
+                    Expected => eval_function!(
+                        [Value::Dice(d)] => Ok(Value::Rational(d.mean()?).into()),
+                        [Value::Distribution(d)] => Ok(Value::Rational(d.mean()?).into()),
+                        [a @ rational_or_int!()] => Ok(Value::Rational(to_rational(a)?).into()),
+                    ),
+                    Advantage => eval_function!(
+                        [a @ random!()] => Ok(Value::Distribution(to_distribution(a)?.advantage()?).into()),
+                    ),
+                    Disadvantage => eval_function!(
+                        [a @ random!()] => Ok(Value::Distribution(to_distribution(a)?.disadvantage()?).into()),
+                    ),
+                    AttackChances => eval_function!(
+                        [roll @ random!(), Value::Integer(bonus), Value::Integer(ac)] => attack_chances(roll, *bonus, *ac, 20),
+                        [roll @ random!(), Value::Integer(bonus), Value::Integer(ac), Value::Integer(crit_range)] => {
+                            attack_chances(roll, *bonus, *ac, *crit_range)
+                        },
+                    ),
+                    Has => eval_function!(
+                        [Value::Record(r), Value::String(field)] => Ok(Value::Boolean(r.contains_key(field)).into()),
+                    ),
+                    Remove => eval_function!(
+                        [Value::Record(r), Value::String(field)] => {
+                            let mut new_record = r.clone();
+                            new_record.remove(field);
+                            Ok(Value::Record(new_record).into())
+                        },
+                    ),
+                    // The default is only evaluated if it is needed
+                    GetOr => lazy_eval!([record, field, default] => {
+                        let record = self.evaluate(record)?;
+                        let field = self.evaluate(field)?;
+                        match (&record.0, &field.0) {
+                            (Value::Record(r), Value::String(field)) => match r.get(field) {
+                                Some(value) => Ok(value.clone()),
+                                None => self.evaluate(default),
+                            },
+                            (Value::Unit, Value::String(_)) => self.evaluate(default),
+                            (Value::Record(_) | Value::Unit, v) => {
+                                Err(Error::type_mismatch(ValueType::String, v.value_type(), Some(1)))
+                            }
+                            (v, _) => Err(Error::new(ErrorKind::TypeMismatch {
+                                expected: vec![ValueType::Record, ValueType::Unit],
+                                actual: v.value_type(),
+                                position: Some(0),
+                            })),
+                        }
+                    }),
+                    OptionalField => eval_function!(
+                        [Value::Record(r), Value::String(field)] => {
+                            Ok(r.get(field).cloned().unwrap_or_else(|| Value::Unit.into()))
+                        },
+                        [Value::Unit, Value::String(_)] => Ok(Value::Unit.into()),
+                    ),
+                    // Only a field read directly on the left falls back when it is missing, like the type
+                    // checker assumes, so misspelled fields inside other expressions are still errors
+                    Coalesce => lazy_eval!([lhs, rhs] => {
+                        let value = match &lhs.expr {
+                            Expr::FieldAccess(record, field) => match self.evaluate(record)? {
+                                EvaluatedValue(Value::Record(r)) => r.get(field).cloned(),
+                                EvaluatedValue(v) => {
+                                    return Err(Error::type_mismatch(ValueType::Record, v.value_type(), None).or_span(lhs.span))
+                                }
+                            },
+                            _ => Some(self.evaluate(lhs)?),
+                        };
+                        match value {
+                            None | Some(EvaluatedValue(Value::Unit)) => self.evaluate(rhs),
+                            Some(value) => Ok(value),
+                        }
+                    }),
+                    DeepMerge => eval_function!(
+                        [Value::Record(left), Value::Record(right)] => Ok(Value::Record(deep_merge(left, right)).into()),
+                    ),
+
+                    // A record of the tags by name, so that reading a misspelled tag from it is an error
+                    Enum => eval_function!(
+                        [Value::List(tags)] => {
+                            let mut record = BTreeMap::new();
+                            for tag in tags {
+                                match &tag.0 {
+                                    Value::Tag(name, _) => record.insert(name.clone(), tag.clone()),
+                                    v => return Err(Error::type_mismatch(ValueType::Tag, v.value_type(), None)),
+                                };
+                            }
+                            Ok(Value::Record(record).into())
+                        },
+                    ),
+
+                    Length => eval_function!(
+                        [Value::List(l)] => Ok(Value::Integer(l.len() as i64).into()),
+                        [Value::Record(r)] => Ok(Value::Integer(r.len() as i64).into()),
+                        [Value::String(s)] => Ok(Value::Integer(s.chars().count() as i64).into()),
+                    ),
+                    Sum => eval_function!(
+                        [Value::List(l)] => match l.split_first() {
+                            Some((EvaluatedValue(Value::Quantity(first)), rest)) => sum_quantities(first, rest),
+                            _ => fold_numbers(l, 0, i64::checked_add, Rational::add),
+                        },
+                    ),
+                    Product => eval_function!(
+                        [Value::List(l)] => fold_numbers(l, 1, i64::checked_mul, Rational::mul),
+                    ),
+                    Sort => eval_function!(
+                        [Value::List(l)] => {
+                            Ok(Value::List(try_sort(l.clone(), &mut |a: &EvaluatedValue, b: &EvaluatedValue| a.0.compare(&b.0))?).into())
+                        },
+                    ),
+                    SortBy => eval_function!(
+                        [Value::Function(f), Value::List(l)] => {
+                            // Compute each key once rather than on every comparison
+                            let mut keyed = Vec::with_capacity(l.len());
+                            for elem in l {
+                                keyed.push((self.evaluate_function(f, &[elem.clone().into()])?, elem.clone()));
+                            }
+                            let sorted = try_sort(keyed, &mut |(a, _): &(EvaluatedValue, EvaluatedValue), (b, _): &(EvaluatedValue, EvaluatedValue)| a.0.compare(&b.0))?;
+                            Ok(Value::List(sorted.into_iter().map(|(_, elem)| elem).collect()).into())
+                        },
+                    ),
+                    Reverse => eval_function!(
+                        [Value::List(l)] => Ok(Value::List(l.iter().rev().cloned().collect()).into()),
+                    ),
+                    Zip => eval_function!(
+                        [Value::List(a), Value::List(b)] => Ok(Value::List(
+                            a.iter()
+                                .zip(b)
+                                .map(|(a, b)| Value::List(vec![a.clone(), b.clone()]).into())
+                                .collect(),
+                        ).into()),
+                    ),
+                    Range => eval_function!(
+                        [Value::Integer(end)] => range(0, *end),
+                        [Value::Integer(start), Value::Integer(end)] => range(*start, *end),
+                    ),
+                    Contains => eval_function!(
+                        [Value::String(s), Value::String(sub)] => Ok(Value::Boolean(s.contains(sub.as_str())).into()),
+                        [Value::List(l), item] => {
+                            for elem in l {
+                                if elem.0.compare(item)?.is_eq() {
+                                    return Ok(Value::Boolean(true).into());
+                                }
+                            }
+                            Ok(Value::Boolean(false).into())
+                        },
+                    ),
+                    Find => eval_function!(
+                        [Value::Function(f), Value::List(l)] => {
+                            for elem in l {
+                                if expect_bool(self.evaluate_function(f, &[elem.clone().into()])?, None)? {
+                                    return Ok(elem.clone());
+                                }
+                            }
+                            Ok(Value::Unit.into())
+                        },
+                    ),
+                    Any => eval_function!(
+                        [Value::Function(f), Value::List(l)] => {
+                            for elem in l {
+                                if expect_bool(self.evaluate_function(f, &[elem.clone().into()])?, None)? {
+                                    return Ok(Value::Boolean(true).into());
+                                }
+                            }
+                            Ok(Value::Boolean(false).into())
+                        },
+                    ),
+                    All => eval_function!(
+                        [Value::Function(f), Value::List(l)] => {
+                            for elem in l {
+                                if !expect_bool(self.evaluate_function(f, &[elem.clone().into()])?, None)? {
+                                    return Ok(Value::Boolean(false).into());
+                                }
+                            }
+                            Ok(Value::Boolean(true).into())
+                        },
+                    ),
+                    Flatten => eval_function!(
+                        [Value::List(l)] => {
+                            let mut flattened = Vec::new();
+                            for elem in l {
+                                match &elem.0 {
+                                    Value::List(inner) => flattened.extend(inner.iter().cloned()),
+                                    v => return Err(Error::type_mismatch(ValueType::List, v.value_type(), None)),
+                                }
+                            }
+                            Ok(Value::List(flattened).into())
+                        },
+                    ),
+                    Take => eval_function!(
+                        [Value::List(l), Value::Integer(n)] => Ok(Value::List(l[..clamp_count(*n, l.len())].to_vec()).into()),
+                    ),
+                    Drop => eval_function!(
+                        [Value::List(l), Value::Integer(n)] => Ok(Value::List(l[clamp_count(*n, l.len())..].to_vec()).into()),
+                    ),
+                    Unique => eval_function!(
+                        [Value::List(l)] => {
+                            let mut unique: Vec<EvaluatedValue> = Vec::new();
+                            for elem in l {
+                                let mut seen = false;
+                                for u in &unique {
+                                    if u.0.compare(&elem.0)?.is_eq() {
+                                        seen = true;
+                                        break;
+                                    }
+                                }
+                                if !seen {
+                                    unique.push(elem.clone());
+                                }
+                            }
+                            Ok(Value::List(unique).into())
+                        },
+                    ),
+                    Upper => eval_function!(
+                        [Value::String(s)] => Ok(Value::String(s.to_uppercase()).into()),
+                    ),
+                    Lower => eval_function!(
+                        [Value::String(s)] => Ok(Value::String(s.to_lowercase()).into()),
+                    ),
+                    Split => eval_function!(
+                        [Value::String(s), Value::String(sep)] => {
+                            // An empty separator splits the string into its characters
+                            let parts: Vec<EvaluatedValue> = if sep.is_empty() {
+                                s.chars().map(|c| Value::String(c.to_string()).into()).collect()
+                            } else {
+                                s.split(sep.as_str()).map(|part| Value::String(part.to_string()).into()).collect()
+                            };
+                            Ok(Value::List(parts).into())
+                        },
+                    ),
+                    Join => eval_function!(
+                        [Value::List(l), Value::String(sep)] => {
+                            let mut parts = Vec::with_capacity(l.len());
+                            for elem in l {
+                                match &elem.0 {
+                                    Value::String(s) => parts.push(s.as_str()),
+                                    v => return Err(Error::type_mismatch(ValueType::String, v.value_type(), None)),
+                                }
+                            }
+                            Ok(Value::String(parts.join(sep)).into())
+                        },
+                    ),
+                    Trim => eval_function!(
+                        [Value::String(s)] => Ok(Value::String(s.trim().to_string()).into()),
+                    ),
+                    StartsWith => eval_function!(
+                        [Value::String(s), Value::String(prefix)] => Ok(Value::Boolean(s.starts_with(prefix.as_str())).into()),
+                    ),
+                    Replace => eval_function!(
+                        [Value::String(s), Value::String(from), Value::String(to)] => {
+                            Ok(Value::String(s.replace(from.as_str(), to)).into())
+                        },
+                    ),
+                    ToString => eval_function!(
+                        [Value::String(s)] => Ok(Value::String(s.clone()).into()),
+                        [v] => Ok(Value::String(v.to_s_expr()).into()),
+                    ),
+                    ParseInt => eval_function!(
+                        [Value::String(s)] => {
+                            let n = s.trim().parse().map_err(|_| Error::new(ErrorKind::InvalidInteger(s.clone())))?;
+                            Ok(Value::Integer(n).into())
+                        },
+                    ),
+                    GroupBy => eval_function!(
+                        [Value::Function(f), Value::List(l)] => {
+                            let mut groups: BTreeMap<String, Vec<EvaluatedValue>> = BTreeMap::new();
+                            for elem in l {
+                                match self.evaluate_function(f, &[elem.clone().into()])?.0 {
+                                    Value::String(key) => groups.entry(key).or_default().push(elem.clone()),
+                                    v => return Err(Error::type_mismatch(ValueType::String, v.value_type(), None)),
+                                }
+                            }
+                            Ok(Value::Record(
+                                groups
+                                    .into_iter()
+                                    .map(|(k, v)| (k, Value::List(v).into()))
+                                    .collect(),
+                            ).into())
+                        },
                     )
                 }
             }
@@ -348,30 +1380,43 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                     .map(|ast| self.evaluate(ast))
                     .collect::<Result<_, _>>()?,
             ))),
+            Value::Tag(tag, payload) => Ok(EvaluatedValue(Value::Tag(
+                tag.clone(),
+                payload.as_ref().map(|p| self.evaluate(p)).transpose()?.map(Box::new),
+            ))),
+            Value::Dice(dice) => Ok(EvaluatedValue(Value::Dice(dice.clone()))),
+            Value::Distribution(distribution) => Ok(EvaluatedValue(Value::Distribution(distribution.clone()))),
+            Value::Rational(r) => Ok(EvaluatedValue(Value::Rational(*r))),
+            Value::Quantity(q) => Ok(EvaluatedValue(Value::Quantity(q.clone()))),
             Value::Function(Function::Builtin(name)) => Ok(EvaluatedValue(Value::Function(
-                Function::Builtin(name.clone()),
+                Function::Builtin(*name),
             ))),
             Value::Function(Function::Lambda(params, body)) => {
-                Ok(EvaluatedValue(Value::Function(Function::Lambda(
-                    params.clone(),
-                    Box::new(self.capture_values(
-                        &mut {
-                            let mut locals = Scope::new();
-                            for param in params.iter() {
-                                locals.insert(param.clone(), ());
-                            }
-                            locals
-                        },
-                        body,
-                    )),
-                ))))
+                Ok(EvaluatedValue(Value::Function(self.capture_lambda(&Scope::new(), params, body))))
             }
         }
     }
 
+    /// Captures the local variables used by a lambda's body and defaults, see [`Self::capture_values`].
+    /// Each default can use the parameters before it.
+    fn capture_lambda(&self, local_scope: &Scope<()>, params: &[Param], body: &AST) -> Function {
+        let mut inner_scope = Scope::new_with_parent(local_scope);
+        let params = params
+            .iter()
+            .map(|param| {
+                let default = param.default.as_ref().map(|d| self.capture_values(&mut inner_scope, d));
+                for name in param.pattern.bound_names() {
+                    inner_scope.insert(name.to_string(), ());
+                }
+                Param::new(param.pattern.clone(), default)
+            })
+            .collect();
+        Function::Lambda(params, Box::new(self.capture_values(&mut inner_scope, body)))
+    }
+
     fn capture_values(&self, local_scope: &mut Scope<()>, ast: &AST) -> AST {
-        match ast {
-            AST::Literal(value) => AST::Literal(match value {
+        let expr = match &ast.expr {
+            Expr::Literal(value) => Expr::Literal(match value {
                 Value::Record(fields) => Value::Record(
                     fields
                         .iter()
@@ -384,50 +1429,80 @@ impl<'inner, 'outer> InterpreterCtx<'inner, 'outer> {
                         .map(|i| self.capture_values(local_scope, i))
                         .collect(),
                 ),
-                Value::Function(Function::Lambda(args, ast)) => {
-                    Value::Function(Function::Lambda(args.clone(), {
-                        let mut inner_scope = Scope::new_with_parent(local_scope);
-                        for arg in args {
-                            inner_scope.insert(arg.clone(), ());
-                        }
-                        Box::new(self.capture_values(&mut inner_scope, ast))
-                    }))
+                Value::Tag(tag, payload) => Value::Tag(
+                    tag.clone(),
+                    payload.as_ref().map(|p| Box::new(self.capture_values(local_scope, p))),
+                ),
+                Value::Function(Function::Lambda(params, body)) => {
+                    Value::Function(self.capture_lambda(local_scope, params, body))
                 }
                 value => value.clone(),
             }),
-            AST::Name(name) => {
-                if let Some(value) = self.local_vars.lookup(name) {
-                    value.into()
+            Expr::Name(name) => {
+                // Names bound inside the captured expression shadow the outer scope
+                if local_scope.lookup(name).is_none()
+                    && let Some(value) = self.local_vars.lookup(name)
+                {
+                    return AST::from(value).at(ast.span);
                 } else {
-                    ast.clone()
+                    return ast.clone();
                 }
             }
-            AST::Function(function, args) => AST::Function(
+            Expr::Function(function, args) => Expr::Function(
                 Box::new(self.capture_values(local_scope, function)),
                 args.iter()
                     .map(|a| self.capture_values(local_scope, a))
                     .collect(),
             ),
-            AST::FieldAccess(ast, field) => AST::FieldAccess(
+            Expr::NamedCall(function, args, named) => Expr::NamedCall(
+                Box::new(self.capture_values(local_scope, function)),
+                args.iter()
+                    .map(|a| self.capture_values(local_scope, a))
+                    .collect(),
+                named
+                    .iter()
+                    .map(|(name, a)| (name.clone(), self.capture_values(local_scope, a)))
+                    .collect(),
+            ),
+            Expr::FieldAccess(ast, field) => Expr::FieldAccess(
                 Box::new(self.capture_values(local_scope, ast)),
                 field.clone(),
             ),
-            AST::Let(bindings, ast) => {
+            Expr::Let(bindings, ast) => {
                 let mut inner_scope = Scope::new_with_parent(local_scope);
                 let new_bindings = bindings
                     .iter()
-                    .map(|Binding(name, expr)| {
+                    .map(|Binding(pattern, expr)| {
                         let new_expr = self.capture_values(&mut inner_scope, expr);
-                        inner_scope.insert(name.clone(), ());
-                        Binding(name.clone(), new_expr)
+                        for name in pattern.bound_names() {
+                            inner_scope.insert(name.to_string(), ());
+                        }
+                        Binding(pattern.clone(), new_expr)
                     })
                     .collect();
-                AST::Let(
+                Expr::Let(
                     new_bindings,
                     Box::new(self.capture_values(&mut inner_scope, ast)),
                 )
             }
-        }
+            Expr::Match(value, arms) => Expr::Match(
+                Box::new(self.capture_values(local_scope, value)),
+                arms.iter()
+                    .map(|arm| {
+                        let mut inner_scope = Scope::new_with_parent(local_scope);
+                        for name in arm.pattern.bound_names() {
+                            inner_scope.insert(name.to_string(), ());
+                        }
+                        MatchArm {
+                            pattern: arm.pattern.clone(),
+                            guard: arm.guard.as_ref().map(|g| self.capture_values(&mut inner_scope, g)),
+                            body: self.capture_values(&mut inner_scope, &arm.body),
+                        }
+                    })
+                    .collect(),
+            ),
+        };
+        AST::new(expr, ast.span)
     }
 }
 
@@ -436,6 +1511,8 @@ impl IntermediateRep for AST {
 
     type Error = Error;
 
+    type Type = Type;
+
     fn parse(text: &str) -> Result<Self, Self::Error> {
         parse(text)
     }
@@ -448,11 +1525,585 @@ impl IntermediateRep for AST {
     /// The function returns a Result containing the evaluated value, or an error message if the evaluation failed.
     ///
     /// The function is used internally by the sheet to evaluate the contents of cells.
-    fn evaluate<'a>(&self, mut ctx: ReactiveContext<'a, Self>) -> Result<Self::Value, Self::Error> {
-        InterpreterCtx::new(&mut ctx).evaluate(self)
+    fn evaluate(&self, mut ctx: ReactiveContext<'_, Self>) -> Result<Self::Value, Self::Error> {
+        let source = ctx.source();
+        InterpreterCtx::new(&mut ctx)
+            .evaluate(self)
+            .map_err(|e| e.with_source(source))
+    }
+
+    /// Infers the type of an AST, reporting type errors without evaluating it.
+    fn check(&self, ctx: &mut ReactiveContext<'_, Self>) -> Result<Self::Type, Self::Error> {
+        let source = ctx.source();
+        check(self, ctx).map_err(|e| e.with_source(source))
+    }
+
+    fn make_cycle_error(cell_name: &str) -> Self::Error {
+        Error::new(ErrorKind::Cycle(cell_name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::errors::Span;
+    use crate::language::units::Dimension;
+    use crate::reactive::sheet::Sheet;
+
+    fn eval(text: &str) -> Result<EvaluatedValue, Error> {
+        let mut sheet = Sheet::<AST>::new();
+        let id = sheet.add_cell("test".to_string(), text).unwrap();
+        sheet.get_cell_value(&id).unwrap().clone()
+    }
+
+    macro_rules! test_eval_success {
+        ($test_name:ident, $input:expr, $expected:expr) => {
+            #[test]
+            fn $test_name() {
+                assert_eq!(eval($input).unwrap().to_s_expr(), $expected);
+            }
+        };
+    }
+
+    macro_rules! test_eval_error_span {
+        ($test_name:ident, $input:expr, $start:expr, $end:expr) => {
+            #[test]
+            fn $test_name() {
+                assert_eq!(eval($input).unwrap_err().span, Some(Span::new($start, $end)));
+            }
+        };
+    }
+
+    macro_rules! test_eval_error {
+        ($test_name:ident, $input:expr, $kind:pat) => {
+            #[test]
+            fn $test_name() {
+                let err = eval($input).unwrap_err();
+                assert!(matches!(err.kind, $kind), "unexpected error {:?}", err.kind);
+            }
+        };
+    }
+
+    test_eval_success!(test_arith, "(10 + 5) * 2", "30");
+    test_eval_success!(test_let_lambda, "let double = fn (x) -> x * 2 in double(10)", "20");
+
+    test_eval_success!(test_div, "7 / 2", "3");
+    test_eval_success!(test_div_negative, "-7 / 2", "-4");
+    test_eval_success!(test_div_negative_divisor, "7 / -2", "-4");
+    test_eval_success!(test_div_exact, "-8 / 2", "-4");
+    test_eval_success!(test_mod, "7 % 3", "1");
+    test_eval_success!(test_mod_negative, "-7 % 3", "2");
+    test_eval_success!(test_mod_negative_divisor, "7 % -3", "-2");
+    test_eval_success!(test_ability_modifier, "map(fn (score) -> (score - 10) / 2, [1, 8, 9, 10, 15, 20])", "[-5, -1, -1, 0, 2, 5]");
+    test_eval_success!(test_div_ceil, "[div_ceil(7, 2), div_ceil(-7, 2), div_ceil(6, 2)]", "[4, -3, 3]");
+    test_eval_success!(test_round_half_up, "[round_half_up(5, 2), round_half_up(-5, 2), round_half_up(7, 3), round_half_up(8, 3)]", "[3, -2, 2, 3]");
+    test_eval_success!(test_min_max, "[min(3, 5), max(3, 5)]", "[3, 5]");
+    test_eval_success!(test_clamp, "[clamp(25, 1, 20), clamp(-3, 1, 20), clamp(7, 1, 20)]", "[20, 1, 7]");
+    test_eval_success!(test_abs, "[abs(-3), abs(3)]", "[3, 3]");
+
+    test_eval_error!(test_div_zero, "1 / 0", ErrorKind::DivisionByZero);
+    test_eval_error!(test_mod_zero, "1 % 0", ErrorKind::DivisionByZero);
+    test_eval_error!(test_div_ceil_zero, "div_ceil(1, 0)", ErrorKind::DivisionByZero);
+
+    test_eval_success!(test_eq_list, "[[1, 2] == [1, 2], [1, 2] == [1, 3], [1] == [1, 2]]", "[true, false, false]");
+    test_eval_success!(test_eq_record, "[{a: 1} == {a: 1}, {a: 1} == {a: 2}, {a: 1} == {b: 1}]", "[true, false, false]");
+    test_eval_success!(test_eq_nested, "{a: [1, {b: \"x\"}]} == {a: [1, {b: \"x\"}]}", "true");
+    test_eval_success!(test_eq_different_types, "[1 == \"1\", [] == {}, true != 1]", "[false, false, true]");
+    test_eval_success!(test_lt_string, "[\"a\" < \"b\", \"b\" <= \"a\", \"abc\" > \"ab\"]", "[true, false, true]");
+    test_eval_success!(test_lt_list, "[[1] < [1, 0], [1, 0] < [2], [2] >= [1, 5]]", "[true, true, true]");
+    test_eval_success!(test_lt_record, "[{a: 1} < {a: 2}, {a: 5} < {b: 1}, {a: 1} < {a: 1, b: 1}]", "[true, true, true]");
+    test_eval_success!(test_lt_bool, "false < true", "true");
+    test_eval_success!(test_type_order, "[false < 0, 0 < \"\", \"\" < [], [] < {}]", "[true, true, true, true]");
+    test_eval_success!(test_compare_decided_before_function, "[1, fn (x) -> x] < [2, fn (x) -> x]", "true");
+    test_eval_error!(
+        test_compare_function,
+        "(fn (x) -> x) == (fn (x) -> x)",
+        ErrorKind::TypeMismatch { actual: ValueType::Function, .. }
+    );
+
+    test_eval_success!(test_length, "[length([]), length([1, 2, 3])]", "[0, 3]");
+    test_eval_success!(test_sum_product, "[sum([1, 2, 3]), sum([]), product([2, 3, 4]), product([])]", "[6, 0, 24, 1]");
+    test_eval_success!(test_min_max_list, "[min([3, 1, 2]), max([\"b\", \"c\", \"a\"])]", "[1, \"c\"]");
+    test_eval_success!(test_sort, "sort([3, 1, 2, 1])", "[1, 1, 2, 3]");
+    test_eval_success!(test_sort_strings, "sort([\"wizard\", \"bard\", \"cleric\"])", "[\"bard\", \"cleric\", \"wizard\"]");
+    test_eval_success!(
+        test_sort_by_stable,
+        "sort_by(fn (x) -> x.w, [{n: 1, w: 2}, {n: 2, w: 1}, {n: 3, w: 2}, {n: 4, w: 1}])",
+        "[{n: 2, w: 1}, {n: 4, w: 1}, {n: 1, w: 2}, {n: 3, w: 2}]"
+    );
+    test_eval_success!(test_reverse, "reverse([1, 2, 3])", "[3, 2, 1]");
+    test_eval_success!(test_zip, "zip([1, 2, 3], [\"a\", \"b\"])", "[[1, \"a\"], [2, \"b\"]]");
+    test_eval_success!(test_range, "[range(3), range(2, 5), range(-1)]", "[[0, 1, 2], [2, 3, 4], []]");
+    test_eval_error!(test_range_too_long, "range(1000000000000)", ErrorKind::RangeTooLong { len: 1000000000000, .. });
+    test_eval_error!(test_range_too_long_operator, "[x for x in -9223372036854775807..9223372036854775807]", ErrorKind::RangeTooLong { .. });
+    test_eval_success!(test_contains, "[contains([1, [2]], [2]), contains([1, 2], 3)]", "[true, false]");
+    test_eval_success!(test_find, "[find(fn (x) -> x > 1, [1, 2, 3]), find(fn (x) -> x > 5, [1])]", "[2, ()]");
+    test_eval_success!(test_any_all, "[any(fn (x) -> x > 2, [1, 3]), all(fn (x) -> x > 2, [1, 3]), any(fn (x) -> x, []), all(fn (x) -> x, [])]", "[true, false, false, true]");
+    test_eval_success!(test_flatten, "flatten([[1], [], [2, [3]]])", "[1, 2, [3]]");
+    test_eval_success!(test_take_drop, "[take([1, 2, 3], 2), drop([1, 2, 3], 2), take([1], 5), drop([1], -1)]", "[[1, 2], [3], [1], [1]]");
+    test_eval_success!(test_unique, "unique([1, 2, 1, [3], [3]])", "[1, 2, [3]]");
+    test_eval_success!(
+        test_group_by,
+        "group_by(fn (i) -> i.kind, [{kind: \"weapon\", n: 1}, {kind: \"armor\", n: 2}, {kind: \"weapon\", n: 3}])",
+        "{armor: [{kind: \"armor\", n: 2}], weapon: [{kind: \"weapon\", n: 1}, {kind: \"weapon\", n: 3}]}"
+    );
+    test_eval_success!(test_count_proficiencies, "length(filter(fn (p) -> p, [true, false, true]))", "2");
+
+    test_eval_success!(test_map_record, "map(fn (k, v) -> k + \"!\", {a: 1})", "{a: \"a!\"}");
+    test_eval_success!(test_fold_record, "fold(fn (acc, k, v) -> acc + k, \"\", {a: 1, b: 2})", "\"ab\"");
+    test_eval_success!(test_filter_record, "filter(fn (k, v) -> v > 1, {a: 1, b: 2})", "{b: 2}");
+    test_eval_success!(test_keys_values, "[keys({b: 1, a: 2}), values({b: 1, a: 2})]", "[[\"a\", \"b\"], [2, 1]]");
+    test_eval_success!(test_entries, "entries({str: 15, dex: 12})", "[[\"dex\", 12], [\"str\", 15]]");
+    test_eval_success!(test_from_entries, "from_entries([[\"str\", 15], [\"dex\", 12], [\"str\", 16]])", "{dex: 12, str: 16}");
+    test_eval_success!(test_entries_round_trip, "let r = {a: 1, b: [2]} in from_entries(entries(r)) == r", "true");
+    test_eval_success!(test_has, "[has({a: 1}, \"a\"), has({a: 1}, \"b\")]", "[true, false]");
+    test_eval_success!(test_remove, "[remove({a: 1, b: 2}, \"a\"), remove({a: 1}, \"b\")]", "[{b: 2}, {a: 1}]");
+    test_eval_success!(test_get_or, "[get_or({a: 1}, \"a\", 0), get_or({a: 1}, \"b\", 0)]", "[1, 0]");
+    test_eval_success!(test_length_record, "length({a: 1, b: 2})", "2");
+    test_eval_success!(
+        test_deep_merge,
+        "deep_merge({stats: {str: 10, dex: 12}, name: \"a\"}, {stats: {str: 14}, name: \"b\"})",
+        "{name: \"b\", stats: {dex: 12, str: 14}}"
+    );
+    test_eval_success!(
+        test_deep_merge_replaces_non_records,
+        "deep_merge({a: {b: 1}, c: [1]}, {a: 2, c: {d: 1}})",
+        "{a: 2, c: {d: 1}}"
+    );
+    test_eval_success!(test_shallow_update, "{stats: {str: 10, dex: 12}} // {stats: {str: 14}}", "{stats: {str: 14}}");
+
+    test_eval_error!(test_from_entries_bad_key, "from_entries([[1, 2]])", ErrorKind::TypeMismatch { actual: ValueType::Integer, .. });
+    test_eval_error!(test_from_entries_bad_pair, "from_entries([[\"a\"]])", ErrorKind::Arity { .. });
+    test_eval_success!(test_string_length, "[length(\"abc\"), length(\"\u{1F409}\")]", "[3, 1]");
+    test_eval_success!(test_upper_lower, "[upper(\"Fire Bolt\"), lower(\"Fire Bolt\")]", "[\"FIRE BOLT\", \"fire bolt\"]");
+    test_eval_success!(test_split, "[split(\"a, b, c\", \", \"), split(\"ab\", \"\")]", "[[\"a\", \"b\", \"c\"], [\"a\", \"b\"]]");
+    test_eval_success!(test_join, "join([\"a\", \"b\", \"c\"], \"-\")", "\"a-b-c\"");
+    test_eval_success!(test_trim, "trim(\"  a b \\n\")", "\"a b\"");
+    test_eval_success!(test_string_contains, "[contains(\"Longsword\", \"sword\"), contains(\"Longsword\", \"bow\")]", "[true, false]");
+    test_eval_success!(test_starts_with, "[starts_with(\"Longsword\", \"Long\"), starts_with(\"Longsword\", \"sword\")]", "[true, false]");
+    test_eval_success!(test_replace, "replace(\"1d8 + 1d8\", \"d8\", \"d10\")", "\"1d10 + 1d10\"");
+    test_eval_success!(test_to_string, "[to_string(\"a\"), to_string(12), to_string(true), to_string([1, \"a\"])]", "[\"a\", \"12\", \"true\", \"[1, \\\"a\\\"]\"]");
+    test_eval_success!(test_parse_int, "[parse_int(\"42\"), parse_int(\" -3 \")]", "[42, -3]");
+    test_eval_success!(test_fstring, "let ac = 16; armor = {name: \"Chain Mail\"} in f\"AC {ac} with {armor.name}\"", "\"AC 16 with Chain Mail\"");
+    test_eval_success!(test_fstring_braces, "f\"{{{1 + 1}}}\"", "\"{2}\"");
+
+    test_eval_error!(test_parse_int_invalid, "parse_int(\"abc\")", ErrorKind::InvalidInteger(_));
+    test_eval_error!(test_join_non_string, "join([\"a\", 1], \"\")", ErrorKind::TypeMismatch { actual: ValueType::Integer, .. });
+    test_eval_error_span!(test_fstring_error_span, "f\"a {1 + true} b\"", 5, 13);
+    test_eval_error!(test_min_empty, "min([])", ErrorKind::EmptyList);
+    test_eval_error!(test_sum_overflow, "sum([9223372036854775807, 1])", ErrorKind::Overflow);
+    test_eval_error!(test_sum_non_integer, "sum([1, \"a\"])", ErrorKind::TypeMismatch { actual: ValueType::String, .. });
+    test_eval_error!(test_sort_functions, "sort([fn (x) -> x, fn (x) -> x])", ErrorKind::TypeMismatch { actual: ValueType::Function, .. });
+    test_eval_error!(test_flatten_non_list, "flatten([[1], 2])", ErrorKind::TypeMismatch { actual: ValueType::Integer, .. });
+
+    test_eval_error!(test_add_overflow, "9223372036854775807 + 1", ErrorKind::Overflow);
+    test_eval_error!(test_sub_overflow, "-9223372036854775807 - 2", ErrorKind::Overflow);
+    test_eval_error!(test_mul_overflow, "4611686018427387904 * 2", ErrorKind::Overflow);
+    test_eval_error!(test_negate_overflow, "-(-9223372036854775807 - 1)", ErrorKind::Overflow);
+    test_eval_error!(test_div_overflow, "(-9223372036854775807 - 1) / -1", ErrorKind::Overflow);
+    test_eval_error!(test_mod_overflow, "(-9223372036854775807 - 1) % -1", ErrorKind::Overflow);
+    test_eval_error!(test_abs_overflow, "abs(-9223372036854775807 - 1)", ErrorKind::Overflow);
+    test_eval_error_span!(test_overflow_span, "1 + 9223372036854775807 * 2", 4, 27);
+
+    test_eval_error_span!(test_error_invalid_args, "1 + (2 * true)", 4, 14);
+    test_eval_error_span!(test_error_unknown_name, "1 + foo", 4, 7);
+    test_eval_error_span!(test_error_in_lambda, "map(fn (x) -> x.a, [1])", 14, 17);
+
+    test_eval_error!(test_unknown_name, "foo", ErrorKind::UnknownName(_));
+    test_eval_error!(test_unknown_cell, "$foo", ErrorKind::UnknownCell(_));
+    test_eval_error!(test_missing_field, "{a: 1}.b", ErrorKind::MissingField(_));
+    test_eval_error!(test_index, "index([1, 2], 2)", ErrorKind::Index { index: 2, len: 2 });
+    test_eval_error!(test_lambda_arity, "(fn (x) -> x)(1, 2)", ErrorKind::Arity { .. });
+    test_eval_error!(test_builtin_arity, "map(1, [1], 2)", ErrorKind::Arity { .. });
+    test_eval_error!(
+        test_type_mismatch,
+        "1 + true",
+        ErrorKind::TypeMismatch { actual: ValueType::Boolean, position: Some(1), .. }
+    );
+    test_eval_error!(
+        test_if_condition,
+        "if 1 then 2 else 3",
+        ErrorKind::TypeMismatch { actual: ValueType::Integer, position: Some(0), .. }
+    );
+    test_eval_error!(
+        test_uncallable,
+        "1(2)",
+        ErrorKind::TypeMismatch { actual: ValueType::Integer, position: None, .. }
+    );
+
+    test_eval_success!(test_match_literal, "match 2 with | 1 -> \"one\" | 2 -> \"two\" | _ -> \"many\"", "\"two\"");
+    test_eval_success!(test_match_wildcard, "match 5 with | 1 -> \"one\" | _ -> \"many\"", "\"many\"");
+    test_eval_success!(
+        test_match_record,
+        "let armor = {type: \"heavy\", ac: 16, name: \"Plate\"} in match armor with | {type: \"light\", ac: a} -> a + 3 | {type: \"heavy\", ac: a} -> a",
+        "16"
+    );
+    test_eval_success!(test_match_record_pun, "match {ac: 12} with | {ac} -> ac", "12");
+    test_eval_success!(test_match_list_rest, "match [1, 2, 3] with | [] -> [] | [first, ..rest] -> [first, rest]", "[1, [2, 3]]");
+    test_eval_success!(test_match_list_exact, "match [1, 2, 3] with | [a, b] -> 2 | [a, b, c] -> 3", "3");
+    test_eval_success!(test_match_list_ignore_rest, "match [1, 2, 3] with | [a, ..] -> a", "1");
+    test_eval_success!(test_match_nested, "match {items: [{n: 1}]} with | {items: [{n}]} -> n", "1");
+    test_eval_success!(test_match_guard, "match 5 with | n if n > 10 -> \"big\" | n if n > 3 -> \"medium\" | _ -> \"small\"", "\"medium\"");
+    test_eval_success!(test_match_negative, "match -1 with | -1 -> true | _ -> false", "true");
+    test_eval_success!(test_match_function_value, "match fn (x) -> x with | 1 -> 1 | f -> f(2)", "2");
+    test_eval_success!(test_match_in_lambda, "let a = 5 in map(fn (x) -> match x with | [a] -> a | _ -> a, [[1], 2])", "[1, 5]");
+    test_eval_success!(test_lambda_param_shadows_capture, "let x = 1 in (fn (x) -> x)(2)", "2");
+
+    test_eval_success!(
+        test_let_destructure_record,
+        "let {strength, dexterity: dex} = {strength: 14, dexterity: 12, wisdom: 8} in [strength, dex]",
+        "[14, 12]"
+    );
+    test_eval_success!(test_let_destructure_list, "let [first, ..rest] = [1, 2, 3]; [second, _] = rest in first + second", "3");
+    test_eval_success!(test_lambda_destructure, "map(fn ([a, b]) -> a * b, zip([1, 2], [3, 4]))", "[3, 8]");
+    test_eval_success!(test_lambda_destructure_record, "(fn ({hp}, damage) -> hp - damage)({hp: 20}, 5)", "15");
+    test_eval_success!(test_lambda_destructure_capture, "let a = 1 in let f = fn ({a}) -> a in f({a: 2})", "2");
+
+    test_eval_error!(test_destructure_not_list, "let [a] = 1 in a", ErrorKind::TypeMismatch { expected: _, actual: ValueType::Integer, position: None });
+    test_eval_error!(test_destructure_missing_field, "let {strength} = {dexterity: 1} in strength", ErrorKind::MissingField(_));
+    test_eval_error!(test_destructure_literal, "let [1, a] = [2, 3] in a", ErrorKind::PatternMismatch { .. });
+    test_eval_error!(
+        test_destructure_list_length,
+        "(fn ([a, b]) -> a)([1, 2, 3])",
+        ErrorKind::ListLength { expected: 2, at_least: false, actual: 3 }
+    );
+    test_eval_error_span!(test_destructure_span, "let {a: [x]} = {a: 1} in x", 8, 11);
+    test_eval_error_span!(test_destructure_missing_field_span, "let {a, b} = {a: 1} in b", 8, 9);
+
+    test_eval_success!(
+        test_record_spread,
+        "let base = {strength: 10, dexterity: 14} in {...base, strength: 12}",
+        "{dexterity: 14, strength: 12}"
+    );
+    test_eval_success!(test_record_spread_order, "let base = {a: 1} in [{a: 0, ...base}, {...base, a: 0}]", "[{a: 1}, {a: 0}]");
+    test_eval_success!(test_record_pun, "let strength = 8; dexterity = 12 in {strength, dexterity}", "{dexterity: 12, strength: 8}");
+    test_eval_success!(
+        test_record_computed_key,
+        "let name = \"stealth\" in {[\"skill_\" + name]: 2, base: 1}",
+        "{base: 1, skill_stealth: 2}"
+    );
+    test_eval_success!(test_record_quoted_key, "index({\"sleight of hand\": 3}, \"sleight of hand\")", "3");
+    test_eval_error!(test_record_spread_not_record, "{...[1], a: 1}", ErrorKind::TypeMismatch { actual: ValueType::List, .. });
+    test_eval_error!(test_record_computed_key_not_string, "{[1]: 2}", ErrorKind::TypeMismatch { actual: ValueType::Integer, .. });
+
+    test_eval_success!(test_optional_field, "[{a: 1}?.a, {a: 1}?.b]", "[1, ()]");
+    test_eval_success!(test_optional_field_chain, "[{a: {b: 2}}?.a?.b, {c: 1}?.a?.b]", "[2, ()]");
+    test_eval_success!(test_coalesce_unit, "{a: 1}?.b ?? 5", "5");
+    test_eval_success!(test_coalesce_range, "let r = {hi: 3} in r?.lo ?? 1..=r?.hi ?? 5", "[1, 2, 3]");
+    test_eval_success!(test_coalesce_present, "{b: 1}?.b ?? 5", "1");
+    test_eval_success!(test_coalesce_missing_field, "{a: 1}.b ?? 5", "5");
+    test_eval_success!(test_coalesce_lazy, "1 ?? foo", "1");
+    test_eval_success!(test_coalesce_false_is_kept, "false ?? true", "false");
+    test_eval_success!(test_get_or_lazy, "get_or({a: 1}, \"a\", foo)", "1");
+    test_eval_success!(test_get_or_unit, "get_or({}?.a, \"b\", 3)", "3");
+    test_eval_error!(test_optional_field_not_record, "1?.a", ErrorKind::TypeMismatch { actual: ValueType::Integer, .. });
+    test_eval_error!(test_coalesce_other_errors, "(1 / 0) ?? 5", ErrorKind::DivisionByZero);
+    test_eval_error!(test_coalesce_nested_missing_field, "let f = fn (r) -> r.bonsu in f({bonus: 1}) ?? 0", ErrorKind::MissingField(_));
+    test_eval_error!(test_coalesce_missing_inner_field, "{a: {}}.b.c ?? 0", ErrorKind::MissingField(_));
+    test_eval_error!(test_coalesce_field_of_non_record, "1.b ?? 0", ErrorKind::TypeMismatch { .. });
+    test_eval_error!(test_get_or_not_record, "get_or(1, \"a\", 2)", ErrorKind::TypeMismatch { position: Some(0), .. });
+
+    test_eval_success!(
+        test_pipeline,
+        "[{w: 2, e: true}, {w: 5, e: false}, {w: 3, e: true}] |> filter(fn (i) -> i.e) |> map(fn (i) -> i.w) |> sum",
+        "5"
+    );
+    test_eval_success!(test_pipeline_if, "let f = fn (x) -> x + 1 in 1 |> (if true then f else f)", "2");
+    test_eval_success!(test_pipeline_bracketed_call, "let make = fn (a) -> fn (b) -> a * 10 + b in 3 |> (make(2))", "23");
+    test_eval_success!(test_method_call, "\" a,b \".trim().split(\",\").join(\"-\").upper()", "\"A-B\"");
+    test_eval_success!(test_method_shadowed, "let length = fn (x) -> 0 in [1, 2].length()", "0");
+    test_eval_success!(test_method_record_field, "let r = {f: fn (x) -> x + 1} in (r.f)(1)", "2");
+    test_eval_success!(test_method_let_bound, "let double = fn (x) -> x * 2 in [1, 2].sum().double()", "6");
+    test_eval_success!(test_method_in_lambda, "(fn (l, f) -> l.f())([1, 2], length)", "2");
+    const ATTACK: &str = "let attack = fn (bonus, dice, crit_range = 20, advantage = false) -> [bonus, dice, crit_range, advantage] in ";
+
+    test_eval_success!(test_defaults, &(ATTACK.to_string() + "attack(5, \"1d8\")"), "[5, \"1d8\", 20, false]");
+    test_eval_success!(test_default_positional, &(ATTACK.to_string() + "attack(5, \"1d8\", 19)"), "[5, \"1d8\", 19, false]");
+    test_eval_success!(test_named_arg, &(ATTACK.to_string() + "attack(5, \"1d8\", advantage: true)"), "[5, \"1d8\", 20, true]");
+    test_eval_success!(test_named_required, &(ATTACK.to_string() + "attack(dice: \"1d6\", bonus: 2)"), "[2, \"1d6\", 20, false]");
+    test_eval_success!(test_default_uses_param, "let f = fn (a, b = a * 2) -> b in [f(1), f(1, 5)]", "[2, 5]");
+    test_eval_success!(test_default_captures, "let x = 3 in let f = fn (a = x) -> a in let x = 4 in f()", "3");
+    test_eval_success!(test_default_partial, &(ATTACK.to_string() + "map(attack(1), [\"1d4\"])"), "[[1, \"1d4\", 20, false]]");
+    test_eval_error!(test_default_too_many, &(ATTACK.to_string() + "attack(1, 2, 3, 4, 5)"), ErrorKind::Arity { actual: 5, .. });
+    test_eval_error!(test_named_unknown, &(ATTACK.to_string() + "attack(5, \"1d8\", advantage: true, crit: 19)"), ErrorKind::UnknownArgument { .. });
+    test_eval_error!(test_named_duplicate, &(ATTACK.to_string() + "attack(5, \"1d8\", bonus: 2)"), ErrorKind::DuplicateArgument(_));
+    test_eval_error!(test_named_missing, &(ATTACK.to_string() + "attack(5, advantage: true)"), ErrorKind::MissingArgument(_));
+    test_eval_error!(test_named_builtin, "max(1, b: 2)", ErrorKind::UnknownArgument { .. });
+    test_eval_error_span!(test_named_unknown_span, "(fn (a) -> a)(b: 1)", 17, 18);
+
+    #[test]
+    fn test_named_error_messages() {
+        let err = eval(&(ATTACK.to_string() + "attack(5, \"1d8\", crit: 19)")).unwrap_err();
+        assert_eq!(
+            err.kind.to_string(),
+            "No parameter named crit, expected bonus, dice, crit_range or advantage"
+        );
+        let err = eval(&(ATTACK.to_string() + "attack(5)(1, 2, 3, 4)")).unwrap_err();
+        assert_eq!(err.kind.to_string(), "Expected 1 arguments but found 4");
+        let err = eval(&(ATTACK.to_string() + "attack(1, 2, 3, 4, 5)")).unwrap_err();
+        assert_eq!(err.kind.to_string(), "Expected 2, 3 or 4 arguments but found 5");
+    }
+
+    test_eval_success!(test_tag, "#Some([1, #fire])", "#Some([1, #fire])");
+    test_eval_success!(test_tag_equality, "[#fire == #fire, #fire == #cold, #Some(1) == #Some(1), #Some(1) == #Some]", "[true, false, true, false]");
+    test_eval_success!(test_tag_sort, "sort([#b, #a(1), 3, #a])", "[3, #a, #a(1), #b]");
+    test_eval_success!(test_tag_to_string, "to_string(#Some(\"x\"))", "\"#Some(\\\"x\\\")\"");
+    test_eval_success!(
+        test_tag_match,
+        "map(fn (d) -> match d with | #fire -> 1 | #Resist(#fire) -> 2 | #Resist(_) -> 3 | _ -> 4, [#fire, #Resist(#fire), #Resist(#cold), #cold])",
+        "[1, 2, 3, 4]"
+    );
+    test_eval_success!(test_tag_capture, "let x = 1 in let f = fn () -> #Some(x) in f()", "#Some(1)");
+    test_eval_error!(test_tag_pattern_mismatch, "let #Some(x) = #none in x", ErrorKind::PatternMismatch { .. });
+    test_eval_error!(test_tag_pattern_type, "let #Some(x) = 1 in x", ErrorKind::TypeMismatch { .. });
+    test_eval_success!(test_enum, "enum([#fire, #cold])", "{cold: #cold, fire: #fire}");
+    test_eval_error!(test_enum_not_tag, "enum([#fire, \"cold\"])", ErrorKind::TypeMismatch { .. });
+    test_eval_success!(test_dice_arithmetic, "2 * 1d8 + 1d6 - 3", "2 * 1d8 + 1d6 - 3");
+    test_eval_success!(test_dice_negate, "10 - 2d4", "-2d4 + 10");
+    test_eval_success!(test_dice_average, "[average(2d6 + 3), average(4d6kh3), average(2d20kl1), average(5)]", "[10, 12, 7, 5]");
+    test_eval_success!(test_dice_bounds, "[min_roll(1d20 - 1d4), max_roll(1d20 - 1d4), max_roll(2d6!)]", "[-3, 19, 48]");
+    test_eval_success!(test_roll, "[roll(4d6kh3, 1), roll(4d6kh3, 2), roll(1d20 + 5, 1), roll(3, 1)]", "[14, 12, 11, 3]");
+    test_eval_success!(test_roll_same_seed, "roll(10d6!, 7) == roll(10d6!, 7)", "true");
+    test_eval_error!(test_dice_times_dice, "1d6 * 1d6", ErrorKind::TypeMismatch { .. });
+    test_eval_error!(test_dice_too_many, "average(1000d1000kh500)", ErrorKind::TooManyDice);
+    test_eval_error!(test_chance_too_many, "P(1d600 + 1d500 >= 500)", ErrorKind::TooManyDice);
+    test_eval_success!(test_chance, "P(1d20 + 5 >= 16)", "0.5");
+    test_eval_success!(test_chance_advantage, "[P(advantage(1d20) >= 11), P(disadvantage(1d20) >= 11)]", "[0.75, 0.25]");
+    test_eval_success!(test_chance_not_equal, "[P(1d6 != 6), P(1d6 == 1d6), P(true)]", "[5/6, 1/6, 1]");
+    test_eval_success!(test_chance_not_equal_no_spaces, "[P(1d6!=6), P(1d6! != 6)]", "[5/6, 1]");
+    test_eval_success!(test_expected, "[expected(2d6 + 3), expected(1d8), expected(4d6kh3)]", "[10, 4.5, 15869/1296]");
+    test_eval_success!(test_expected_damage, "P(1d20 >= 11) * expected(2d6) + 1", "4.5");
+    test_eval_success!(
+        test_distribution,
+        "distribution(1d4 + 1d4)",
+        "(distribution 2: 0.0625, 3: 0.125, 4: 0.1875, 5: 0.25, 6: 0.1875, 7: 0.125, 8: 0.0625)"
+    );
+    test_eval_success!(test_distribution_arithmetic, "expected(2 * distribution(1d6) - 1d4)", "4.5");
+    test_eval_success!(test_attack_chances, "attack_chances(1d20, 5, 16, 19)", "{crit: 0.1, hit: 0.5}");
+    test_eval_success!(test_attack_chances_auto, "[attack_chances(1d20, 20, 5).hit, attack_chances(1d20, 0, 30).hit]", "[0.95, 0.05]");
+    test_eval_success!(
+        test_chance_rational,
+        "[P(1d20 >= 10.5), P(1d20 < 0.5), P(1d20 == 2.5), P(2.5 < 1d4), P(distribution(1d4) <= 2.5)]",
+        "[0.5, 0, 0, 0.5, 0.5]"
+    );
+    test_eval_success!(test_rational_compare, "[expected(2d6) == 7, expected(1d8) < 5, sort([expected(1d6), 3, 4])]", "[true, true, [3, 3.5, 4]]");
+    test_eval_success!(test_decimal_arithmetic, "[2.5 * 2, 0.5 + 1, 1 - 1.5, -0.5 * 0.5]", "[5, 1.5, -0.5, -0.25]");
+    test_eval_success!(test_decimal_division, "[1 / 2.0, 7 / 2, 10 / 3.0, 7.5 % 2]", "[0.5, 3, 10/3, 1.5]");
+    test_eval_success!(test_decimal_sum, "[sum([0.5, 1.5, 0.25]), product([0.5, 0.5]), sum([1, 2])]", "[2.25, 0.25, 3]");
+    test_eval_success!(test_decimal_min_max, "[min(2, 1.5), max(2, 1.5), clamp(7.5, 0, 5), abs(-0.5)]", "[1.5, 2, 5, 0.5]");
+    test_eval_success!(test_rounding, "[floor(2.5), ceil(2.5), round(2.5), round(-2.5), floor(-0.5), round(3)]", "[2, 3, 3, -2, -1, 3]");
+    test_eval_error!(test_ceil_overflow, "ceil(9223372036854775808.0 * 9223372036854775808.0 * -2)", ErrorKind::Overflow);
+    test_eval_success!(test_halved_damage, "floor(expected(2d6) / 2)", "3");
+    test_eval_success!(test_decimal_match, "match 1 / 2.0 with | 0.5 -> \"half\" | _ -> \"other\"", "\"half\"");
+    test_eval_success!(test_quantity_add, "[30 ft + 10 ft, 1 mi - 2640 ft, 1 min + 2 rounds, -(5 lb)]", "[40 ft, 0.5 mi, 1.2 min, -5 lb]");
+    test_eval_success!(test_quantity_scale, "[2 * 30 ft, 0.5 * 5 lb, 30 ft / 2, 30 ft / 5 ft]", "[60 ft, 2.5 lb, 15 ft, 6]");
+    test_eval_success!(test_quantity_compare, "[1 mi > 5000 ft, 1 round == 6 s, sort([1 m, 1 ft, 1 yd])]", "[true, true, [1 ft, 1 yd, 1 m]]");
+    test_eval_success!(test_quantity_to, "[to(30 ft, \"m\"), to(10 min, \"rounds\"), to(2.5, \"kg\")]", "[9.144 m, 100 rounds, 2.5 kg]");
+    test_eval_success!(test_quantity_sum, "sum([5 lb, 8 oz, 1 lb])", "6.5 lb");
+    test_eval_error!(test_quantity_add_mismatch, "30 ft + 5 lb", ErrorKind::DimensionMismatch(Some(Dimension::Length), Some(Dimension::Weight)));
+    test_eval_error!(test_quantity_add_number, "30 ft + 5", ErrorKind::DimensionMismatch(Some(Dimension::Length), None));
+    test_eval_error!(test_quantity_compare_mismatch, "10 min < 30 ft", ErrorKind::DimensionMismatch(..));
+    test_eval_error!(test_quantity_to_mismatch, "to(5 lb, \"m\")", ErrorKind::DimensionMismatch(..));
+    test_eval_error!(test_quantity_unknown_unit, "to(5 lb, \"stone\")", ErrorKind::UnknownUnit(_));
+    test_eval_error!(test_decimal_divide_zero, "1.5 / 0", ErrorKind::DivisionByZero);
+
+    #[test]
+    fn test_enum_cell() {
+        let mut sheet = Sheet::<AST>::new();
+        sheet.add_cell("damage_types".to_string(), "enum([#fire, #cold, #lightning])");
+        let attack = sheet
+            .add_cell("attack".to_string(), "{damage: $damage_types.fire}")
+            .unwrap();
+        assert_eq!(sheet.get_cell_value(&attack).unwrap().as_ref().unwrap().to_s_expr(), "{damage: #fire}");
+
+        // Misspelled tags are not in the enumeration
+        let typo = sheet
+            .add_cell("typo".to_string(), "{damage: $damage_types.fier}")
+            .unwrap();
+        let err = sheet.get_cell_value(&typo).unwrap().as_ref().unwrap_err();
+        assert!(matches!(&err.kind, ErrorKind::MissingField(field) if field == "fier"));
+        let err = sheet.get_cell_type(&typo).unwrap().as_ref().unwrap_err();
+        assert!(matches!(&err.kind, ErrorKind::MissingField(field) if field == "fier"));
+    }
+
+    #[test]
+    fn test_expected_damage_cell() {
+        let mut sheet = Sheet::<AST>::new();
+        sheet.add_cell("attack_bonus".to_string(), "7");
+        sheet.add_cell("damage_bonus".to_string(), "4");
+        let expected_damage = "fn (bonus, damage) -> \
+            let chances = attack_chances(1d20, bonus, 16) in \
+            chances.hit * expected(2d6 + damage) + chances.crit * expected(2d6)";
+        sheet.add_cell("expected_damage".to_string(), expected_damage);
+        let normal = sheet
+            .add_cell("normal".to_string(), "expected_damage($attack_bonus, $damage_bonus)")
+            .unwrap();
+        let gwm = sheet
+            .add_cell("gwm".to_string(), "expected_damage($attack_bonus - 5, $damage_bonus + 10)")
+            .unwrap();
+        // 12/20 * 11 + 1/20 * 7 and 7/20 * 21 + 1/20 * 7
+        assert_eq!(sheet.get_cell_value(&normal).unwrap().as_ref().unwrap().to_s_expr(), "6.95");
+        assert_eq!(sheet.get_cell_value(&gwm).unwrap().as_ref().unwrap().to_s_expr(), "7.7");
+    }
+
+    #[test]
+    fn test_roll_log() {
+        let mut sheet = Sheet::<AST>::new();
+        let bonus = sheet.add_cell("bonus".to_string(), "5").unwrap();
+        let attack = sheet.add_cell("attack".to_string(), "roll(1d20) + $bonus").unwrap();
+        let log = sheet.add_cell("log".to_string(), "map(fn (r) -> [r.cell, r.formula, r.seed, r.time], rolls())").unwrap();
+        let seeded = sheet.add_cell("seeded".to_string(), "roll(1d20, 0) + $bonus").unwrap();
+        let value = |sheet: &Sheet<AST>, id| sheet.get_cell_value(id).unwrap().as_ref().unwrap().to_s_expr();
+
+        assert_eq!(value(&sheet, &attack), value(&sheet, &seeded));
+        assert_eq!(value(&sheet, &log), "[[\"attack\", \"1d20\", 0, 0]]");
+        let entry = &sheet.roll_log().entries()[0];
+        assert_eq!((entry.dice.len(), entry.total + 5), (1, value(&sheet, &attack).parse().unwrap()));
+
+        // Changing a cell the roll reads keeps the roll
+        sheet.update_cell(&bonus, "6");
+        assert_eq!(value(&sheet, &attack), value(&sheet, &seeded));
+        assert_eq!(sheet.roll_log().entries().len(), 1);
+
+        // Editing the roll or rerolling it rolls again
+        sheet.update_cell(&attack, "roll(1d20) + $bonus + 0");
+        sheet.reroll(&attack);
+        assert_eq!(
+            value(&sheet, &log),
+            "[[\"attack\", \"1d20\", 0, 0], [\"attack\", \"1d20\", 1, 1], [\"attack\", \"1d20\", 2, 2]]"
+        );
+    }
+
+    #[test]
+    fn test_roll_log_reader_rolls() {
+        let mut sheet = Sheet::<AST>::new();
+        let a = sheet.add_cell("a".to_string(), "map(fn (r) -> roll(1d6), [0] + rolls())").unwrap();
+        let c = sheet.add_cell("c".to_string(), "map(fn (r) -> roll(1d4), rolls())").unwrap();
+        sheet.add_cell("b".to_string(), "roll(1d20)").unwrap();
+        sheet.update_cell(&a, "map(fn (r) -> roll(1d8), [0] + rolls())");
+        assert!(sheet.get_cell_value(&a).unwrap().is_ok());
+        assert!(sheet.get_cell_value(&c).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_roll_seed() {
+        let mut sheet = Sheet::<AST>::new();
+        sheet.set_roll_seed(100);
+        let id = sheet.add_cell("test".to_string(), "[roll(4d6kh3), roll(4d6kh3, 100), roll(4d6kh3), roll(4d6kh3, 101)]").unwrap();
+        let value = sheet.get_cell_value(&id).unwrap().as_ref().unwrap();
+        let Value::List(rolls) = &value.0 else { panic!("expected a list") };
+        assert_eq!(rolls[0].to_s_expr(), rolls[1].to_s_expr());
+        assert_eq!(rolls[2].to_s_expr(), rolls[3].to_s_expr());
+        assert_eq!(sheet.roll_log().entries()[1].dice.len(), 4);
+    }
+
+    test_eval_success!(test_range_operator, "1..4", "[1, 2, 3]");
+    test_eval_success!(test_range_inclusive, "1..=4", "[1, 2, 3, 4]");
+    test_eval_success!(test_range_empty, "3..1", "[]");
+    test_eval_success!(test_comprehension, "[x * 2 for x in [1, 5, 7] if x > 3]", "[10, 14]");
+    test_eval_success!(
+        test_comprehension_nested,
+        "[f\"{a}{b}\" for a in [\"x\", \"y\"] for b in 1..=2 if b != 1 or a == \"x\"]",
+        "[\"x1\", \"x2\", \"y2\"]"
+    );
+    test_eval_success!(
+        test_comprehension_destructure,
+        "[name for {name, equipped} in [{name: \"a\", equipped: true}, {name: \"b\", equipped: false}] if equipped]",
+        "[\"a\"]"
+    );
+    test_eval_success!(test_comprehension_captures, "let n = 10 in [x + n for x in 0..2]", "[10, 11]");
+    test_eval_success!(test_section_fold, "fold((+), 0, [1, 2, 3])", "6");
+    test_eval_success!(test_section_map, "map((* 2), [1, 2])", "[2, 4]");
+    test_eval_success!(test_section_filter, "filter((>= 10), [5, 10, 15])", "[10, 15]");
+    test_eval_success!(test_section_not_eq, "(!=)(1, 2)", "true");
+    test_eval_success!(test_partial_lambda, "let add = fn (a, b) -> a + b in map(add(1), [1, 2])", "[2, 3]");
+    test_eval_success!(test_partial_chain, "let f = fn (a, b, c) -> a * b + c in f(2)(3)(4)", "10");
+    test_eval_success!(test_partial_builtin, "map(index([10, 20]), [1, 0])", "[20, 10]");
+    test_eval_success!(test_partial_lazy, "let x = 2 in let t = try(x / 0) in t(x)", "2");
+    test_eval_error!(test_partial_no_args, "(fn (x, y) -> x)()", ErrorKind::Arity { .. });
+    test_eval_success!(test_try_ok, "try(1 + 1, 0)", "2");
+    test_eval_success!(test_try_error, "try(1 / 0, 0)", "0");
+    test_eval_success!(test_try_lazy, "try(1, foo)", "1");
+    test_eval_success!(test_catch_ok, "1 catch e -> e", "1");
+    test_eval_success!(test_catch_message, "{a: 1}.b catch e -> e", "\"Field \\\"b\\\" does not exist\"");
+    test_eval_success!(test_catch_in_map, "sum(map(fn (x) -> 10 / x catch _ -> 0, [1, 0, 2]))", "15");
+    test_eval_success!(test_is_error, "[is_error(1 / 0), is_error(1)]", "[true, false]");
+    test_eval_error!(test_try_fallback_error, "try(1 / 0, foo)", ErrorKind::UnknownName(_));
+
+    #[test]
+    fn test_is_error_cell() {
+        let mut sheet = Sheet::<AST>::new();
+        let sword = sheet.add_cell("sword".to_string(), "{weight: 3}").unwrap();
+        sheet.add_cell("shield".to_string(), "{weight: 6}");
+        let total = sheet
+            .add_cell(
+                "total".to_string(),
+                "try($sword.weight, 0) + try($shield.weight, 0)",
+            )
+            .unwrap();
+        let broken = sheet
+            .add_cell("broken".to_string(), "if is_error($sword) then \"broken\" else \"ok\"")
+            .unwrap();
+        assert_eq!(sheet.get_cell_value(&total).unwrap().as_ref().unwrap().to_s_expr(), "9");
+
+        // A broken cell is caught by the summary rather than turning it into an error
+        sheet.update_cell(&sword, "{weight: 1 / 0}");
+        assert_eq!(sheet.get_cell_value(&total).unwrap().as_ref().unwrap().to_s_expr(), "6");
+        assert_eq!(sheet.get_cell_value(&broken).unwrap().as_ref().unwrap().to_s_expr(), "\"broken\"");
+
+        let message = sheet.add_cell("message".to_string(), "$sword catch e -> e").unwrap();
+        assert_eq!(
+            sheet.get_cell_value(&message).unwrap().as_ref().unwrap().to_s_expr(),
+            "\"Error in read cell sword: Division by zero\""
+        );
+    }
+
+    #[test]
+    fn test_match_no_arm() {
+        match eval("match [1, 2] with | [] -> 0 | [a] -> a").unwrap_err().kind {
+            ErrorKind::NoMatch(value) => assert_eq!(value, "[1, 2]"),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    test_eval_error!(test_match_guard_not_bool, "match 1 with | n if n -> n", ErrorKind::TypeMismatch { .. });
+    test_eval_error_span!(test_match_guard_span, "match 1 with | n if n -> n", 20, 21);
+
+    #[test]
+    fn test_type_mismatch_expected() {
+        match eval("[1] + 2").unwrap_err().kind {
+            ErrorKind::TypeMismatch { expected, .. } => assert_eq!(expected, vec![ValueType::List]),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_propagated_root_cause() {
+        let mut sheet = Sheet::<AST>::new();
+        sheet.add_cell("a".to_string(), "foo");
+        sheet.add_cell("b".to_string(), "$a + 1");
+        let id = sheet.add_cell("c".to_string(), "b * 2").unwrap();
+        let err = sheet.get_cell_value(&id).unwrap().clone().unwrap_err();
+        assert_eq!(err.causes().count(), 3);
+        assert!(matches!(err.root_cause().kind, ErrorKind::UnknownName(_)));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut sheet = Sheet::<AST>::new();
+        let a = sheet.add_cell("a".to_string(), "1").unwrap();
+        sheet.add_cell("b".to_string(), "a + 1");
+        sheet.update_cell(&a, "b + 1");
+        let err = sheet.get_cell_value(&a).unwrap().clone().unwrap_err();
+        assert!(matches!(err.root_cause().kind, ErrorKind::Cycle(_)));
     }
 
-    fn make_error(message: impl Into<String>) -> Self::Error {
-        Error::with_message(message)
+    #[test]
+    fn test_error_in_other_cell_lambda() {
+        let mut sheet = Sheet::<AST>::new();
+        sheet.add_cell("f".to_string(), "fn (x) -> x + true");
+        let id = sheet.add_cell("test".to_string(), "1 + f(1)").unwrap();
+        let err = sheet.get_cell_value(&id).unwrap().clone().unwrap_err();
+        assert_eq!(err.span, Some(Span::new(4, 5)));
     }
 }
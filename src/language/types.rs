@@ -0,0 +1,1342 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::{
+    language::{
+        ast::{AST, Binding, Expr, Function, MatchArm, Param, Pattern, PatternKind, Value, missing_arguments},
+        bultins::{BuiltinFunction, lookup_builtin},
+        errors::{Error, ErrorKind},
+        units::Dimension,
+    },
+    reactive::language::ReactiveContext,
+};
+
+pub type TypeVar = u32;
+
+/// The static type of an expression, as inferred by the type checker
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    /// A value the checker knows nothing about, compatible with every type.
+    ///
+    /// Used for values that are only known at runtime, such as pushed values,
+    /// and for calls to builtins whose overload cannot be decided statically.
+    Any,
+    Var(TypeVar),
+
+    Unit,
+    Integer,
+    String,
+    Boolean,
+    /// A tagged variant, the values tags carry are not tracked
+    Tag,
+    Dice,
+    Distribution,
+    Rational,
+    /// A quantity with a unit of the given dimension, such as a length
+    Quantity(Dimension),
+
+    List(Box<Type>),
+    /// A record containing the given fields.
+    ///
+    /// If the row variable is present the record may contain further fields,
+    /// otherwise it contains exactly these fields.
+    Record(BTreeMap<String, Type>, Option<TypeVar>),
+    Function(Vec<Type>, Box<Type>),
+}
+
+const UNIT: Type = Type::Unit;
+const INT: Type = Type::Integer;
+const STRING: Type = Type::String;
+const BOOL: Type = Type::Boolean;
+const DICE: Type = Type::Dice;
+const DIST: Type = Type::Distribution;
+const RATIONAL: Type = Type::Rational;
+
+/// Types whose builtin overloads are only considered for arguments known to have one of them
+fn is_numeric_extension(t: &Type) -> bool {
+    match t {
+        Type::Dice | Type::Distribution | Type::Rational | Type::Quantity(_) => true,
+        Type::List(elem) => is_numeric_extension(elem),
+        _ => false,
+    }
+}
+
+fn list(elem: &Type) -> Type {
+    Type::List(Box::new(elem.clone()))
+}
+
+fn func(params: Vec<Type>, ret: Type) -> Type {
+    Type::Function(params, Box::new(ret))
+}
+
+fn var_name(var: TypeVar) -> String {
+    if var < 26 {
+        format!("'{}", (b'a' + var as u8) as char)
+    } else {
+        format!("'t{}", var)
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Any => write!(f, "any"),
+            Type::Var(var) => write!(f, "{}", var_name(*var)),
+            Type::Unit => write!(f, "()"),
+            Type::Integer => write!(f, "int"),
+            Type::String => write!(f, "string"),
+            Type::Boolean => write!(f, "bool"),
+            Type::Tag => write!(f, "tag"),
+            Type::Dice => write!(f, "dice"),
+            Type::Distribution => write!(f, "distribution"),
+            Type::Rational => write!(f, "rational"),
+            Type::Quantity(dimension) => write!(f, "{}", dimension),
+            Type::List(elem) => write!(f, "[{}]", elem),
+            Type::Record(fields, tail) => {
+                let mut parts = fields
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, v))
+                    .collect::<Vec<_>>();
+                if tail.is_some() {
+                    parts.push("..".to_string());
+                }
+                write!(f, "{{{}}}", parts.join(", "))
+            }
+            Type::Function(params, ret) => write!(
+                f,
+                "({}) -> {}",
+                params
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                ret
+            ),
+        }
+    }
+}
+
+impl Type {
+    /// Calls `f` on every type and row variable in the type, in order of appearance.
+    fn visit_vars(&self, f: &mut impl FnMut(TypeVar, bool)) {
+        match self {
+            Type::Var(var) => f(*var, false),
+            Type::List(elem) => elem.visit_vars(f),
+            Type::Record(fields, tail) => {
+                fields.values().for_each(|t| t.visit_vars(f));
+                if let Some(tail) = tail {
+                    f(*tail, true);
+                }
+            }
+            Type::Function(params, ret) => {
+                params.iter().for_each(|t| t.visit_vars(f));
+                ret.visit_vars(f);
+            }
+            _ => {}
+        }
+    }
+
+    fn rename_vars(&self, mapping: &HashMap<TypeVar, TypeVar>) -> Type {
+        let rename = |var: &TypeVar| *mapping.get(var).unwrap_or(var);
+        match self {
+            Type::Var(var) => Type::Var(rename(var)),
+            Type::List(elem) => Type::List(Box::new(elem.rename_vars(mapping))),
+            Type::Record(fields, tail) => Type::Record(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.rename_vars(mapping)))
+                    .collect(),
+                tail.as_ref().map(rename),
+            ),
+            Type::Function(params, ret) => Type::Function(
+                params.iter().map(|p| p.rename_vars(mapping)).collect(),
+                Box::new(ret.rename_vars(mapping)),
+            ),
+            t => t.clone(),
+        }
+    }
+
+    fn free_vars(&self) -> HashSet<TypeVar> {
+        let mut vars = HashSet::new();
+        self.visit_vars(&mut |var, _| {
+            vars.insert(var);
+        });
+        vars
+    }
+}
+
+/// Renumbers the variables of the given types from zero so they display as `'a`, `'b`, ...
+///
+/// Type variables are numbered before row variables since the latter are never displayed.
+/// The types are renumbered together so a variable shared between them keeps the same name.
+fn normalize<const N: usize>(types: [Type; N]) -> [Type; N] {
+    let mut mapping = HashMap::new();
+    for rows in [false, true] {
+        for t in &types {
+            t.visit_vars(&mut |var, is_row| {
+                if is_row == rows && !mapping.contains_key(&var) {
+                    mapping.insert(var, mapping.len() as TypeVar);
+                }
+            });
+        }
+    }
+    types.map(|t| t.rename_vars(&mapping))
+}
+
+/// A type with some of its variables universally quantified, as given to let bindings
+#[derive(Debug, Clone)]
+struct Scheme {
+    quantified: Vec<TypeVar>,
+    ty: Type,
+}
+
+impl Scheme {
+    fn monomorphic(ty: Type) -> Self {
+        Scheme {
+            quantified: vec![],
+            ty,
+        }
+    }
+}
+
+/// A candidate signature of a builtin function
+type Signature = (Vec<Type>, Type);
+
+struct Checker<'c, 'a> {
+    ctx: &'c mut ReactiveContext<'a, AST>,
+    subst: HashMap<TypeVar, Type>,
+    next_var: TypeVar,
+    env: Vec<(String, Scheme)>,
+}
+
+impl Checker<'_, '_> {
+    fn fresh_var(&mut self) -> TypeVar {
+        self.next_var += 1;
+        self.next_var - 1
+    }
+
+    fn fresh(&mut self) -> Type {
+        Type::Var(self.fresh_var())
+    }
+
+    fn open_record(&mut self) -> Type {
+        Type::Record(BTreeMap::new(), Some(self.fresh_var()))
+    }
+
+    /// Applies the current substitution, replacing every bound variable in the type
+    fn apply(&self, t: &Type) -> Type {
+        match t {
+            Type::Var(var) => match self.subst.get(var) {
+                Some(bound) => self.apply(bound),
+                None => t.clone(),
+            },
+            Type::List(elem) => Type::List(Box::new(self.apply(elem))),
+            Type::Record(fields, tail) => {
+                let mut fields = fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), self.apply(v)))
+                    .collect::<BTreeMap<_, _>>();
+                let mut tail = *tail;
+                // Bound row variables are records holding the rest of the row
+                while let Some(Type::Record(more, next)) = tail.and_then(|v| self.subst.get(&v)) {
+                    for (k, v) in more {
+                        fields.insert(k.clone(), self.apply(v));
+                    }
+                    tail = *next;
+                }
+                Type::Record(fields, tail)
+            }
+            Type::Function(params, ret) => Type::Function(
+                params.iter().map(|p| self.apply(p)).collect(),
+                Box::new(self.apply(ret)),
+            ),
+            t => t.clone(),
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), ()> {
+        match (self.apply(a), self.apply(b)) {
+            (Type::Any, _) | (_, Type::Any) => Ok(()),
+            (Type::Var(a), Type::Var(b)) if a == b => Ok(()),
+            (Type::Var(var), t) | (t, Type::Var(var)) => {
+                if t.free_vars().contains(&var) {
+                    Err(())
+                } else {
+                    self.subst.insert(var, t);
+                    Ok(())
+                }
+            }
+            (Type::Unit, Type::Unit)
+            | (Type::Integer, Type::Integer)
+            | (Type::String, Type::String)
+            | (Type::Boolean, Type::Boolean)
+            | (Type::Tag, Type::Tag)
+            | (Type::Dice, Type::Dice)
+            | (Type::Distribution, Type::Distribution)
+            | (Type::Rational, Type::Rational) => Ok(()),
+            (Type::Quantity(a), Type::Quantity(b)) if a == b => Ok(()),
+            (Type::List(a), Type::List(b)) => self.unify(&a, &b),
+            (Type::Function(a_params, a_ret), Type::Function(b_params, b_ret)) => {
+                if a_params.len() != b_params.len() {
+                    return Err(());
+                }
+                for (a, b) in a_params.iter().zip(&b_params) {
+                    self.unify(a, b)?;
+                }
+                self.unify(&a_ret, &b_ret)
+            }
+            (Type::Record(a_fields, a_tail), Type::Record(b_fields, b_tail)) => {
+                for (k, a) in &a_fields {
+                    if let Some(b) = b_fields.get(k) {
+                        self.unify(a, b)?;
+                    }
+                }
+                let only_in = |fields: &BTreeMap<String, Type>, other: &BTreeMap<String, Type>| {
+                    fields
+                        .iter()
+                        .filter(|(k, _)| !other.contains_key(*k))
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect::<BTreeMap<_, _>>()
+                };
+                let only_a = only_in(&a_fields, &b_fields);
+                let only_b = only_in(&b_fields, &a_fields);
+
+                match (a_tail, b_tail) {
+                    (Some(a_tail), Some(b_tail)) if a_tail == b_tail => {
+                        if only_a.is_empty() && only_b.is_empty() {
+                            Ok(())
+                        } else {
+                            Err(())
+                        }
+                    }
+                    // Both rows are open, so each gains the fields only the other has
+                    (Some(a_tail), Some(b_tail)) => {
+                        let rest = self.fresh_var();
+                        self.subst.insert(a_tail, Type::Record(only_b, Some(rest)));
+                        self.subst.insert(b_tail, Type::Record(only_a, Some(rest)));
+                        Ok(())
+                    }
+                    (Some(a_tail), None) if only_a.is_empty() => {
+                        self.subst.insert(a_tail, Type::Record(only_b, None));
+                        Ok(())
+                    }
+                    (None, Some(b_tail)) if only_b.is_empty() => {
+                        self.subst.insert(b_tail, Type::Record(only_a, None));
+                        Ok(())
+                    }
+                    (None, None) if only_a.is_empty() && only_b.is_empty() => Ok(()),
+                    _ => Err(()),
+                }
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Unifies the type of a value with the type it is expected to have,
+    /// reporting a type error if they are incompatible.
+    fn expect(&mut self, expected: &Type, actual: &Type) -> Result<(), Error> {
+        let applied = [self.apply(expected), self.apply(actual)];
+        self.unify(expected, actual).map_err(|_| {
+            let [expected, actual] = normalize(applied);
+            Error::new(ErrorKind::StaticTypeMismatch { expected, actual })
+        })
+    }
+
+    fn env_vars(&self) -> HashSet<TypeVar> {
+        self.env
+            .iter()
+            .flat_map(|(_, scheme)| {
+                let quantified = &scheme.quantified;
+                self.apply(&scheme.ty)
+                    .free_vars()
+                    .into_iter()
+                    .filter(move |v| !quantified.contains(v))
+            })
+            .collect()
+    }
+
+    fn generalize(&self, t: &Type) -> Scheme {
+        let ty = self.apply(t);
+        let env_vars = self.env_vars();
+        Scheme {
+            quantified: ty
+                .free_vars()
+                .into_iter()
+                .filter(|v| !env_vars.contains(v))
+                .collect(),
+            ty,
+        }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let mapping = scheme
+            .quantified
+            .iter()
+            .map(|v| (*v, self.fresh_var()))
+            .collect();
+        self.apply(&scheme.ty).rename_vars(&mapping)
+    }
+
+    /// The type of a cell read by the formula, with all of its variables made fresh
+    fn cell_type(&mut self, name: &str) -> Option<Type> {
+        let ty = match self.ctx.read_cell_type_by_name(name)? {
+            (_, Some(Ok(ty))) => ty.clone(),
+            // Cells that are in error are reported when their value is read
+            _ => return Some(Type::Any),
+        };
+        let scheme = Scheme {
+            quantified: ty.free_vars().into_iter().collect(),
+            ty,
+        };
+        Some(self.instantiate(&scheme))
+    }
+
+    fn lookup_local(&mut self, name: &str) -> Option<Type> {
+        let scheme = self
+            .env
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, scheme)| scheme.clone())?;
+        Some(self.instantiate(&scheme))
+    }
+
+    fn infer(&mut self, ast: &AST) -> Result<Type, Error> {
+        self.infer_expr(ast).map_err(|e| e.or_span(ast.span))
+    }
+
+    fn infer_expr(&mut self, ast: &AST) -> Result<Type, Error> {
+        match &ast.expr {
+            Expr::Literal(value) => self.infer_value(value),
+
+            Expr::Name(name) => {
+                if let Some(cell) = name.strip_prefix('$') {
+                    self.cell_type(cell)
+                        .ok_or_else(|| Error::new(ErrorKind::UnknownCell(cell.to_string())))
+                } else if let Some(t) = self.lookup_local(name) {
+                    Ok(t)
+                } else if let Some(builtin) = lookup_builtin(name) {
+                    Ok(self.builtin_type(builtin))
+                } else {
+                    self.cell_type(name)
+                        .ok_or_else(|| Error::new(ErrorKind::UnknownName(name.clone())))
+                }
+            }
+
+            Expr::FieldAccess(record, field) => {
+                let record_type = self.infer(record)?;
+                if let Type::Record(fields, None) = self.apply(&record_type)
+                    && !fields.contains_key(field)
+                {
+                    return Err(Error::new(ErrorKind::MissingField(field.clone())));
+                }
+                let field_type = self.fresh();
+                let expected = Type::Record(
+                    BTreeMap::from([(field.clone(), field_type.clone())]),
+                    Some(self.fresh_var()),
+                );
+                self.expect(&expected, &record_type)?;
+                Ok(field_type)
+            }
+
+            Expr::Let(bindings, expr) => {
+                let depth = self.env.len();
+                for Binding(pattern, expr) in bindings {
+                    let t = self.infer(expr)?;
+                    if let PatternKind::Name(name) = &pattern.kind {
+                        let scheme = self.generalize(&t);
+                        self.env.push((name.clone(), scheme));
+                    } else {
+                        // Names bound by destructuring are not generalised
+                        let pattern_type = self.infer_pattern(pattern)?;
+                        self.expect(&pattern_type, &t)
+                            .map_err(|e| e.or_span(pattern.span))?;
+                    }
+                }
+                let result = self.infer(expr);
+                self.env.truncate(depth);
+                result
+            }
+
+            Expr::Match(value, arms) => {
+                let value_type = self.infer(value)?;
+                let result = self.fresh();
+                for MatchArm { pattern, guard, body } in arms {
+                    let depth = self.env.len();
+                    let pattern_type = self.infer_pattern(pattern)?;
+                    self.expect(&pattern_type, &value_type)
+                        .map_err(|e| e.or_span(pattern.span))?;
+                    if let Some(guard) = guard {
+                        let guard_type = self.infer(guard)?;
+                        self.expect(&BOOL, &guard_type)
+                            .map_err(|e| e.or_span(guard.span))?;
+                    }
+                    let body_type = self.infer(body)?;
+                    self.expect(&result, &body_type)
+                        .map_err(|e| e.or_span(body.span))?;
+                    self.env.truncate(depth);
+                }
+                Ok(result)
+            }
+
+            // The parameter names of a function are not part of its type, so named calls are not checked
+            Expr::NamedCall(function, args, named) => {
+                self.infer(function)?;
+                self.infer_args(args)?;
+                for (_, arg) in named {
+                    self.infer(arg)?;
+                }
+                Ok(Type::Any)
+            }
+
+            Expr::Function(function, args) => {
+                if let Some(builtin) = self.as_builtin(function) {
+                    return self.infer_builtin_call(builtin, args);
+                }
+
+                let function_type = self.infer(function)?;
+                let arg_types = self.infer_args(args)?;
+                match self.apply(&function_type) {
+                    Type::Any => Ok(Type::Any),
+                    Type::Function(mut params, ret) => {
+                        if let Some(missing) = missing_arguments(&[params.len()], args.len()) {
+                            let rest = params.split_off(params.len() - missing);
+                            for ((param, arg_type), arg) in params.iter().zip(&arg_types).zip(args) {
+                                self.expect(param, arg_type)
+                                    .map_err(|e| e.or_span(arg.span))?;
+                            }
+                            return Ok(func(rest, *ret));
+                        }
+                        if params.len() != args.len() {
+                            return Err(Error::new(ErrorKind::Arity {
+                                expected: vec![params.len()],
+                                actual: args.len(),
+                            }));
+                        }
+                        for ((param, arg_type), arg) in params.iter().zip(&arg_types).zip(args) {
+                            self.expect(param, arg_type)
+                                .map_err(|e| e.or_span(arg.span))?;
+                        }
+                        Ok(*ret)
+                    }
+                    t => {
+                        let ret = self.fresh();
+                        self.expect(&func(arg_types, ret.clone()), &t)
+                            .map_err(|e| e.or_span(function.span))?;
+                        Ok(ret)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Infers the type of a lambda parameter, checking that its default matches it
+    fn infer_param(&mut self, param: &Param) -> Result<Type, Error> {
+        let default = param.default.as_ref().map(|d| self.infer(d)).transpose()?;
+        let t = self.infer_pattern(&param.pattern)?;
+        if let (Some(default), Some(default_type)) = (&param.default, default) {
+            self.expect(&t, &default_type)
+                .map_err(|e| e.or_span(default.span))?;
+        }
+        Ok(t)
+    }
+
+    /// Infers the type of values matched by a pattern, adding the names it binds to the environment
+    fn infer_pattern(&mut self, pattern: &Pattern) -> Result<Type, Error> {
+        match &pattern.kind {
+            PatternKind::Wildcard => Ok(self.fresh()),
+            PatternKind::Name(name) => {
+                let t = self.fresh();
+                self.env.push((name.clone(), Scheme::monomorphic(t.clone())));
+                Ok(t)
+            }
+            PatternKind::Literal(value) => self.infer_value(&value.clone().into()),
+            PatternKind::List(elements, rest) => {
+                let elem = self.fresh();
+                for element in elements {
+                    let t = self.infer_pattern(element)?;
+                    self.expect(&elem, &t)
+                        .map_err(|e| e.or_span(element.span))?;
+                }
+                if let Some(rest) = rest {
+                    let t = self.infer_pattern(rest)?;
+                    self.expect(&list(&elem), &t)
+                        .map_err(|e| e.or_span(rest.span))?;
+                }
+                Ok(list(&elem))
+            }
+            PatternKind::Record(fields) => {
+                let mut field_types = BTreeMap::new();
+                for (field, p) in fields {
+                    field_types.insert(field.clone(), self.infer_pattern(p)?);
+                }
+                Ok(Type::Record(field_types, Some(self.fresh_var())))
+            }
+            PatternKind::Tag(_, payload) => {
+                if let Some(payload) = payload {
+                    // The values tags carry are not tracked, so the names bound in them can have any type
+                    let t = self.infer_pattern(payload)?;
+                    t.visit_vars(&mut |var, is_row| {
+                        if !is_row {
+                            self.subst.insert(var, Type::Any);
+                        }
+                    });
+                }
+                Ok(Type::Tag)
+            }
+        }
+    }
+
+    fn infer_args(&mut self, args: &[AST]) -> Result<Vec<Type>, Error> {
+        args.iter().map(|arg| self.infer(arg)).collect()
+    }
+
+    fn infer_value(&mut self, value: &Value<AST>) -> Result<Type, Error> {
+        match value {
+            Value::Unit => Ok(UNIT),
+            Value::Integer(_) => Ok(INT),
+            Value::String(_) => Ok(STRING),
+            Value::Boolean(_) => Ok(BOOL),
+            Value::Tag(_, payload) => {
+                if let Some(payload) = payload {
+                    self.infer(payload)?;
+                }
+                Ok(Type::Tag)
+            }
+            Value::Dice(_) => Ok(DICE),
+            Value::Distribution(_) => Ok(DIST),
+            Value::Rational(_) => Ok(RATIONAL),
+            Value::Quantity(q) => Ok(Type::Quantity(q.dimension())),
+            Value::List(items) => {
+                let elem = self.fresh();
+                for item in items {
+                    let t = self.infer(item)?;
+                    self.expect(&elem, &t).map_err(|e| e.or_span(item.span))?;
+                }
+                Ok(list(&elem))
+            }
+            Value::Record(fields) => {
+                let mut field_types = BTreeMap::new();
+                for (k, v) in fields {
+                    field_types.insert(k.clone(), self.infer(v)?);
+                }
+                Ok(Type::Record(field_types, None))
+            }
+            Value::Function(Function::Builtin(builtin)) => Ok(self.builtin_type(*builtin)),
+            Value::Function(Function::Lambda(params, body)) => {
+                let depth = self.env.len();
+                let param_types = params
+                    .iter()
+                    .map(|p| self.infer_param(p))
+                    .collect::<Result<Vec<_>, _>>();
+                let inferred = param_types.and_then(|params| Ok((params, self.infer(body)?)));
+                self.env.truncate(depth);
+                let (param_types, ret) = inferred?;
+                // Function types have no optional parameters, so calls to these are not checked
+                if params.iter().any(|p| p.default.is_some()) {
+                    return Ok(Type::Any);
+                }
+                Ok(func(param_types, ret))
+            }
+        }
+    }
+
+    /// The builtin a call refers to, if it can be determined from the syntax alone
+    fn as_builtin(&self, function: &AST) -> Option<BuiltinFunction> {
+        match &function.expr {
+            Expr::Literal(Value::Function(Function::Builtin(builtin))) => Some(*builtin),
+            Expr::Name(name) if !self.env.iter().any(|(n, _)| n == name) => lookup_builtin(name),
+            _ => None,
+        }
+    }
+
+    /// The type of a builtin used as a value rather than called directly
+    fn builtin_type(&mut self, builtin: BuiltinFunction) -> Type {
+        match self.signatures(builtin).as_slice() {
+            [(params, ret)] => func(params.clone(), ret.clone()),
+            _ => Type::Any,
+        }
+    }
+
+    /// Infers the type of a call to a builtin by finding the signature that fits the arguments.
+    ///
+    /// If several signatures fit, the result is `Any` and the arguments are left unconstrained.
+    fn infer_builtin_call(
+        &mut self,
+        builtin: BuiltinFunction,
+        args: &[AST],
+    ) -> Result<Type, Error> {
+        match (builtin, args) {
+            (BuiltinFunction::RecordUpdate, [_, _]) => return self.infer_record_update(args),
+            (BuiltinFunction::Coalesce, [left, right]) => return self.infer_coalesce(left, right),
+            (BuiltinFunction::Enum, [tags]) => {
+                if let Expr::Literal(Value::List(tags)) = &tags.expr {
+                    return self.infer_enum(tags);
+                }
+            }
+            (BuiltinFunction::OptionalField, [record, field]) => {
+                if let Expr::Literal(Value::String(field)) = &field.expr {
+                    return self.infer_optional_field(record, field);
+                }
+            }
+            _ => {}
+        }
+
+        let arg_types = self.infer_args(args)?;
+        let mut signatures = self.signatures(builtin);
+        let arities = signatures.iter().map(|(params, _)| params.len()).collect::<Vec<_>>();
+        if missing_arguments(&arities, args.len()).is_some() {
+            // A partial application returns a function taking the remaining parameters
+            signatures = signatures
+                .into_iter()
+                .map(|(mut params, ret)| {
+                    let rest = params.split_off(args.len());
+                    (params, func(rest, ret))
+                })
+                .collect();
+        }
+        let mut candidates = signatures
+            .iter()
+            .filter(|(params, _)| params.len() == args.len())
+            .collect::<Vec<_>>();
+        // Overloads for dice, distributions and rationals only apply to arguments known to be
+        // one of them, so that arithmetic on values of unknown type is checked as integer arithmetic
+        if !arg_types.iter().any(|t| is_numeric_extension(&self.apply(t))) {
+            let plain = candidates
+                .iter()
+                .copied()
+                .filter(|(params, _)| !params.iter().any(is_numeric_extension))
+                .collect::<Vec<_>>();
+            if !plain.is_empty() {
+                candidates = plain;
+            }
+        }
+        if candidates.is_empty() {
+            let mut expected = signatures
+                .iter()
+                .map(|(params, _)| params.len())
+                .collect::<Vec<_>>();
+            expected.dedup();
+            return Err(Error::new(ErrorKind::Arity {
+                expected,
+                actual: args.len(),
+            }));
+        }
+
+        let original = self.subst.clone();
+        let mut matches = vec![];
+        // The candidate that matched the most arguments, used to report the error
+        let mut closest = (0, candidates[0]);
+        for candidate in &candidates {
+            self.subst = original.clone();
+            let (params, ret) = candidate;
+            let matched = params
+                .iter()
+                .zip(&arg_types)
+                .take_while(|(param, arg)| self.unify(param, arg).is_ok())
+                .count();
+            if matched == params.len() {
+                matches.push((self.subst.clone(), ret.clone()));
+            } else if matched > closest.0 {
+                closest = (matched, *candidate);
+            }
+        }
+
+        match matches.len() {
+            1 => {
+                let (subst, ret) = matches.pop().unwrap();
+                self.subst = subst;
+                Ok(ret)
+            }
+            0 => {
+                self.subst = original;
+                let (position, (params, _)) = closest;
+                // Replay the arguments that matched so the error shows the same types
+                for (param, arg) in params.iter().zip(&arg_types).take(position) {
+                    self.unify(param, arg).unwrap();
+                }
+                Err(self
+                    .expect(&params[position], &arg_types[position])
+                    .unwrap_err()
+                    .or_span(args[position].span))
+            }
+            _ => {
+                self.subst = original;
+                Ok(Type::Any)
+            }
+        }
+    }
+
+    /// Infers the type of an enumeration declared with a list of tag literals, which has a field for each tag
+    fn infer_enum(&mut self, tags: &[AST]) -> Result<Type, Error> {
+        let mut fields = BTreeMap::new();
+        for tag in tags {
+            let t = self.infer(tag)?;
+            self.expect(&Type::Tag, &t)
+                .map_err(|e| e.or_span(tag.span))?;
+            match &tag.expr {
+                Expr::Literal(Value::Tag(name, _)) => fields.insert(name.clone(), Type::Tag),
+                // Tags that are only known at runtime could add any field
+                _ => return Ok(Type::Any),
+            };
+        }
+        Ok(Type::Record(fields, None))
+    }
+
+    /// Infers the type of `left // right`.
+    ///
+    /// The fields of `right` replace those of `left`. If `right` may contain
+    /// fields the checker does not know about, any field of `left` may have been
+    /// replaced so only the fields of `right` are known.
+    fn infer_record_update(&mut self, args: &[AST]) -> Result<Type, Error> {
+        let [left, right] = args else {
+            return Err(Error::new(ErrorKind::Arity {
+                expected: vec![2],
+                actual: args.len(),
+            }));
+        };
+        let left_type = self.infer(left)?;
+        let right_type = self.infer(right)?;
+        let open = self.open_record();
+        self.expect(&open, &left_type)
+            .map_err(|e| e.or_span(left.span))?;
+        let open = self.open_record();
+        self.expect(&open, &right_type)
+            .map_err(|e| e.or_span(right.span))?;
+
+        Ok(match (self.apply(&left_type), self.apply(&right_type)) {
+            (Type::Record(mut fields, tail), Type::Record(right_fields, None)) => {
+                fields.extend(right_fields);
+                Type::Record(fields, tail)
+            }
+            (_, Type::Record(right_fields, _)) => {
+                Type::Record(right_fields, Some(self.fresh_var()))
+            }
+            _ => Type::Any,
+        })
+    }
+
+    /// Infers the type of `record?.field`.
+    ///
+    /// The field's type is used if the record is known to have it, and unit if the
+    /// record is known not to.
+    fn infer_optional_field(&mut self, record: &AST, field: &str) -> Result<Type, Error> {
+        let record_type = self.infer(record)?;
+        match self.apply(&record_type) {
+            Type::Record(fields, tail) => Ok(match fields.get(field) {
+                Some(t) => t.clone(),
+                None if tail.is_none() => UNIT,
+                None => Type::Any,
+            }),
+            Type::Unit => Ok(UNIT),
+            Type::Var(_) | Type::Any => Ok(Type::Any),
+            actual => {
+                let expected = self.open_record();
+                Err(self.expect(&expected, &actual).unwrap_err().or_span(record.span))
+            }
+        }
+    }
+
+    /// Infers the type of `left ?? right`.
+    ///
+    /// A field access on the left is treated like `?.`, since a missing field falls back to the right.
+    fn infer_coalesce(&mut self, left: &AST, right: &AST) -> Result<Type, Error> {
+        let left_type = match &left.expr {
+            Expr::FieldAccess(record, field) => self.infer_optional_field(record, field)?,
+            _ => self.infer(left)?,
+        };
+        let right_type = self.infer(right)?;
+        match self.apply(&left_type) {
+            Type::Unit | Type::Any => Ok(right_type),
+            _ => {
+                self.expect(&left_type, &right_type)
+                    .map_err(|e| e.or_span(right.span))?;
+                Ok(left_type)
+            }
+        }
+    }
+
+    /// The signatures a builtin can be called with, using fresh type variables
+    fn signatures(&mut self, builtin: BuiltinFunction) -> Vec<Signature> {
+        use BuiltinFunction::*;
+
+        let a = self.fresh();
+        let b = self.fresh();
+        let record = self.open_record();
+        let other_record = self.open_record();
+        let int_binop = || vec![(vec![INT, INT], INT)];
+        // Adding or subtracting dice keeps them dice, but anything involving a distribution is one
+        let random_arithmetic = || {
+            let operands = [INT, DICE, DIST];
+            let mut signatures = vec![];
+            for x in &operands {
+                for y in &operands {
+                    let result = if *x == DIST || *y == DIST { DIST } else { DICE };
+                    if (x, y) != (&INT, &INT) {
+                        signatures.push((vec![x.clone(), y.clone()], result));
+                    }
+                }
+            }
+            signatures
+        };
+        let rational_binop = || {
+            vec![
+                (vec![RATIONAL, RATIONAL], RATIONAL),
+                (vec![RATIONAL, INT], RATIONAL),
+                (vec![INT, RATIONAL], RATIONAL),
+            ]
+        };
+        // Comparing dice or distributions gives the chance of the comparison passing
+        let chance = |a: Type| {
+            let mut signatures = vec![(vec![a.clone(), a], BOOL)];
+            for x in [INT, DICE, DIST] {
+                for y in [INT, DICE, DIST] {
+                    if (&x, &y) != (&INT, &INT) {
+                        signatures.push((vec![x.clone(), y], DIST));
+                    }
+                }
+            }
+            signatures.extend([(vec![RATIONAL, INT], BOOL), (vec![INT, RATIONAL], BOOL)]);
+            for x in [DICE, DIST] {
+                signatures.extend([(vec![x.clone(), RATIONAL], DIST), (vec![RATIONAL, x], DIST)]);
+            }
+            signatures
+        };
+        // One signature for quantities of each dimension
+        let quantity = |signature: fn(Type) -> Vec<Signature>| {
+            Dimension::ALL.into_iter().flat_map(|d| signature(Type::Quantity(d))).collect::<Vec<_>>()
+        };
+        let quantity_sum = || quantity(|q| vec![(vec![q.clone(), q.clone()], q)]);
+        let predicate = func(vec![a.clone()], BOOL);
+
+        match builtin {
+            Add => [
+                vec![
+                    (vec![INT, INT], INT),
+                    (vec![STRING, STRING], STRING),
+                    (vec![list(&a), list(&a)], list(&a)),
+                ],
+                random_arithmetic(),
+                rational_binop(),
+                quantity_sum(),
+            ]
+            .concat(),
+            Sub => [int_binop(), random_arithmetic(), rational_binop(), quantity_sum()].concat(),
+            Mul => [
+                int_binop(),
+                vec![
+                    (vec![INT, DICE], DICE),
+                    (vec![DICE, INT], DICE),
+                    (vec![INT, DIST], DIST),
+                    (vec![DIST, INT], DIST),
+                ],
+                rational_binop(),
+                quantity(|q| {
+                    vec![
+                        (vec![q.clone(), INT], q.clone()),
+                        (vec![INT, q.clone()], q.clone()),
+                        (vec![q.clone(), RATIONAL], q.clone()),
+                        (vec![RATIONAL, q.clone()], q),
+                    ]
+                }),
+            ]
+            .concat(),
+            Div => [
+                int_binop(),
+                rational_binop(),
+                quantity(|q| {
+                    vec![
+                        (vec![q.clone(), INT], q.clone()),
+                        (vec![q.clone(), RATIONAL], q.clone()),
+                        (vec![q.clone(), q], RATIONAL),
+                    ]
+                }),
+            ]
+            .concat(),
+            Mod => [int_binop(), rational_binop()].concat(),
+            DivCeil | RoundHalfUp => int_binop(),
+            Negate => vec![
+                (vec![INT], INT),
+                (vec![DICE], DICE),
+                (vec![DIST], DIST),
+                (vec![RATIONAL], RATIONAL),
+            ]
+            .into_iter()
+            .chain(quantity(|q| vec![(vec![q.clone()], q)]))
+            .collect(),
+            Abs => vec![(vec![INT], INT), (vec![RATIONAL], RATIONAL)],
+            Floor | Ceil | Round => vec![(vec![RATIONAL], INT), (vec![INT], INT)],
+            // The dimension of a number given a unit depends on the unit's name, so is not known
+            To => [
+                vec![(vec![INT, STRING], Type::Any), (vec![RATIONAL, STRING], Type::Any)],
+                quantity(|q| vec![(vec![q.clone(), STRING], q)]),
+            ]
+            .concat(),
+            Min | Max => [vec![(vec![INT, INT], INT), (vec![list(&a)], a)], rational_binop()].concat(),
+            Clamp => {
+                // Any rational bound makes the result rational
+                let mut signatures = vec![(vec![INT, INT, INT], INT)];
+                for x in [INT, RATIONAL] {
+                    for lo in [INT, RATIONAL] {
+                        for hi in [INT, RATIONAL] {
+                            if [&x, &lo, &hi].contains(&&RATIONAL) {
+                                signatures.push((vec![x.clone(), lo.clone(), hi], RATIONAL));
+                            }
+                        }
+                    }
+                }
+                signatures
+            }
+
+            Push => vec![(vec![STRING, a.clone()], a)],
+            Read => vec![(vec![], list(&Type::Any))],
+
+            Index => vec![
+                (vec![list(&a), INT], a),
+                (vec![record, STRING], Type::Any),
+            ],
+
+            LessThan | GreaterThan | LessThanEqual | GreaterThanEqual | Equals => chance(a),
+            And | Or => vec![(vec![BOOL, BOOL], BOOL)],
+            Not => vec![(vec![BOOL], BOOL), (vec![DIST], DIST)],
+            If => vec![(vec![BOOL, a.clone(), a.clone()], a)],
+
+            Try => vec![(vec![a.clone(), a.clone()], a)],
+            Catch => vec![(vec![a.clone(), func(vec![STRING], a.clone())], a)],
+            IsError => vec![(vec![a], BOOL)],
+
+            Map => vec![
+                (vec![func(vec![a.clone()], b.clone()), list(&a)], list(&b)),
+                (
+                    vec![func(vec![STRING, Type::Any], b), record],
+                    Type::Any,
+                ),
+            ],
+            Fold => vec![
+                (vec![func(vec![b.clone(), a.clone()], b.clone()), b.clone(), list(&a)], b.clone()),
+                (
+                    vec![func(vec![b.clone(), STRING, Type::Any], b.clone()), b.clone(), record],
+                    b,
+                ),
+            ],
+            Filter => vec![
+                (vec![predicate.clone(), list(&a)], list(&a)),
+                (vec![func(vec![STRING, Type::Any], BOOL), record], Type::Any),
+            ],
+
+            Length => vec![
+                (vec![list(&a)], INT),
+                (vec![record], INT),
+                (vec![STRING], INT),
+            ],
+            Sum => [
+                vec![(vec![list(&INT)], INT), (vec![list(&RATIONAL)], RATIONAL)],
+                quantity(|q| vec![(vec![list(&q)], q)]),
+            ]
+            .concat(),
+            Product => vec![(vec![list(&INT)], INT), (vec![list(&RATIONAL)], RATIONAL)],
+            Sort | Reverse | Unique => vec![(vec![list(&a)], list(&a))],
+            SortBy => vec![(vec![func(vec![a.clone()], b), list(&a)], list(&a))],
+            Zip => vec![(vec![list(&a), list(&b)], list(&list(&Type::Any)))],
+            Range => vec![(vec![INT], list(&INT)), (vec![INT, INT], list(&INT))],
+            Contains => vec![
+                (vec![STRING, STRING], BOOL),
+                (vec![list(&a), a], BOOL),
+            ],
+            // Not finding an element gives unit, so the result may not be an `a`
+            Find => vec![(vec![predicate, list(&a)], Type::Any)],
+            Any | All => vec![(vec![predicate, list(&a)], BOOL)],
+            Flatten => vec![(vec![list(&list(&a))], list(&a))],
+            Take | Drop => vec![(vec![list(&a), INT], list(&a))],
+            GroupBy => vec![(vec![func(vec![a.clone()], STRING), list(&a)], Type::Any)],
+
+            Upper | Lower | Trim => vec![(vec![STRING], STRING)],
+            Split => vec![(vec![STRING, STRING], list(&STRING))],
+            Join => vec![(vec![list(&STRING), STRING], STRING)],
+            StartsWith => vec![(vec![STRING, STRING], BOOL)],
+            Replace => vec![(vec![STRING, STRING, STRING], STRING)],
+            ToString => vec![(vec![a], STRING)],
+            ParseInt => vec![(vec![STRING], INT)],
+
+            // Calls are handled by `infer_record_update`
+            RecordUpdate => vec![(vec![record.clone(), other_record], Type::Any)],
+            Keys => vec![(vec![record], list(&STRING))],
+            Values => vec![(vec![record], list(&Type::Any))],
+            Entries => vec![(vec![record], list(&list(&Type::Any)))],
+            FromEntries => vec![(vec![list(&list(&Type::Any))], Type::Any)],
+            // The name of the field is only known at runtime
+            ComputedField => vec![(vec![STRING, a], record)],
+            Has => vec![(vec![record, STRING], BOOL)],
+            Remove => vec![(vec![record, STRING], Type::Any)],
+            GetOr => vec![
+                (vec![record.clone(), STRING, a.clone()], Type::Any),
+                (vec![UNIT, STRING, a.clone()], a),
+            ],
+            OptionalField => vec![(vec![record, STRING], Type::Any), (vec![UNIT, STRING], UNIT)],
+            Coalesce => vec![(vec![a.clone(), a.clone()], a)],
+            DeepMerge => vec![(vec![record, other_record], Type::Any)],
+
+            Enum => vec![(vec![list(&Type::Tag)], Type::Any)],
+
+            Average | MinRoll | MaxRoll => vec![(vec![DICE], INT), (vec![INT], INT)],
+            Roll => vec![
+                (vec![DICE, INT], INT),
+                (vec![INT, INT], INT),
+                (vec![DICE], INT),
+                (vec![INT], INT),
+            ],
+            Rolls => {
+                let entry = [
+                    ("cell", STRING),
+                    ("dice", list(&INT)),
+                    ("formula", STRING),
+                    ("seed", INT),
+                    ("time", INT),
+                    ("total", INT),
+                ];
+                let entry = Type::Record(entry.into_iter().map(|(k, t)| (k.to_string(), t)).collect(), None);
+                vec![(vec![], list(&entry))]
+            }
+
+            ToDistribution => vec![(vec![DICE], DIST), (vec![DIST], DIST), (vec![INT], DIST)],
+            Probability => vec![(vec![DIST], RATIONAL), (vec![BOOL], RATIONAL)],
+            Expected => vec![
+                (vec![DICE], RATIONAL),
+                (vec![DIST], RATIONAL),
+                (vec![RATIONAL], RATIONAL),
+                (vec![INT], RATIONAL),
+            ],
+            Advantage | Disadvantage => vec![(vec![DICE], DIST), (vec![DIST], DIST)],
+            AttackChances => {
+                let chances = Type::Record(
+                    BTreeMap::from([("crit".to_string(), RATIONAL), ("hit".to_string(), RATIONAL)]),
+                    None,
+                );
+                let mut signatures = vec![];
+                for roll in [DICE, DIST] {
+                    signatures.push((vec![roll.clone(), INT, INT], chances.clone()));
+                    signatures.push((vec![roll, INT, INT, INT], chances.clone()));
+                }
+                signatures
+            }
+        }
+    }
+}
+
+/// Infers the type of a formula without evaluating it.
+///
+/// The types of cells read by the formula are taken from the sheet, and the
+/// reads are recorded so the cell is checked again when they change.
+pub fn check(ast: &AST, ctx: &mut ReactiveContext<'_, AST>) -> Result<Type, Error> {
+    let mut checker = Checker {
+        ctx,
+        subst: HashMap::new(),
+        next_var: 0,
+        env: vec![],
+    };
+    let t = checker.infer(ast)?;
+    let [t] = normalize([checker.apply(&t)]);
+    Ok(t)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::language::ast::AST;
+    use crate::reactive::sheet::Sheet;
+
+    use super::*;
+
+    fn infer(text: &str) -> Result<Type, Error> {
+        let mut sheet = Sheet::<AST>::new();
+        let id = sheet.add_cell("test".to_string(), text).unwrap();
+        sheet.get_cell_type(&id).unwrap().clone()
+    }
+
+    macro_rules! test_infer {
+        ($name:ident, $input:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                match infer($input) {
+                    Ok(t) => assert_eq!(t.to_string(), $expected),
+                    Err(e) => panic!("Type error: {}", e),
+                }
+            }
+        };
+    }
+
+    macro_rules! test_type_error {
+        ($name:ident, $input:expr, $($pattern:tt)+) => {
+            #[test]
+            fn $name() {
+                match infer($input) {
+                    Ok(t) => panic!("Expected a type error but found {}", t),
+                    #[allow(unused_variables)]
+                    Err(e) => assert!(matches!(&e.kind, $($pattern)+), "Unexpected error: {}", e),
+                }
+            }
+        };
+    }
+
+    test_infer!(test_literal, "1 + 2", "int");
+    test_infer!(test_string_concat, "\"a\" + \"b\"", "string");
+    test_infer!(test_list, "[1, 2, 3]", "[int]");
+    test_infer!(test_empty_list, "[]", "['a]");
+    test_infer!(test_record, "{a: 1, b: \"x\"}", "{a: int, b: string}");
+    test_infer!(test_identity, "fn (x) -> x", "('a) -> 'a");
+    test_infer!(test_lambda_arithmetic, "fn (x) -> x + 1", "(int) -> int");
+    test_infer!(test_field_access, "fn (r) -> r.strength", "({strength: 'a, ..}) -> 'a");
+    test_infer!(
+        test_field_access_two_fields,
+        "fn (r) -> r.strength * r.dexterity",
+        "({dexterity: int, strength: int, ..}) -> int"
+    );
+    test_infer!(test_let_polymorphism, "let id = fn (x) -> x in [id(1), id(2)]", "[int]");
+    test_infer!(test_let_polymorphism_two_types, "let id = fn (x) -> x in {a: id(1), b: id(\"s\")}", "{a: int, b: string}");
+    test_infer!(test_map, "map(fn (x) -> x > 1, [1, 2])", "[bool]");
+    test_infer!(test_fold, "fold(fn (acc, x) -> acc + x, 0, [1, 2])", "int");
+    test_infer!(test_record_update, "{a: 1, b: 2} // {b: \"x\"}", "{a: int, b: string}");
+    test_infer!(test_record_update_open, "fn (r) -> r // {hp: 10}", "({..}) -> {hp: int, ..}");
+    test_infer!(test_method_let_bound, "let double = fn (x) -> x * 2 in 3.double()", "int");
+    test_infer!(test_builtin_value, "let f = div_ceil in f(1, 2)", "int");
+    test_infer!(test_overloaded_builtin_value, "let f = length in f([1])", "any");
+    test_infer!(test_if, "if 1 < 2 then \"a\" else \"b\"", "string");
+    test_infer!(test_read_is_dynamic, "read()", "[any]");
+
+    test_infer!(
+        test_match,
+        "fn (armor) -> match armor with | {type: \"heavy\", ac} -> ac | {ac, dex_cap: d} -> ac - d",
+        "({ac: int, dex_cap: int, type: string, ..}) -> int"
+    );
+    test_infer!(test_match_list, "fn (l) -> match l with | [first, ..rest] -> rest | [] -> []", "(['a]) -> ['a]");
+
+    test_infer!(test_let_destructure, "let {strength, dexterity: dex} = {strength: 1, dexterity: 2} in strength - dex", "int");
+    test_infer!(test_lambda_destructure, "fn ([a, b]) -> a - b", "([int]) -> int");
+    test_infer!(test_lambda_destructure_record, "fn ({hp}) -> hp", "({hp: 'a, ..}) -> 'a");
+
+    test_infer!(test_record_spread, "let base = {strength: 10, name: \"Orc\"} in {...base, strength: 12, hp: 15}", "{hp: int, name: string, strength: int}");
+    test_infer!(test_record_computed_key, "let name = \"athletics\" in {[\"skill_\" + name]: 2}", "{..}");
+    test_infer!(test_record_computed_key_access, "{a: 1, [\"b\"]: 2}.b + 1", "int");
+    test_infer!(test_record_pun, "fn (strength) -> {strength, dex: 1}", "('a) -> {dex: int, strength: 'a}");
+
+    test_infer!(test_optional_field_known, "{a: 1}?.a", "int");
+    test_infer!(test_optional_field_absent, "{a: 1}?.b", "()");
+    test_infer!(test_optional_field_open, "fn (r) -> r?.bonus", "('a) -> any");
+    test_infer!(test_coalesce, "fn (r) -> r?.bonus ?? 0", "('a) -> int");
+    test_infer!(test_coalesce_missing_field, "{a: 1}.b ?? 0", "int");
+
+    test_infer!(test_tag, "[#fire, #Some(1)]", "[tag]");
+    test_infer!(test_tag_pattern_any, "match #Some(1) with | #Some(x) -> x | _ -> \"a\"", "string");
+    test_infer!(test_enum, "enum([#fire, #cold])", "{cold: tag, fire: tag}");
+    test_infer!(test_enum_dynamic, "let t = #fire in enum([t])", "any");
+    test_type_error!(test_enum_missing, "enum([#fire, #cold]).fier", ErrorKind::MissingField(_));
+    test_type_error!(test_tag_mismatch, "#fire + 1", ErrorKind::StaticTypeMismatch { .. });
+    test_infer!(test_dice, "2 * 1d8 + 3", "dice");
+    test_infer!(test_dice_average, "average(4d6kh3)", "int");
+    test_infer!(test_dice_roll, "fn (seed) -> roll(1d20, seed) + 5", "(int) -> int");
+    test_infer!(test_arithmetic_unknown_not_dice, "fn (x) -> x * 2", "(int) -> int");
+    test_type_error!(test_dice_times_dice, "1d6 * 1d6", ErrorKind::StaticTypeMismatch { .. });
+    test_infer!(test_chance, "1d20 + 5 >= 16", "distribution");
+    test_infer!(test_chance_rational, "[1d20 >= 10.5, 2.5 < distribution(1d4)]", "[distribution]");
+    test_infer!(test_probability, "P(advantage(1d20) >= 11) * expected(2d6)", "rational");
+    test_infer!(test_attack_chances, "attack_chances(1d20, 5, 15)", "{crit: rational, hit: rational}");
+    test_infer!(test_compare_unknown_not_dice, "fn (x) -> x < 3", "(int) -> bool");
+    test_infer!(test_decimal, "2.5 * 2 + 1", "rational");
+    test_infer!(test_decimal_rounding, "floor(2.5) + 1", "int");
+    test_infer!(test_decimal_sum, "sum([0.5, 1.5])", "rational");
+    test_infer!(test_quantity, "[30 ft + 2 * 5 ft, to(1 mi, \"km\")]", "[length]");
+    test_infer!(test_quantity_ratio, "30 ft / 5 ft", "rational");
+    test_infer!(test_quantity_param, "fn (speed) -> speed + 10 ft", "(length) -> length");
+    test_infer!(test_quantity_sum, "sum([5 lb, 8 oz]) > 10 lb", "bool");
+    test_infer!(test_divide_unknown_not_rational, "fn (x) -> x / 2", "(int) -> int");
+    test_type_error!(test_advantage_int, "advantage(5)", ErrorKind::StaticTypeMismatch { .. });
+    test_infer!(test_rolls, "map(fn (r) -> r.total, rolls())", "[int]");
+    test_infer!(test_roll_unseeded, "roll(1d20) + 5", "int");
+    test_infer!(test_range, "1..=3", "[int]");
+    test_infer!(test_comprehension, "[f\"{x}\" for x in 1..3 if x > 1]", "[string]");
+    test_infer!(test_comprehension_nested, "[x * y for x in 1..3 for y in 1..3]", "[int]");
+    test_type_error!(test_comprehension_bad_filter, "[x for x in 1..3 if x]", ErrorKind::StaticTypeMismatch { .. });
+    test_infer!(test_default_any, "fn (a, b = 1) -> a * b", "any");
+    test_infer!(test_named_call_any, "let f = fn (a, b = 1) -> a * b in f(1, b: 2)", "any");
+    test_type_error!(test_default_mismatch, "fn (a, b = \"x\") -> a * b", ErrorKind::StaticTypeMismatch { .. });
+    test_infer!(test_section, "(* 2)", "(int) -> int");
+    test_infer!(test_partial_lambda, "let f = fn (a, b) -> a * b in f(2)", "(int) -> int");
+    test_infer!(test_partial_builtin, "map(index([\"a\"]), [0])", "[string]");
+    test_type_error!(test_partial_mismatch, "let f = fn (a, b) -> a * b in f(\"a\")", ErrorKind::StaticTypeMismatch { .. });
+    test_infer!(test_try, "try(1 / 2, 0)", "int");
+    test_infer!(test_catch, "1 catch e -> length(e)", "int");
+    test_infer!(test_is_error, "is_error(1)", "bool");
+
+    test_type_error!(test_catch_mismatch, "1 catch e -> e", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_optional_field_not_record, "1?.a", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_coalesce_mismatch, "{a: 1}.a ?? \"x\"", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_record_computed_key_not_string, "{[1]: 2}", ErrorKind::StaticTypeMismatch { expected: Type::String, actual: Type::Integer });
+    test_type_error!(test_record_spread_not_record, "{...1}", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_destructure_missing_field, "let {a} = {b: 1} in a", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_add_mismatch, "1 + \"a\"", ErrorKind::StaticTypeMismatch { expected: Type::Integer, actual: Type::String });
+    test_type_error!(test_list_mismatch, "[1, \"a\"]", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_if_branches, "if true then 1 else \"a\"", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_missing_field, "{a: 1}.b", ErrorKind::MissingField(f) if f == "b");
+    test_type_error!(test_call_non_function, "1(2)", ErrorKind::StaticTypeMismatch { expected: Type::Function(..), actual: Type::Integer });
+    test_type_error!(test_lambda_arity, "(fn (x) -> x)(1, 2)", ErrorKind::Arity { expected, actual: 2 } if expected == &vec![1usize]);
+    test_type_error!(test_builtin_arity, "length(1, 2)", ErrorKind::Arity { .. });
+    test_type_error!(test_self_application, "fn (x) -> x(x)", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_closed_record_field, "(fn (r) -> r.b)({a: 1})", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_match_arms_differ, "match 1 with | 1 -> 1 | _ -> \"a\"", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_match_pattern_type, "match 1 with | \"a\" -> 1", ErrorKind::StaticTypeMismatch { expected: Type::String, actual: Type::Integer });
+    test_type_error!(test_quantity_mismatch, "30 ft + 5 lb", ErrorKind::StaticTypeMismatch { .. });
+    test_type_error!(test_unknown_name, "foo", ErrorKind::UnknownName(n) if n == "foo");
+
+    #[test]
+    fn test_error_span() {
+        let e = infer("1 + \"a\"").unwrap_err();
+        assert_eq!(e.span, Some(crate::language::errors::Span::new(4, 7)));
+    }
+
+    #[test]
+    fn test_cell_types() {
+        let mut sheet = Sheet::<AST>::new();
+        sheet.add_cell("stats".to_string(), "{strength: 14, dexterity: 12}");
+        sheet.add_cell("modifier".to_string(), "fn (score) -> (score - 10) / 2");
+        let id = sheet
+            .add_cell("str_mod".to_string(), "modifier(stats.strength)")
+            .unwrap();
+        assert_eq!(sheet.get_cell_type(&id).unwrap().as_ref().unwrap(), &Type::Integer);
+
+        // Cells using a polymorphic cell instantiate it separately each time
+        sheet.add_cell("first".to_string(), "fn (l) -> index(l, 0)");
+        let id = sheet
+            .add_cell("pair".to_string(), "[to_string(first([1])), first([\"a\"])]")
+            .unwrap();
+        assert_eq!(sheet.get_cell_type(&id).unwrap().as_ref().unwrap().to_string(), "[string]");
+    }
+
+    #[test]
+    fn test_types_update_with_dependencies() {
+        let mut sheet = Sheet::<AST>::new();
+        let a = sheet.add_cell("a".to_string(), "1").unwrap();
+        let b = sheet.add_cell("b".to_string(), "a + 1").unwrap();
+        assert!(sheet.get_cell_type(&b).unwrap().is_ok());
+
+        sheet.update_cell(&a, "\"text\"");
+        let e = sheet.get_cell_type(&b).unwrap().as_ref().unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::StaticTypeMismatch { .. }));
+        // Without type checking enabled the cell is still evaluated
+        assert!(sheet.get_cell_value(&b).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_type_checking_prevents_evaluation() {
+        let mut sheet = Sheet::<AST>::new();
+        sheet.set_type_checking(true);
+        // The error branch is never evaluated but is still rejected
+        let id = sheet
+            .add_cell("test".to_string(), "if true then 1 else push(\"other\", \"a\")")
+            .unwrap();
+        let e = sheet.get_cell_value(&id).unwrap().as_ref().unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::StaticTypeMismatch { .. }));
+        assert!(e.snippet.is_some());
+
+        let id = sheet.add_cell("fine".to_string(), "1 + 2").unwrap();
+        assert!(sheet.get_cell_value(&id).unwrap().is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+
+use crate::language::errors::{Error, ErrorKind};
+use crate::language::rational::Rational;
+
+/// What a unit measures. Only quantities of the same dimension can be added or compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Dimension {
+    Length,
+    Weight,
+    Time,
+}
+
+impl Dimension {
+    pub const ALL: [Dimension; 3] = [Dimension::Length, Dimension::Weight, Dimension::Time];
+}
+
+impl Display for Dimension {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Dimension::Length => "length",
+            Dimension::Weight => "weight",
+            Dimension::Time => "time",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A unit of measure such as feet or rounds
+#[derive(Debug, PartialEq, Eq)]
+pub struct Unit {
+    pub name: &'static str,
+    /// The name used for amounts other than one, the same as `name` for abbreviations
+    pub plural: &'static str,
+    pub dimension: Dimension,
+    /// The size of the unit in the smallest whole unit of its dimension, as a fraction
+    size: (i128, i128),
+}
+
+const fn unit(name: &'static str, plural: &'static str, dimension: Dimension, size: (i128, i128)) -> Unit {
+    Unit {
+        name,
+        plural,
+        dimension,
+        size,
+    }
+}
+
+/// Lengths are measured in feet, weights in pounds and times in seconds.
+/// Metric units are converted exactly, as a foot is defined as 0.3048 m and a pound as 0.45359237 kg.
+static UNITS: [Unit; 14] = [
+    unit("ft", "ft", Dimension::Length, (1, 1)),
+    unit("yd", "yd", Dimension::Length, (3, 1)),
+    unit("mi", "mi", Dimension::Length, (5280, 1)),
+    unit("cm", "cm", Dimension::Length, (25, 762)),
+    unit("m", "m", Dimension::Length, (1250, 381)),
+    unit("km", "km", Dimension::Length, (1_250_000, 381)),
+    unit("oz", "oz", Dimension::Weight, (1, 16)),
+    unit("lb", "lb", Dimension::Weight, (1, 1)),
+    unit("g", "g", Dimension::Weight, (100_000, 45_359_237)),
+    unit("kg", "kg", Dimension::Weight, (100_000_000, 45_359_237)),
+    unit("s", "s", Dimension::Time, (1, 1)),
+    unit("round", "rounds", Dimension::Time, (6, 1)),
+    unit("min", "min", Dimension::Time, (60, 1)),
+    unit("hour", "hours", Dimension::Time, (3600, 1)),
+];
+
+impl Unit {
+    /// Finds a unit by its name or plural
+    pub fn lookup(name: &str) -> Option<&'static Unit> {
+        UNITS.iter().find(|u| u.name == name || u.plural == name)
+    }
+
+    fn size(&self) -> Rational {
+        Rational::new(self.size.0, self.size.1).expect("unit sizes are never zero")
+    }
+}
+
+/// An amount of some unit, such as `30 ft`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quantity {
+    pub amount: Rational,
+    pub unit: &'static Unit,
+}
+
+/// The error for combining quantities of the dimensions `a` and `b`, where `None` is a plain number
+pub fn dimension_mismatch(a: Option<Dimension>, b: Option<Dimension>) -> Error {
+    Error::new(ErrorKind::DimensionMismatch(a, b))
+}
+
+impl Quantity {
+    pub fn new(amount: Rational, unit: &'static Unit) -> Quantity {
+        Quantity { amount, unit }
+    }
+
+    pub fn dimension(&self) -> Dimension {
+        self.unit.dimension
+    }
+
+    fn check_dimension(&self, other: &Quantity) -> Result<(), Error> {
+        if self.dimension() == other.dimension() {
+            Ok(())
+        } else {
+            Err(dimension_mismatch(Some(self.dimension()), Some(other.dimension())))
+        }
+    }
+
+    /// The same quantity measured in `unit`
+    pub fn to(&self, unit: &'static Unit) -> Result<Quantity, Error> {
+        if unit.dimension != self.dimension() {
+            return Err(dimension_mismatch(Some(self.dimension()), Some(unit.dimension)));
+        }
+        let amount = self.amount.mul(&self.unit.size())?.div(&unit.size())?;
+        Ok(Quantity::new(amount, unit))
+    }
+
+    /// Adds a quantity of the same dimension, giving the result in the unit of `self`
+    pub fn add(&self, other: &Quantity) -> Result<Quantity, Error> {
+        self.check_dimension(other)?;
+        Ok(Quantity::new(self.amount.add(&other.to(self.unit)?.amount)?, self.unit))
+    }
+
+    pub fn sub(&self, other: &Quantity) -> Result<Quantity, Error> {
+        self.add(&other.scale(&Rational::from(-1))?)
+    }
+
+    pub fn scale(&self, n: &Rational) -> Result<Quantity, Error> {
+        Ok(Quantity::new(self.amount.mul(n)?, self.unit))
+    }
+
+    /// How many times `other` fits into `self`, as in `30 ft / 5 ft`
+    pub fn ratio(&self, other: &Quantity) -> Result<Rational, Error> {
+        self.check_dimension(other)?;
+        self.amount.div(&other.to(self.unit)?.amount)
+    }
+
+    /// Compares quantities of the same dimension by their size, whatever their units
+    pub fn compare(&self, other: &Quantity) -> Result<Ordering, Error> {
+        self.check_dimension(other)?;
+        Ok(self.amount.mul(&self.unit.size())?.cmp(&other.amount.mul(&other.unit.size())?))
+    }
+}
+
+impl Display for Quantity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = if self.amount == Rational::ONE { self.unit.name } else { self.unit.plural };
+        write!(f, "{} {}", self.amount, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn q(amount: i64, unit: &str) -> Quantity {
+        Quantity::new(Rational::from(amount), Unit::lookup(unit).unwrap())
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(Unit::lookup("rounds"), Unit::lookup("round"));
+        assert_eq!(Unit::lookup("ft").unwrap().dimension, Dimension::Length);
+        assert!(Unit::lookup("parsec").is_none());
+    }
+
+    #[test]
+    fn test_convert() {
+        let m = Unit::lookup("m").unwrap();
+        assert_eq!(q(30, "ft").to(m).unwrap().to_string(), "9.144 m");
+        assert_eq!(q(1, "mi").to(Unit::lookup("ft").unwrap()).unwrap(), q(5280, "ft"));
+        assert_eq!(q(10, "min").to(Unit::lookup("round").unwrap()).unwrap().to_string(), "100 rounds");
+        assert!(q(5, "lb").to(m).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(q(30, "ft").add(&q(2, "yd")).unwrap(), q(36, "ft"));
+        assert_eq!(q(1, "min").sub(&q(2, "round")).unwrap().to_string(), "0.8 min");
+        assert_eq!(q(30, "ft").ratio(&q(5, "ft")).unwrap(), Rational::from(6));
+        assert!(q(30, "ft").add(&q(5, "lb")).is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(q(1, "mi").compare(&q(5000, "ft")).unwrap(), Ordering::Greater);
+        assert_eq!(q(1, "round").compare(&q(6, "s")).unwrap(), Ordering::Equal);
+        assert!(q(1, "round").compare(&q(6, "ft")).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(q(1, "round").to_string(), "1 round");
+        assert_eq!(q(3, "round").to_string(), "3 rounds");
+        assert_eq!(q(5, "lb").to_string(), "5 lb");
+    }
+}
@@ -7,6 +7,16 @@ pub mod bimap {
         right_to_left: HashMap<Right, Left>,
     }
 
+    impl<Left, Right> Default for BiMap<Left, Right>
+    where
+        Left: Eq + Hash + Clone,
+        Right: Eq + Hash + Clone,
+    {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl<Left, Right> BiMap<Left, Right>
     where
         Left: Eq + Hash + Clone,
@@ -44,6 +54,16 @@ pub mod pairmap {
         right_to_left: HashMap<Right, HashSet<Left>>,
     }
 
+    impl<Left, Right> Default for PairMap<Left, Right>
+    where
+        Left: Eq + Hash + Clone,
+        Right: Eq + Hash + Clone,
+    {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl<Left, Right> PairMap<Left, Right>
     where
         Left: Eq + Hash + Clone,
@@ -59,11 +79,11 @@ pub mod pairmap {
         pub fn insert(&mut self, left: Left, right: Right) {
             self.left_to_right
                 .entry(left.clone())
-                .or_insert(HashSet::new())
+                .or_default()
                 .insert(right.clone());
             self.right_to_left
                 .entry(right.clone())
-                .or_insert(HashSet::new())
+                .or_default()
                 .insert(left.clone());
         }
 
@@ -99,6 +119,12 @@ pub mod fastqueue {
         set: HashSet<T>,
     }
 
+    impl<T: Eq + std::hash::Hash + Clone> Default for FastQueue<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl<T: Eq + std::hash::Hash + Clone> FastQueue<T> {
         pub fn new() -> Self {
             FastQueue {
@@ -115,9 +141,8 @@ pub mod fastqueue {
         }
 
         pub fn pop(&mut self) -> Option<T> {
-            self.queue.pop_front().map(|item| {
-                self.set.remove(&item);
-                item
+            self.queue.pop_front().inspect(|item| {
+                self.set.remove(item);
             })
         }
     }
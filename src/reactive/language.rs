@@ -1,45 +1,111 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 
+use super::rolls::{CellRolls, PendingRoll, RollEntry};
 use super::sheet::{CellId, Sheet};
 
 pub trait IntermediateRep: Sized {
     type Value;
     type Error;
+    type Type;
 
     fn parse(text: &str) -> Result<Self, Self::Error>;
 
-    fn evaluate<'a>(
+    /// Statically checks the IR before it is evaluated, returning its type
+    fn check(&self, ctx: &mut ReactiveContext<'_, Self>) -> Result<Self::Type, Self::Error>;
+
+    fn evaluate(
         &self,
-        ctx: ReactiveContext<'a, Self>
+        ctx: ReactiveContext<'_, Self>
     ) -> Result<Self::Value, Self::Error>;
 
-    fn make_error(message: impl Into<String>) -> Self::Error;
+    /// The error given to a cell which depends on its own value
+    fn make_cycle_error(cell_name: &str) -> Self::Error;
 }
 
+/// The result of evaluating a cell
+pub type CellResult<IR> = Result<<IR as IntermediateRep>::Value, <IR as IntermediateRep>::Error>;
+
+/// The result of type checking a cell
+pub type TypeResult<IR> = Result<<IR as IntermediateRep>::Type, <IR as IntermediateRep>::Error>;
+
 pub struct ReactiveContext<'a, IR: IntermediateRep> {
     pub(super) ctx: &'a Sheet<IR>,
+    pub(super) source: &'a str,
     pub(super) pushed_values: &'a Vec<IR::Value>,
     pub(super) reads: &'a mut HashSet<CellId>,
     pub(super) pushes: &'a mut HashMap<CellId, Vec<IR::Value>>,
+    pub(super) rolls: &'a mut CellRolls,
 }
 
 impl<'a, IR: IntermediateRep> ReactiveContext<'a, IR> 
 where 
     IR::Value: Clone + Debug,
+    IR::Error: Clone,
 {
-    pub fn read_cell_by_name(&mut self, name: &str) -> Option<(CellId, &Result<IR::Value, IR::Error>)> {
+    /// The raw text of the cell being evaluated
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    pub fn read_cell_by_name(&mut self, name: &str) -> Option<(CellId, &CellResult<IR>)> {
         let id = CellId(name.to_string());
         self.reads.insert(id.clone());
         self.ctx.get_cell_value(&id).map(|v| (id, v))
     }
 
+    /// Like `read_cell_by_name` but reads the type of the cell, which is None if
+    /// the cell could not be parsed.
+    pub fn read_cell_type_by_name(
+        &mut self,
+        name: &str,
+    ) -> Option<(CellId, Option<&TypeResult<IR>>)> {
+        let id = CellId(name.to_string());
+        self.reads.insert(id.clone());
+        self.ctx.cell_type(&id).map(|t| (id, t))
+    }
+
     pub fn get_pushes(&self) -> &Vec<IR::Value> {
         self.pushed_values
     }
 
     pub fn add_push_by_name(&mut self, target: &str, value: &IR::Value) {
-        let results = self.pushes.entry(CellId(target.to_string())).or_insert_with(Vec::new);
+        let results = self.pushes.entry(CellId(target.to_string())).or_default();
         results.push(value.clone());
     }
+
+    /// The seed for the next roll the cell makes.
+    ///
+    /// Each roll reuses the seed of the same roll from the last time the cell
+    /// was evaluated, and otherwise gets a new seed from the sheet's roll log.
+    pub fn roll_seed(&mut self) -> i64 {
+        let index = self.rolls.seeds.len();
+        let seed = match self.rolls.previous_seeds.get(index) {
+            Some(seed) => *seed,
+            None => self.ctx.roll_log().next_seed(self.rolls.new.len()),
+        };
+        self.rolls.seeds.push(seed);
+        seed
+    }
+
+    /// Records the result of rolling with the seed last returned by `roll_seed`.
+    ///
+    /// Only rolls with a new seed are added to the log, a roll with a reused
+    /// seed is already there.
+    pub fn log_roll(&mut self, formula: String, seed: i64, dice: Vec<i64>, total: i64) {
+        if self.rolls.seeds.len() > self.rolls.previous_seeds.len() {
+            self.rolls.new.push(PendingRoll {
+                formula,
+                seed,
+                dice,
+                total,
+            });
+        }
+    }
+
+    /// Every roll made by the sheet so far. The cell is evaluated again when new rolls are made.
+    pub fn read_roll_log(&mut self) -> &'a [RollEntry] {
+        self.rolls.read_log = true;
+        self.ctx.roll_log().entries()
+    }
 }
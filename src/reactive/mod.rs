@@ -1,2 +1,3 @@
 pub mod sheet;
-pub mod language;
\ No newline at end of file
+pub mod language;
+pub mod rolls;
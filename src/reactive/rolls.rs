@@ -0,0 +1,85 @@
+use super::sheet::CellId;
+
+/// A roll made by a cell, as recorded in the sheet's roll log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollEntry {
+    /// The dice that were rolled, as written in a formula
+    pub formula: String,
+    pub seed: i64,
+    /// Every die rolled in order, including dice that were not kept
+    pub dice: Vec<i64>,
+    pub total: i64,
+    /// The cell that made the roll
+    pub cell: CellId,
+    /// The position of the roll in the log, counting from 0
+    pub time: u64,
+}
+
+/// A roll made while evaluating a cell, before it is added to the log
+#[derive(Debug, Clone)]
+pub(super) struct PendingRoll {
+    pub formula: String,
+    pub seed: i64,
+    pub dice: Vec<i64>,
+    pub total: i64,
+}
+
+/// Every roll made by the cells of a sheet, in the order they were made.
+///
+/// The seeds of new rolls follow on from the log's seed, so a sheet that is
+/// built up the same way always rolls the same numbers.
+#[derive(Debug, Clone, Default)]
+pub struct RollLog {
+    entries: Vec<RollEntry>,
+    seed: i64,
+}
+
+impl RollLog {
+    pub fn new(seed: i64) -> Self {
+        RollLog {
+            entries: vec![],
+            seed,
+        }
+    }
+
+    pub fn entries(&self) -> &[RollEntry] {
+        &self.entries
+    }
+
+    pub(super) fn set_seed(&mut self, seed: i64) {
+        self.seed = seed;
+    }
+
+    /// The seed for a roll that will be made after `pending` other new rolls
+    pub(super) fn next_seed(&self, pending: usize) -> i64 {
+        self.seed.wrapping_add((self.entries.len() + pending) as i64)
+    }
+
+    pub(super) fn record(&mut self, cell: &CellId, rolls: Vec<PendingRoll>) {
+        for roll in rolls {
+            let time = self.entries.len() as u64;
+            self.entries.push(RollEntry {
+                formula: roll.formula,
+                seed: roll.seed,
+                dice: roll.dice,
+                total: roll.total,
+                cell: cell.clone(),
+                time,
+            });
+        }
+    }
+}
+
+/// The rolls made by a cell while it is evaluated.
+///
+/// A cell that is evaluated again reuses the seeds of its previous rolls in
+/// order, so that it only rolls again when it is edited or rerolled, and not
+/// whenever a cell it reads changes.
+#[derive(Debug, Default)]
+pub(super) struct CellRolls {
+    pub previous_seeds: Vec<i64>,
+    pub seeds: Vec<i64>,
+    pub new: Vec<PendingRoll>,
+    /// Whether the cell read the roll log, so must be evaluated again when it changes
+    pub read_log: bool,
+}
@@ -2,7 +2,8 @@ use crate::language::ast::AST;
 use crate::language::s_exprs::ToSExpr;
 use crate::maps::fastqueue::FastQueue;
 use crate::maps::pairmap::PairMap;
-use crate::reactive::language::ReactiveContext;
+use crate::reactive::language::{CellResult, ReactiveContext, TypeResult};
+use crate::reactive::rolls::{CellRolls, RollLog};
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
 
@@ -17,11 +18,23 @@ pub struct Sheet<IR: IntermediateRep> {
     writer_to_targets: HashMap<CellId, HashSet<CellId>>,
     // Mapping from targets to the cells that push to them and the values
     targets_from_writer: HashMap<CellId, BTreeMap<CellId, Vec<IR::Value>>>,
+    // Whether cells that fail type checking are left unevaluated
+    type_checking: bool,
+    // Every roll made by the cells
+    roll_log: RollLog,
+    // Cells that read the roll log, which are evaluated again when new rolls are made
+    roll_readers: HashSet<CellId>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct CellId(pub(super) String);
 
+impl CellId {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
 impl<T: IntermediateRep> Sheet<T> {
     pub fn get_cell_name(&self, id: &CellId) -> String {
         id.0.clone()
@@ -30,13 +43,27 @@ impl<T: IntermediateRep> Sheet<T> {
 
 struct Cell<IR: IntermediateRep> {
     raw_contents: String,
-    value: Result<IR::Value, IR::Error>,
+    value: CellResult<IR>,
+    ty: Option<TypeResult<IR>>,
     parsed: Option<IR>,
+    // The seeds of the rolls made the last time the cell was evaluated, which are reused until it is edited
+    roll_seeds: Vec<i64>,
+}
+
+impl<IR: IntermediateRep> Default for Sheet<IR>
+where
+    IR::Value: Clone + Debug,
+    IR::Error: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<IR: IntermediateRep> Sheet<IR>
 where
     IR::Value: Clone + Debug,
+    IR::Error: Clone,
 {
     /// Creates a new, empty sheet.
     pub fn new() -> Sheet<IR> {
@@ -45,9 +72,58 @@ where
             read_relations: PairMap::new(),
             writer_to_targets: HashMap::new(),
             targets_from_writer: HashMap::new(),
+            type_checking: false,
+            roll_log: RollLog::default(),
+            roll_readers: HashSet::new(),
         }
     }
 
+    /// Sets the seed that the seeds of new rolls follow on from.
+    ///
+    /// Sheets built up the same way with the same seed make the same rolls.
+    pub fn set_roll_seed(&mut self, seed: i64) {
+        self.roll_log.set_seed(seed);
+    }
+
+    /// Every roll made by the cells of the sheet
+    pub fn roll_log(&self) -> &RollLog {
+        &self.roll_log
+    }
+
+    /// Adds the rolls a cell made to the log, returning whether there were any new ones
+    fn record_rolls(&mut self, id: &CellId, rolls: CellRolls) -> bool {
+        if rolls.read_log {
+            self.roll_readers.insert(id.clone());
+        } else {
+            self.roll_readers.remove(id);
+        }
+        if let Some(cell) = self.cells.get_mut(id) {
+            cell.roll_seeds = rolls.seeds;
+        }
+        let rolled = !rolls.new.is_empty();
+        self.roll_log.record(id, rolls.new);
+        rolled
+    }
+
+    /// Sets whether cells are type checked before they are evaluated.
+    ///
+    /// The type of every cell is always inferred, but when type checking is
+    /// enabled a cell with a type error takes the error as its value instead
+    /// of being evaluated. This applies to cells evaluated after the change.
+    pub fn set_type_checking(&mut self, enabled: bool) {
+        self.type_checking = enabled;
+    }
+
+    /// Type checks and then evaluates a parsed cell
+    fn check_and_evaluate(&self, ast: &IR, mut ctx: ReactiveContext<'_, IR>) -> (CellResult<IR>, TypeResult<IR>) {
+        let ty = ast.check(&mut ctx);
+        let value = match &ty {
+            Err(e) if self.type_checking => Err(e.clone()),
+            _ => ast.evaluate(ctx),
+        };
+        (value, ty)
+    }
+
     /// Adds a cell to the sheet.
     ///
     /// If a cell with the given name already exists, returns None.
@@ -63,25 +139,31 @@ where
         } else {
             let mut reads = HashSet::new();
             let mut pushes = HashMap::new();
+            let mut rolls = CellRolls::default();
             let contents = contents.into();
-            let (value, ast) = match IR::parse(&contents) {
+            let (value, ty, ast) = match IR::parse(&contents) {
                 Ok(ast) => {
                     let ctx = ReactiveContext {
-                        ctx: &self,
+                        ctx: self,
+                        source: &contents,
                         pushed_values: &Vec::new(),
                         reads: &mut reads,
                         pushes: &mut pushes,
+                        rolls: &mut rolls,
                     };
                     
-                    (ast.evaluate(ctx), Some(ast))
+                    let (value, ty) = self.check_and_evaluate(&ast, ctx);
+                    (value, Some(ty), Some(ast))
                 },
-                Err(err) => (Err(err), None),
+                Err(err) => (Err(err), None, None),
             };
 
             let new_cell = Cell {
                 raw_contents: contents,
-                value: value,
+                value,
+                ty,
                 parsed: ast,
+                roll_seeds: vec![],
             };
 
             self.cells.insert(id.clone(), new_cell);
@@ -90,6 +172,11 @@ where
                 self.read_relations.insert(read, id.clone());
             }
 
+            if self.record_rolls(&id, rolls) {
+                let readers = self.roll_readers.iter().filter(|r| **r != id).cloned().collect::<Vec<_>>();
+                self.propagate(readers);
+            }
+
             Some(id)
         }
     }
@@ -99,7 +186,8 @@ where
     /// The contents are parsed into an intermediate representation and evaluated
     /// in the context of the sheet.
     ///
-    /// All cells that depend on the updated cell are re-evaluated.
+    /// All cells that depend on the updated cell are re-evaluated. The updated
+    /// cell rolls its dice again, but the cells that depend on it reuse their rolls.
     pub fn update_cell(&mut self, id: &CellId, contents: impl Into<String>) -> HashSet<CellId> {
         // Update cell
         let cell = self.cells.get_mut(id).unwrap();
@@ -108,18 +196,46 @@ where
             Ok(ast) => cell.parsed = Some(ast),
             Err(err) => {
                 cell.parsed = None;
+                cell.ty = None;
                 cell.value = Err(err);
             }
         }
         cell.raw_contents = contents;
+        cell.roll_seeds.clear();
 
+        self.propagate([id.clone()])
+    }
+
+    /// Rolls the dice of the cell with the given id again, re-evaluating the cells that depend on it.
+    pub fn reroll(&mut self, id: &CellId) -> HashSet<CellId> {
+        if let Some(cell) = self.cells.get_mut(id) {
+            cell.roll_seeds.clear();
+        }
+        self.propagate([id.clone()])
+    }
+
+    /// Re-evaluates the given cells and then all cells that depend on them, returning every cell evaluated
+    fn propagate(&mut self, ids: impl IntoIterator<Item = CellId>) -> HashSet<CellId> {
         let mut to_evaluate = FastQueue::new();
-        to_evaluate.push(id.clone());
+        for id in ids {
+            to_evaluate.push(id);
+        }
         let mut visited = HashSet::new();
+        // Cells that read the roll log are evaluated again for new rolls at most once, so that cells
+        // which read the log and roll themselves can not keep making each other roll
+        let mut log_reruns = HashSet::new();
 
         while let Some(id) = to_evaluate.pop() {
             if visited.insert(id.clone()) || !self.has_cyclic_dependency(&id) {
+                let logged = self.roll_log.entries().len();
                 let pushes = self.recompute_cell(&id);
+                if self.roll_log.entries().len() > logged {
+                    for reader in self.roll_readers.iter().filter(|r| **r != id) {
+                        if log_reruns.insert(reader.clone()) {
+                            to_evaluate.push(reader.clone());
+                        }
+                    }
+                }
                 // If there are cells that were written to push them to the queue
                 if let Some(pushes) = pushes {
                     for dependant in pushes {
@@ -133,7 +249,7 @@ where
                     to_evaluate.push(dependant.clone());
                 }
             } else {
-                self.cells.get_mut(&id).unwrap().value = Err(IR::make_error("Circular dependency"));
+                self.cells.get_mut(&id).unwrap().value = Err(IR::make_cycle_error(&id.0));
             }
         }
 
@@ -146,9 +262,14 @@ where
     fn recompute_cell(&mut self, id: &CellId) -> Option<HashSet<CellId>> {
         self.read_relations.delete_with_right(id);
 
-        if let Some(ast) = &self.cells.get(id).unwrap().parsed {
+        let cell = self.cells.get(id).unwrap();
+        if let Some(ast) = &cell.parsed {
             let mut new_reads = HashSet::new();
             let mut new_pushes = HashMap::new();
+            let mut rolls = CellRolls {
+                previous_seeds: cell.roll_seeds.clone(),
+                ..CellRolls::default()
+            };
             
             let pushed_values = self
                 .targets_from_writer
@@ -157,15 +278,18 @@ where
                 .unwrap_or_default();
             
             let ctx = ReactiveContext {
-                ctx: &self,
+                ctx: self,
+                source: &cell.raw_contents,
                 pushed_values: &pushed_values,
                 reads: &mut new_reads,
                 pushes: &mut new_pushes,
+                rolls: &mut rolls,
             };
             
-            let new_value = ast.evaluate(ctx);
+            let (new_value, new_type) = self.check_and_evaluate(ast, ctx);
             let cell = self.cells.get_mut(id).unwrap();
             cell.value = new_value;
+            cell.ty = Some(new_type);
 
             for read in new_reads {
                 self.read_relations.insert(read, id.clone());
@@ -186,15 +310,17 @@ where
                     .entry(target_id.clone())
                     .or_default();
                 // If we are writing a new value then update the entry
-                if let Some(new_values) = new_pushes.get(&target_id) {
+                if let Some(new_values) = new_pushes.get(target_id) {
                     entry.insert(id.clone(), new_values.clone());
                 } else {
                     entry.remove(id);
                 }
             }
+            // The cells that read the log are evaluated again for new rolls by `propagate`
+            self.record_rolls(id, rolls);
             return Some(to_update);
         }
-        return None;
+        None
     }
 
     /// Checks if cell id is dependant on itself
@@ -219,10 +345,21 @@ where
     /// Returns the current value of the cell with the given id.
     ///
     /// This is None if the cell does not exist.
-    pub fn get_cell_value(&self, id: &CellId) -> Option<&Result<IR::Value, IR::Error>> {
+    pub fn get_cell_value(&self, id: &CellId) -> Option<&CellResult<IR>> {
         self.cells.get(id).map(|c| &c.value)
     }
 
+    /// Returns the inferred type of the cell with the given id.
+    ///
+    /// This is None if the cell does not exist or could not be parsed.
+    pub fn get_cell_type(&self, id: &CellId) -> Option<&TypeResult<IR>> {
+        self.cells.get(id).and_then(|c| c.ty.as_ref())
+    }
+
+    pub(super) fn cell_type(&self, id: &CellId) -> Option<Option<&TypeResult<IR>>> {
+        self.cells.get(id).map(|c| c.ty.as_ref())
+    }
+
     pub fn get_cell_text(&self, id: &CellId) -> Option<&str> {
         self.cells.get(id).map(|c| c.raw_contents.as_str())
     }
@@ -232,8 +369,7 @@ impl Sheet<AST> {
     pub fn get_ast_s_expr(&self, id: &CellId) -> String {
         self.cells
             .get(id)
-            .map(|c| (&c.parsed).as_ref())
-            .flatten()
+            .and_then(|c| c.parsed.as_ref())
             .map(|ast| ast.to_s_expr())
             .unwrap_or("No ast".to_string())
     }